@@ -47,6 +47,11 @@ resource "kubernetes_manifest" "customresourcedefinition_cardanonodeports_demete
               "name" = "Auth Token"
               "type" = "string"
             },
+            {
+              "jsonPath" = ".status.state"
+              "name" = "State"
+              "type" = "string"
+            },
           ]
           "name" = "v1alpha1"
           "schema" = {
@@ -59,12 +64,45 @@ resource "kubernetes_manifest" "customresourcedefinition_cardanonodeports_demete
                       "nullable" = true
                       "type" = "string"
                     }
+                    "clientCertificate" = {
+                      "description" = "Client certificate the proxy requires on the TLS connections of the port."
+                      "nullable" = true
+                      "properties" = {
+                        "ca" = {
+                          "description" = "PEM of the CAs that issue the certificates. The token is still needed to find the port."
+                          "nullable" = true
+                          "type" = "string"
+                        }
+                        "fingerprint" = {
+                          "description" = "SHA-256 fingerprint of the certificate in hex. Connections with it are identified by the\ncertificate, so the token isn't needed in the SNI."
+                          "nullable" = true
+                          "type" = "string"
+                        }
+                      }
+                      "type" = "object"
+                    }
                     "network" = {
                       "type" = "string"
                     }
+                    "readOnly" = {
+                      "nullable" = true
+                      "type" = "boolean"
+                    }
                     "throughputTier" = {
                       "type" = "string"
                     }
+                    "validFrom" = {
+                      "description" = "RFC 3339 time the token starts being accepted."
+                      "format" = "date-time"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "validUntil" = {
+                      "description" = "RFC 3339 time the token stops being accepted. Open connections are closed then."
+                      "format" = "date-time"
+                      "nullable" = true
+                      "type" = "string"
+                    }
                     "version" = {
                       "type" = "string"
                     }
@@ -82,9 +120,53 @@ resource "kubernetes_manifest" "customresourcedefinition_cardanonodeports_demete
                     "authToken" = {
                       "type" = "string"
                     }
+                    "authTokens" = {
+                      "default" = []
+                      "description" = "Tokens the proxy accepts for the port, the current one first."
+                      "items" = {
+                        "properties" = {
+                          "notAfter" = {
+                            "description" = "RFC 3339 time a replaced token stops being accepted. The current token has none."
+                            "nullable" = true
+                            "type" = "string"
+                          }
+                          "token" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "token",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "authenticatedEndpointUrl" = {
                       "type" = "string"
                     }
+                    "rotations" = {
+                      "default" = []
+                      "description" = "Values of the rotate annotation already applied, the latest last. Reusing one doesn't\nmint its token again."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "specAuthToken" = {
+                      "description" = "`authToken` of the spec when it was last applied, to tell when it changes."
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "state" = {
+                      "default" = "Active"
+                      "description" = "Whether the time is within the validity window of the port."
+                      "enum" = [
+                        "Pending",
+                        "Active",
+                        "Expired",
+                      ]
+                      "type" = "string"
+                    }
                   }
                   "required" = [
                     "authToken",
@@ -23,6 +23,11 @@ variable "metrics_delay" {
   default     = "30"
 }
 
+variable "token_rotation_grace" {
+  description = "Time a rotated token stays valid (in seconds)"
+  default     = "86400"
+}
+
 variable "resources" {
   type = object({
     limits = object({
@@ -71,6 +71,11 @@ resource "kubernetes_deployment_v1" "operator" {
             value = var.metrics_delay
           }
 
+          env {
+            name  = "TOKEN_ROTATION_GRACE"
+            value = var.token_rotation_grace
+          }
+
           resources {
             limits = {
               cpu    = var.resources.limits.cpu
@@ -5,15 +5,16 @@ resource "kubernetes_namespace" "namespace" {
 }
 
 module "node_v1_feature" {
-  depends_on         = [kubernetes_namespace.namespace]
-  source             = "./feature"
-  namespace          = var.namespace
-  operator_image_tag = var.operator_image_tag
-  metrics_delay      = var.metrics_delay
-  extension_name     = var.extension_name
-  dns_zone           = var.dns_zone
-  api_key_salt       = var.api_key_salt
-  resources          = var.operator_resources
+  depends_on           = [kubernetes_namespace.namespace]
+  source               = "./feature"
+  namespace            = var.namespace
+  operator_image_tag   = var.operator_image_tag
+  metrics_delay        = var.metrics_delay
+  token_rotation_grace = var.token_rotation_grace
+  extension_name       = var.extension_name
+  dns_zone             = var.dns_zone
+  api_key_salt         = var.api_key_salt
+  resources            = var.operator_resources
 }
 
 // blue (once we have a green, we can update its name to proxy-blue)
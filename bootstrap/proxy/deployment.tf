@@ -46,6 +46,39 @@ resource "kubernetes_deployment_v1" "node_proxy" {
             protocol       = "TCP"
           }
 
+          port {
+            name           = "websocket"
+            container_port = local.websocket_port
+            protocol       = "TCP"
+          }
+
+          port {
+            name           = "submit-api"
+            container_port = local.submit_api_port
+            protocol       = "TCP"
+          }
+
+          port {
+            name           = "jsonrpc"
+            container_port = local.jsonrpc_port
+            protocol       = "TCP"
+          }
+
+          port {
+            name           = "mempool"
+            container_port = local.mempool_port
+            protocol       = "TCP"
+          }
+
+          dynamic "port" {
+            for_each = var.relay_networks
+            content {
+              name           = "n2n-${port.key}"
+              container_port = port.value
+              protocol       = "TCP"
+            }
+          }
+
           env {
             name  = "PROXY_NAMESPACE"
             value = var.namespace
@@ -56,6 +89,26 @@ resource "kubernetes_deployment_v1" "node_proxy" {
             value = local.proxy_addr
           }
 
+          env {
+            name  = "PROXY_WS_ADDR"
+            value = local.websocket_addr
+          }
+
+          env {
+            name  = "SUBMIT_API_ADDR"
+            value = local.submit_api_addr
+          }
+
+          env {
+            name  = "JSONRPC_ADDR"
+            value = local.jsonrpc_addr
+          }
+
+          env {
+            name  = "MEMPOOL_ADDR"
+            value = local.mempool_addr
+          }
+
           env {
             name  = "PROMETHEUS_ADDR"
             value = local.prometheus_addr
@@ -86,6 +139,14 @@ resource "kubernetes_deployment_v1" "node_proxy" {
             value = "/configs/tiers.toml"
           }
 
+          dynamic "env" {
+            for_each = length(var.relay_networks) > 0 ? toset(["relay"]) : toset([])
+            content {
+              name  = "RELAY_NETWORKS"
+              value = join(",", [for network, port in var.relay_networks : "${network}=${port}"])
+            }
+          }
+
           volume_mount {
             mount_path = "/certs"
             name       = "certs"
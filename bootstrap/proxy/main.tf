@@ -6,6 +6,14 @@ locals {
   prometheus_addr = "0.0.0.0:${local.prometheus_port}"
   proxy_port      = 8080
   proxy_addr      = "0.0.0.0:${local.proxy_port}"
+  websocket_port  = 8081
+  websocket_addr  = "0.0.0.0:${local.websocket_port}"
+  submit_api_port = 8082
+  submit_api_addr = "0.0.0.0:${local.submit_api_port}"
+  jsonrpc_port    = 8083
+  jsonrpc_addr    = "0.0.0.0:${local.jsonrpc_port}"
+  mempool_port    = 8084
+  mempool_addr    = "0.0.0.0:${local.mempool_port}"
   proxy_labels    = var.environment != null ? { role = "${local.role}-${var.environment}" } : { role = local.role }
 }
 
@@ -68,6 +76,12 @@ variable "node_port" {
   default = 3307
 }
 
+variable "relay_networks" {
+  description = "Node-to-node port the proxy relays for each network"
+  type        = map(number)
+  default     = {}
+}
+
 variable "node_dns" {
   type    = string
   default = "ftr-nodes-v2.svc.cluster.local"
@@ -26,6 +26,34 @@ resource "kubernetes_service_v1" "proxy_service_aws" {
       protocol    = "TCP"
     }
 
+    port {
+      name        = "websocket"
+      port        = 443
+      target_port = local.websocket_port
+      protocol    = "TCP"
+    }
+
+    port {
+      name        = "submit-api"
+      port        = 8090
+      target_port = local.submit_api_port
+      protocol    = "TCP"
+    }
+
+    port {
+      name        = "jsonrpc"
+      port        = 1337
+      target_port = local.jsonrpc_port
+      protocol    = "TCP"
+    }
+
+    port {
+      name        = "mempool"
+      port        = 8443
+      target_port = local.mempool_port
+      protocol    = "TCP"
+    }
+
     port {
       name        = "health"
       port        = 80
@@ -60,6 +88,34 @@ resource "kubernetes_service_v1" "proxy_service_gcp" {
       protocol    = "TCP"
     }
 
+    port {
+      name        = "websocket"
+      port        = 443
+      target_port = local.websocket_port
+      protocol    = "TCP"
+    }
+
+    port {
+      name        = "submit-api"
+      port        = 8090
+      target_port = local.submit_api_port
+      protocol    = "TCP"
+    }
+
+    port {
+      name        = "jsonrpc"
+      port        = 1337
+      target_port = local.jsonrpc_port
+      protocol    = "TCP"
+    }
+
+    port {
+      name        = "mempool"
+      port        = 8443
+      target_port = local.mempool_port
+      protocol    = "TCP"
+    }
+
     port {
       name        = "health"
       port        = 80
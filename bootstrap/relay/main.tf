@@ -7,6 +7,14 @@ variable "cloud_provider" {
   default = "aws"
 }
 
+variable "selector" {
+  description = "pods behind the relay, the nodes or the proxy when it relays node-to-node traffic"
+  type        = map(string)
+  default = {
+    "role" = "node"
+  }
+}
+
 resource "kubernetes_service_v1" "node-relay-n2n-aws" {
   for_each = toset([for n in toset(["loadbalancer"]) : n if var.cloud_provider == "aws"])
   metadata {
@@ -23,9 +31,7 @@ resource "kubernetes_service_v1" "node-relay-n2n-aws" {
     type                = "LoadBalancer"
     load_balancer_class = "service.k8s.aws/nlb"
 
-    selector = {
-      "role"    = "node"
-    }
+    selector = var.selector
 
     port {
       name        = "mainnet"
@@ -64,9 +70,7 @@ resource "kubernetes_service_v1" "node-relay-n2n-gcp" {
     type                    = "LoadBalancer"
     external_traffic_policy = "Local"
 
-    selector = {
-      "role"    = "node"
-    }
+    selector = var.selector
 
     port {
       name        = "mainnet"
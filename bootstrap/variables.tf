@@ -37,6 +37,11 @@ variable "metrics_delay" {
   default = 60
 }
 
+variable "token_rotation_grace" {
+  type    = number
+  default = 86400
+}
+
 variable "operator_resources" {
   type = object({
     limits = object({
@@ -16,6 +16,7 @@ pub struct Config {
     pub api_key_salt: String,
     pub metrics_delay: Duration,
     pub prometheus_url: String,
+    pub token_rotation_grace: Duration,
 }
 
 impl Config {
@@ -31,6 +32,14 @@ impl Config {
                     .expect("METRICS_DELAY must be a number"),
             ),
             prometheus_url: env::var("PROMETHEUS_URL").expect("PROMETHEUS_URL must be set"),
+            token_rotation_grace: Duration::from_secs(
+                env::var("TOKEN_ROTATION_GRACE")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("TOKEN_ROTATION_GRACE must be a number")
+                    })
+                    .unwrap_or(86400),
+            ),
         }
     }
 }
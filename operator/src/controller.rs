@@ -1,3 +1,4 @@
+use chrono::{DateTime, TimeDelta, Utc};
 use futures::StreamExt;
 use kube::{
     api::ListParams,
@@ -9,9 +10,13 @@ use serde::{Deserialize, Serialize};
 use std::{sync::Arc, time::Duration};
 use tracing::{error, info};
 
-use crate::{build_api_key, build_hostname, patch_resource_status, Error, Metrics, Result, State};
+use crate::{
+    build_api_key, build_hostname, get_config, patch_resource_status, Error, Metrics, Result, State,
+};
 
 pub static CARDANO_NODE_PORT_FINALIZER: &str = "cardanonodeports.demeter.run";
+/// Setting a new value on the annotation mints a new token for the port.
+pub static ROTATE_TOKEN_ANNOTATION: &str = "demeter.run/rotate-token";
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[kube(
@@ -28,7 +33,8 @@ pub static CARDANO_NODE_PORT_FINALIZER: &str = "cardanonodeports.demeter.run";
         {"name": "Version", "jsonPath": ".spec.version", "type": "string"},
         {"name": "Throughput Tier", "jsonPath": ".spec.throughputTier", "type": "string"},
         {"name": "Authenticated Endpoint URL", "jsonPath": ".status.authenticatedEndpointUrl", "type": "string"},
-        {"name": "Auth Token", "jsonPath": ".status.authToken", "type": "string"}
+        {"name": "Auth Token", "jsonPath": ".status.authToken", "type": "string"},
+        {"name": "State", "jsonPath": ".status.state", "type": "string"}
     "#)]
 #[serde(rename_all = "camelCase")]
 pub struct CardanoNodePortSpec {
@@ -36,6 +42,25 @@ pub struct CardanoNodePortSpec {
     pub version: String,
     pub throughput_tier: String,
     pub auth_token: Option<String>,
+    pub read_only: Option<bool>,
+    pub client_certificate: Option<ClientCertificate>,
+    /// RFC 3339 time the token starts being accepted.
+    #[schemars(extend("format" = "date-time"))]
+    pub valid_from: Option<String>,
+    /// RFC 3339 time the token stops being accepted. Open connections are closed then.
+    #[schemars(extend("format" = "date-time"))]
+    pub valid_until: Option<String>,
+}
+
+/// Client certificate the proxy requires on the TLS connections of the port.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientCertificate {
+    /// SHA-256 fingerprint of the certificate in hex. Connections with it are identified by the
+    /// certificate, so the token isn't needed in the SNI.
+    pub fingerprint: Option<String>,
+    /// PEM of the CAs that issue the certificates. The token is still needed to find the port.
+    pub ca: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
@@ -43,6 +68,34 @@ pub struct CardanoNodePortSpec {
 pub struct CardanoNodePortStatus {
     pub authenticated_endpoint_url: String,
     pub auth_token: String,
+    /// Tokens the proxy accepts for the port, the current one first.
+    #[serde(default)]
+    pub auth_tokens: Vec<AuthToken>,
+    /// Whether the time is within the validity window of the port.
+    #[serde(default)]
+    pub state: TokenState,
+    /// Values of the rotate annotation already applied, the latest last. Reusing one doesn't
+    /// mint its token again.
+    #[serde(default)]
+    pub rotations: Vec<String>,
+    /// `authToken` of the spec when it was last applied, to tell when it changes.
+    pub spec_auth_token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, JsonSchema, PartialEq)]
+pub enum TokenState {
+    Pending,
+    #[default]
+    Active,
+    Expired,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthToken {
+    pub token: String,
+    /// RFC 3339 time a replaced token stops being accepted. The current token has none.
+    pub not_after: Option<String>,
 }
 
 struct Context {
@@ -56,14 +109,50 @@ impl Context {
 }
 
 async fn reconcile(crd: Arc<CardanoNodePort>, ctx: Arc<Context>) -> Result<Action> {
-    let key = match &crd.spec.auth_token {
-        Some(key) => key.clone(),
-        None => build_api_key(&crd).await?,
+    let current = crd.status.as_ref();
+    let mut rotations = current.map(|s| s.rotations.clone()).unwrap_or_default();
+    let spec_changed = current.is_some_and(|s| s.spec_auth_token != crd.spec.auth_token);
+
+    // The current token is kept until the annotation gets a new value or the spec token changes.
+    let key = match (new_rotation(&crd), current_token(current)) {
+        (Some(rotation), _) => {
+            rotations.push(rotation.to_string());
+            build_api_key(&crd, Some(rotation)).await?
+        }
+        (None, Some(key)) if !spec_changed || crd.spec.auth_token.is_none() => key.to_string(),
+        (None, _) => match &crd.spec.auth_token {
+            Some(key) => key.clone(),
+            None => build_api_key(&crd, None).await?,
+        },
+    };
+
+    let now = Utc::now();
+    let grace = TimeDelta::from_std(get_config().token_rotation_grace).unwrap_or_default();
+    let auth_tokens = active_tokens(current, &key, now, grace);
+
+    let valid_from = parse_time(crd.spec.valid_from.as_deref())?;
+    let valid_until = parse_time(crd.spec.valid_until.as_deref())?;
+    let state = match (valid_from, valid_until) {
+        (Some(from), _) if now < from => TokenState::Pending,
+        (_, Some(until)) if now >= until => TokenState::Expired,
+        _ => TokenState::Active,
     };
 
+    let next_change = auth_tokens
+        .iter()
+        .filter_map(not_after)
+        .chain(valid_from)
+        .chain(valid_until)
+        .filter(|time| *time > now)
+        .min();
+
     let status = CardanoNodePortStatus {
         authenticated_endpoint_url: build_hostname(&key),
         auth_token: key,
+        auth_tokens,
+        state,
+        rotations,
+        spec_auth_token: crd.spec.auth_token.clone(),
     };
 
     let namespace = crd.namespace().unwrap();
@@ -80,7 +169,82 @@ async fn reconcile(crd: Arc<CardanoNodePort>, ctx: Arc<Context>) -> Result<Actio
 
     info!(resource = crd.name_any(), "Reconcile completed");
 
-    Ok(Action::await_change())
+    // Reconciles again to drop the replaced tokens once their grace period ends, and to update the
+    // state when the validity window opens or closes.
+    match next_change {
+        Some(time) => Ok(Action::requeue(
+            (time - now).to_std().unwrap_or_default() + Duration::from_secs(1),
+        )),
+        None => Ok(Action::await_change()),
+    }
+}
+
+/// Value of the rotate annotation when the port wasn't rotated with it yet.
+fn new_rotation(crd: &CardanoNodePort) -> Option<&str> {
+    let rotation = crd.annotations().get(ROTATE_TOKEN_ANNOTATION)?;
+    let rotations = crd.status.as_ref().map(|s| s.rotations.as_slice());
+    match rotations {
+        Some(rotations) if rotations.contains(rotation) => None,
+        _ => Some(rotation),
+    }
+}
+
+/// Token the port has now, if it was reconciled before.
+fn current_token(status: Option<&CardanoNodePortStatus>) -> Option<&str> {
+    status
+        .map(|s| s.auth_token.as_str())
+        .filter(|token| !token.is_empty())
+}
+
+/// Tokens of the port once `key` is the current one. Tokens it replaces stay valid for the
+/// rotation grace period.
+fn active_tokens(
+    status: Option<&CardanoNodePortStatus>,
+    key: &str,
+    now: DateTime<Utc>,
+    grace: TimeDelta,
+) -> Vec<AuthToken> {
+    let mut tokens = match status {
+        Some(status) if !status.auth_tokens.is_empty() => status.auth_tokens.clone(),
+        // Statuses written before the token list.
+        Some(status) if !status.auth_token.is_empty() => vec![AuthToken {
+            token: status.auth_token.clone(),
+            not_after: None,
+        }],
+        _ => vec![],
+    };
+
+    for token in tokens.iter_mut() {
+        if token.not_after.is_none() {
+            token.not_after = Some((now + grace).to_rfc3339());
+        }
+    }
+    tokens.retain(|token| token.token != key && not_after(token).is_some_and(|t| t > now));
+
+    tokens.insert(
+        0,
+        AuthToken {
+            token: key.to_string(),
+            not_after: None,
+        },
+    );
+    tokens
+}
+
+fn parse_time(time: Option<&str>) -> Result<Option<DateTime<Utc>>> {
+    let Some(time) = time else {
+        return Ok(None);
+    };
+    Ok(Some(
+        DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc),
+    ))
+}
+
+fn not_after(token: &AuthToken) -> Option<DateTime<Utc>> {
+    let not_after = token.not_after.as_ref()?;
+    DateTime::parse_from_rfc3339(not_after)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
 }
 
 fn error_policy(crd: Arc<CardanoNodePort>, err: &Error, ctx: Arc<Context>) -> Action {
@@ -111,3 +275,136 @@ pub async fn run(state: Arc<State>) {
         .for_each(|_| futures::future::ready(()))
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(tokens: &[(&str, Option<DateTime<Utc>>)]) -> CardanoNodePortStatus {
+        CardanoNodePortStatus {
+            auth_token: tokens[0].0.into(),
+            auth_tokens: tokens
+                .iter()
+                .map(|(token, not_after)| AuthToken {
+                    token: token.to_string(),
+                    not_after: not_after.map(|t| t.to_rfc3339()),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn port(rotation: Option<&str>, rotations: &[&str]) -> CardanoNodePort {
+        let mut port = CardanoNodePort::new(
+            "port",
+            CardanoNodePortSpec {
+                network: "mainnet".into(),
+                version: "stable".into(),
+                throughput_tier: "0".into(),
+                auth_token: None,
+                read_only: None,
+                client_certificate: None,
+                valid_from: None,
+                valid_until: None,
+            },
+        );
+        if let Some(rotation) = rotation {
+            port.annotations_mut()
+                .insert(ROTATE_TOKEN_ANNOTATION.into(), rotation.into());
+        }
+        port.status = Some(CardanoNodePortStatus {
+            auth_token: "current".into(),
+            rotations: rotations.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        });
+        port
+    }
+
+    #[test]
+    fn active_tokens_first_token() {
+        let now = Utc::now();
+        let tokens = active_tokens(None, "key", now, TimeDelta::hours(1));
+        assert_eq!(
+            tokens,
+            vec![AuthToken {
+                token: "key".into(),
+                not_after: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn active_tokens_rotation_starts_grace() {
+        let now = Utc::now();
+        let grace = TimeDelta::hours(1);
+        let tokens = active_tokens(Some(&status(&[("old", None)])), "new", now, grace);
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].token, "new");
+        assert_eq!(tokens[0].not_after, None);
+        assert_eq!(tokens[1].token, "old");
+        assert_eq!(not_after(&tokens[1]), Some(now + grace));
+
+        // Reconciling again keeps the grace period that started with the rotation.
+        let status = CardanoNodePortStatus {
+            auth_token: "new".into(),
+            auth_tokens: tokens.clone(),
+            ..Default::default()
+        };
+        let later = now + TimeDelta::minutes(30);
+        assert_eq!(active_tokens(Some(&status), "new", later, grace), tokens);
+    }
+
+    #[test]
+    fn active_tokens_drop_after_grace() {
+        let now = Utc::now();
+        let status = status(&[
+            ("new", None),
+            ("old", Some(now + TimeDelta::minutes(5))),
+            ("older", Some(now)),
+            ("oldest", Some(now - TimeDelta::minutes(5))),
+        ]);
+        let tokens = active_tokens(Some(&status), "new", now, TimeDelta::hours(1));
+        let tokens: Vec<&str> = tokens.iter().map(|t| t.token.as_str()).collect();
+        assert_eq!(tokens, vec!["new", "old"]);
+
+        let later = now + TimeDelta::minutes(10);
+        let tokens = active_tokens(Some(&status), "new", later, TimeDelta::hours(1));
+        assert_eq!(tokens.len(), 1);
+    }
+
+    #[test]
+    fn active_tokens_reactivated_token_is_current() {
+        let now = Utc::now();
+        let status = status(&[("new", None), ("old", Some(now + TimeDelta::minutes(5)))]);
+        let tokens = active_tokens(Some(&status), "old", now, TimeDelta::hours(1));
+        assert_eq!(tokens[0].token, "old");
+        assert_eq!(tokens[0].not_after, None);
+        assert_eq!(tokens[1].token, "new");
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn active_tokens_status_before_token_list() {
+        let now = Utc::now();
+        let status = CardanoNodePortStatus {
+            auth_token: "old".into(),
+            ..Default::default()
+        };
+        let tokens = active_tokens(Some(&status), "new", now, TimeDelta::hours(1));
+        let tokens: Vec<&str> = tokens.iter().map(|t| t.token.as_str()).collect();
+        assert_eq!(tokens, vec!["new", "old"]);
+    }
+
+    #[test]
+    fn rotation_only_on_unseen_values() {
+        assert_eq!(new_rotation(&port(Some("1"), &[])), Some("1"));
+        assert_eq!(new_rotation(&port(Some("2"), &["1"])), Some("2"));
+        assert_eq!(new_rotation(&port(Some("2"), &["1", "2"])), None);
+        // Going back to an earlier value doesn't mint its retired token again.
+        assert_eq!(new_rotation(&port(Some("1"), &["1", "2"])), None);
+        // Removing the annotation keeps the current token.
+        let removed = port(None, &["1", "2"]);
+        assert_eq!(new_rotation(&removed), None);
+        assert_eq!(current_token(removed.status.as_ref()), Some("current"));
+    }
+}
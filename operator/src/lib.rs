@@ -20,6 +20,9 @@ pub enum Error {
 
     #[error("Config Error: {0}")]
     ConfigError(String),
+
+    #[error("Time Error: {0}")]
+    TimeError(String),
 }
 impl Error {
     pub fn metric_label(&self) -> String {
@@ -51,6 +54,11 @@ impl From<bech32::primitives::hrp::Error> for Error {
         Error::Bech32Error(value.to_string())
     }
 }
+impl From<chrono::ParseError> for Error {
+    fn from(value: chrono::ParseError) -> Self {
+        Error::TimeError(value.to_string())
+    }
+}
 impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self {
         Error::HttpError(value.to_string())
@@ -35,11 +35,14 @@ pub fn build_hostname(key: &str) -> String {
     format!("{key}.{extension_name}.{dns_zone}")
 }
 
-pub async fn build_api_key(crd: &CardanoNodePort) -> Result<String, Error> {
+/// Rotations mix the value of the rotation annotation into the key, so each one mints a new key.
+pub async fn build_api_key(crd: &CardanoNodePort, rotation: Option<&str>) -> Result<String, Error> {
     let namespace = crd.namespace().unwrap();
     let name = format!("cardano-node-auth-{}", &crd.name_any());
 
-    let password = format!("{}{}", name, namespace).as_bytes().to_vec();
+    let password = format!("{}{}{}", name, namespace, rotation.unwrap_or_default())
+        .as_bytes()
+        .to_vec();
 
     let config = get_config();
     let salt = config.api_key_salt.as_bytes();
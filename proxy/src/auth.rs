@@ -24,11 +24,94 @@ impl AuthBackgroundService {
     }
 
     async fn sync_consumer(&self, mut consumer: Consumer) -> Consumer {
-        if let Some(old_consumer) = self.state.consumers.read().await.clone().get(&consumer.key) {
+        let old_consumer = {
+            let consumers = self.state.consumers.read().await;
+            consumers
+                .get(&consumer.key)
+                .or_else(|| consumers.values().find(|c| c.is_port(&consumer)))
+                .cloned()
+        };
+        if let Some(old_consumer) = &old_consumer {
             consumer.active_connections = old_consumer.active_connections;
+            if old_consumer.key != consumer.key {
+                info!(consumer = consumer.to_string(), "auth: token rotated");
+                self.rotate_key(&old_consumer.key, &consumer).await;
+            }
+        }
+
+        if !consumer.is_valid() {
+            info!(
+                consumer = consumer.to_string(),
+                "auth: port outside its validity window"
+            );
         }
+
+        // A capture starts when the annotation is added or changed and stops when it's removed.
+        let old_capture = old_consumer.and_then(|c| c.capture);
+        match consumer.capture {
+            Some(connections) if old_capture != consumer.capture => {
+                info!(
+                    consumer = consumer.to_string(),
+                    connections, "auth: capture requested"
+                );
+                self.state
+                    .captures
+                    .write()
+                    .await
+                    .insert(consumer.key.clone(), connections);
+            }
+            Some(_) => {}
+            None => {
+                self.state.captures.write().await.remove(&consumer.key);
+            }
+        }
+
         consumer
     }
+
+    /// Forgets the port, so none of its tokens is accepted anymore.
+    async fn remove_port(&self, crd: &CardanoNodePort) {
+        let namespace = crd.namespace().unwrap_or_default();
+        let name = crd.name_any();
+
+        let keys: Vec<Vec<u8>> = {
+            let mut consumers = self.state.consumers.write().await;
+            let keys: Vec<Vec<u8>> = consumers
+                .values()
+                .filter(|consumer| consumer.namespace == namespace && consumer.port_name == name)
+                .map(|consumer| consumer.key.clone())
+                .collect();
+            for key in &keys {
+                consumers.remove(key);
+            }
+            keys
+        };
+        for key in &keys {
+            self.state.limiter.write().await.remove(key);
+            self.state.query_limiter.write().await.remove(key);
+            self.state.tx_limiter.write().await.remove(key);
+            self.state.captures.write().await.remove(key);
+        }
+    }
+
+    /// Moves the state of the port from its replaced key to the new one. The port is replaced in
+    /// one step, so the connections opened with the old key always find it by the port.
+    async fn rotate_key(&self, old_key: &[u8], consumer: &Consumer) {
+        let key = &consumer.key;
+        {
+            let mut consumers = self.state.consumers.write().await;
+            consumers.remove(old_key);
+            consumers.insert(key.clone(), consumer.clone());
+        }
+        self.state.limiter.write().await.remove(old_key);
+        self.state.query_limiter.write().await.remove(old_key);
+        self.state.tx_limiter.write().await.remove(old_key);
+
+        let mut captures = self.state.captures.write().await;
+        if let Some(connections) = captures.remove(old_key) {
+            captures.insert(key.to_vec(), connections);
+        }
+    }
 }
 
 #[async_trait]
@@ -55,13 +138,12 @@ impl BackgroundService for AuthBackgroundService {
                     info!("auth: Initial apply for: {}", crd.name_any());
                     // Handle initial resource like a new/modified one
                     if crd.status.is_some() {
-                        let result = Consumer::new(&crd);
-                        if let Err(error) = result {
-                            error!(?error, "invalid consumer");
+                        let Some(consumer) = valid_consumer(&crd) else {
+                            self.remove_port(&crd).await;
                             continue;
-                        }
+                        };
 
-                        let consumer = self.sync_consumer(result.unwrap()).await;
+                        let consumer = self.sync_consumer(consumer).await;
                         self.state
                             .consumers
                             .write()
@@ -78,15 +160,17 @@ impl BackgroundService for AuthBackgroundService {
                     Some(_) => {
                         info!("auth: Adding new consumer: {}", crd.name_any());
 
-                        let result = Consumer::new(&crd);
-                        if let Err(error) = result {
-                            error!(?error, "invalid consumer");
+                        // The port keeps no credentials from before an invalid update.
+                        let Some(consumer) = valid_consumer(&crd) else {
+                            self.remove_port(&crd).await;
                             continue;
-                        }
+                        };
 
-                        let consumer = self.sync_consumer(result.unwrap()).await;
+                        let consumer = self.sync_consumer(consumer).await;
 
                         self.state.limiter.write().await.remove(&consumer.key);
+                        self.state.query_limiter.write().await.remove(&consumer.key);
+                        self.state.tx_limiter.write().await.remove(&consumer.key);
                         self.state
                             .consumers
                             .write()
@@ -106,14 +190,7 @@ impl BackgroundService for AuthBackgroundService {
                         crd.name_any()
                     );
 
-                    let result = Consumer::new(&crd);
-                    if let Err(error) = result {
-                        error!(?error, "invalid consumer");
-                        continue;
-                    }
-                    let consumer = result.unwrap();
-                    self.state.consumers.write().await.remove(&consumer.key);
-                    self.state.limiter.write().await.remove(&consumer.key);
+                    self.remove_port(&crd).await;
                 }
                 // Empty response from stream. Should never happen.
                 Ok(None) => {
@@ -129,3 +206,80 @@ impl BackgroundService for AuthBackgroundService {
         }
     }
 }
+
+/// Consumer of the port, logging why the port is invalid when it is.
+fn valid_consumer(crd: &CardanoNodePort) -> Option<Consumer> {
+    Consumer::new(crd)
+        .map_err(|error| error!(?error, port = crd.name_any(), "invalid consumer"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use operator::{kube::api::ObjectMeta, CardanoNodePortSpec};
+
+    use super::*;
+
+    fn port(namespace: &str, name: &str) -> CardanoNodePort {
+        CardanoNodePort {
+            metadata: ObjectMeta {
+                namespace: Some(namespace.into()),
+                name: Some(name.into()),
+                ..Default::default()
+            },
+            spec: CardanoNodePortSpec {
+                network: "mainnet".into(),
+                version: "stable".into(),
+                throughput_tier: "0".into(),
+                auth_token: None,
+                read_only: None,
+                client_certificate: None,
+                valid_from: None,
+                valid_until: None,
+            },
+            status: None,
+        }
+    }
+
+    fn consumer(namespace: &str, name: &str, key: &[u8]) -> Consumer {
+        Consumer {
+            namespace: namespace.into(),
+            port_name: name.into(),
+            key: key.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn remove_port_by_name() {
+        let state = State::shared();
+        let auth = AuthBackgroundService::new(state.clone());
+        for consumer in [
+            consumer("prj-auth", "removed", b"removed"),
+            consumer("prj-auth", "kept", b"kept"),
+            consumer("prj-other", "removed", b"other namespace"),
+        ] {
+            state
+                .limiter
+                .write()
+                .await
+                .insert(consumer.key.clone(), vec![]);
+            state
+                .consumers
+                .write()
+                .await
+                .insert(consumer.key.clone(), consumer);
+        }
+
+        // The port is found by its name, even when its spec no longer builds a consumer.
+        auth.remove_port(&port("prj-auth", "removed")).await;
+
+        let consumers = state.consumers.read().await;
+        assert!(!consumers.contains_key(b"removed".as_slice()));
+        assert!(consumers.contains_key(b"kept".as_slice()));
+        assert!(consumers.contains_key(b"other namespace".as_slice()));
+        let limiters = state.limiter.read().await;
+        assert!(!limiters.contains_key(b"removed".as_slice()));
+        assert!(limiters.contains_key(b"kept".as_slice()));
+    }
+}
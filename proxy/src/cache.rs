@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use pallas_codec::minicbor::{decode, Decoder};
+use pallas_network::miniprotocols::handshake::VersionNumber;
+
+/// Local-state-query results shared by the consumers of each network and node version, apart by
+/// N2C version. Instances of different versions may not be at the same tip, so each one follows
+/// its own. Only the results for the tip are kept, so they are all dropped once a newer tip is
+/// acquired.
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    instances: HashMap<(String, String), InstanceCache>,
+}
+
+#[derive(Debug, Default)]
+struct InstanceCache {
+    tip: Vec<u8>,
+    slot: u64,
+    results: HashMap<(VersionNumber, Vec<u8>), Vec<u8>>,
+}
+
+impl QueryCache {
+    /// Moves the tip of the network and node version when the point acquired by a consumer is
+    /// newer.
+    pub fn observe_point(
+        &mut self,
+        network: &str,
+        node_version: &str,
+        point: &[u8],
+    ) -> Result<(), decode::Error> {
+        let slot = slot(point)?;
+        let cache = self
+            .instances
+            .entry((network.to_string(), node_version.to_string()))
+            .or_default();
+        if cache.tip.is_empty() || slot > cache.slot {
+            cache.tip = point.to_vec();
+            cache.slot = slot;
+            cache.results.clear();
+        }
+        Ok(())
+    }
+
+    pub fn get(
+        &self,
+        network: &str,
+        node_version: &str,
+        version: VersionNumber,
+        point: &[u8],
+        query: &[u8],
+    ) -> Option<Vec<u8>> {
+        let cache = self
+            .instances
+            .get(&(network.to_string(), node_version.to_string()))?;
+        if cache.tip != point {
+            return None;
+        }
+        cache.results.get(&(version, query.to_vec())).cloned()
+    }
+
+    pub fn insert(
+        &mut self,
+        network: &str,
+        node_version: &str,
+        version: VersionNumber,
+        point: &[u8],
+        query: &[u8],
+        result: &[u8],
+    ) {
+        let key = (network.to_string(), node_version.to_string());
+        if let Some(cache) = self.instances.get_mut(&key) {
+            if cache.tip == point {
+                cache
+                    .results
+                    .insert((version, query.to_vec()), result.to_vec());
+            }
+        }
+    }
+}
+
+// Points are `[]` for the origin and `[slot, hash]` for any other block.
+fn slot(point: &[u8]) -> Result<u64, decode::Error> {
+    let mut d = Decoder::new(point);
+    match d.array()? {
+        Some(0) => Ok(0),
+        _ => d.u64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas_codec::minicbor::Encoder;
+
+    use super::*;
+
+    const QUERY: &[u8] = &[0x82, 0x03, 0x80];
+
+    fn point(slot: u64) -> Vec<u8> {
+        let mut e = Encoder::new(Vec::new());
+        e.array(2)
+            .unwrap()
+            .u64(slot)
+            .unwrap()
+            .bytes(&[slot as u8; 32])
+            .unwrap();
+        e.into_writer()
+    }
+
+    #[test]
+    fn hit_at_the_tip() {
+        let mut cache = QueryCache::default();
+        cache
+            .observe_point("mainnet", "stable", &point(10))
+            .unwrap();
+        assert_eq!(cache.get("mainnet", "stable", 16, &point(10), QUERY), None);
+
+        cache.insert("mainnet", "stable", 16, &point(10), QUERY, b"result");
+        assert_eq!(
+            cache.get("mainnet", "stable", 16, &point(10), QUERY),
+            Some(b"result".to_vec())
+        );
+        // Other N2C versions and networks have their own results.
+        assert_eq!(cache.get("mainnet", "stable", 17, &point(10), QUERY), None);
+        assert_eq!(cache.get("preprod", "stable", 16, &point(10), QUERY), None);
+
+        // Results for points other than the tip aren't kept.
+        cache.insert("mainnet", "stable", 16, &point(9), QUERY, b"old");
+        assert_eq!(cache.get("mainnet", "stable", 16, &point(9), QUERY), None);
+    }
+
+    #[test]
+    fn miss_on_tip_change() {
+        let mut cache = QueryCache::default();
+        cache
+            .observe_point("mainnet", "stable", &point(10))
+            .unwrap();
+        cache.insert("mainnet", "stable", 16, &point(10), QUERY, b"result");
+
+        // An older point doesn't move the tip.
+        cache.observe_point("mainnet", "stable", &point(9)).unwrap();
+        assert!(cache
+            .get("mainnet", "stable", 16, &point(10), QUERY)
+            .is_some());
+
+        cache
+            .observe_point("mainnet", "stable", &point(11))
+            .unwrap();
+        assert_eq!(cache.get("mainnet", "stable", 16, &point(10), QUERY), None);
+        assert_eq!(cache.get("mainnet", "stable", 16, &point(11), QUERY), None);
+    }
+
+    #[test]
+    fn tip_by_node_version() {
+        let mut cache = QueryCache::default();
+        cache
+            .observe_point("mainnet", "stable", &point(11))
+            .unwrap();
+        cache.insert("mainnet", "stable", 16, &point(11), QUERY, b"stable");
+
+        // An instance behind the other version keeps its own tip and results.
+        cache
+            .observe_point("mainnet", "10.1.4", &point(10))
+            .unwrap();
+        cache.insert("mainnet", "10.1.4", 16, &point(10), QUERY, b"10.1.4");
+        assert_eq!(
+            cache.get("mainnet", "10.1.4", 16, &point(10), QUERY),
+            Some(b"10.1.4".to_vec())
+        );
+        assert_eq!(
+            cache.get("mainnet", "stable", 16, &point(11), QUERY),
+            Some(b"stable".to_vec())
+        );
+        assert_eq!(cache.get("mainnet", "stable", 16, &point(10), QUERY), None);
+    }
+
+    #[test]
+    fn invalid_point() {
+        let mut cache = QueryCache::default();
+        assert!(cache.observe_point("mainnet", "stable", &[0x01]).is_err());
+        assert!(cache.observe_point("mainnet", "stable", &[0x80]).is_ok());
+    }
+}
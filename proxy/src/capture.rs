@@ -0,0 +1,213 @@
+use std::{
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use crate::mux::{Direction, Frame, FrameDecoder, HEADER_LEN};
+
+/// Capture files start with the magic and have a record per mux frame: the unix timestamp in
+/// microseconds (u64), the direction (u8, 0 is inbound and 1 is outbound), the frame length (u32)
+/// and the frame with its header. Integers are big endian.
+pub const MAGIC: &[u8; 8] = b"NPXCAP01";
+
+const RECORD_HEADER_LEN: usize = 13;
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub frame: Frame,
+}
+
+/// Writes the records of a connection until the file would go past `max_bytes`.
+pub struct CaptureWriter {
+    file: BufWriter<File>,
+    written: usize,
+    max_bytes: usize,
+}
+impl CaptureWriter {
+    pub async fn create(path: &Path, max_bytes: usize) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path).await?);
+        file.write_all(MAGIC).await?;
+        Ok(Self {
+            file,
+            written: MAGIC.len(),
+            max_bytes,
+        })
+    }
+
+    /// Writes the record of the frame, or returns `false` without writing it when the file would
+    /// go past the limit.
+    pub async fn write(&mut self, direction: Direction, frame: &Frame) -> io::Result<bool> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let direction: u8 = match direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        };
+        let frame = frame.encode();
+        let len = RECORD_HEADER_LEN + frame.len();
+        if self.written + len > self.max_bytes {
+            return Ok(false);
+        }
+        self.written += len;
+
+        self.file.write_all(&timestamp.to_be_bytes()).await?;
+        self.file.write_all(&[direction]).await?;
+        self.file
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await?;
+        self.file.write_all(&frame).await?;
+        Ok(true)
+    }
+
+    pub async fn close(mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+}
+
+pub fn read(bytes: &[u8]) -> Result<Vec<Record>, String> {
+    let records = bytes
+        .strip_prefix(MAGIC)
+        .ok_or("invalid capture file, missing magic")?;
+
+    let mut position = 0;
+    let mut result = Vec::new();
+    while position < records.len() {
+        let header = records
+            .get(position..position + RECORD_HEADER_LEN)
+            .ok_or("truncated capture record")?;
+        let timestamp = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let direction = match header[8] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            _ => return Err("invalid capture record direction".into()),
+        };
+        let len = u32::from_be_bytes(header[9..13].try_into().unwrap()) as usize;
+        position += RECORD_HEADER_LEN;
+
+        let bytes = records
+            .get(position..position + len)
+            .filter(|bytes| bytes.len() >= HEADER_LEN)
+            .ok_or("truncated capture record")?;
+        position += len;
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(bytes);
+        let frame = decoder.next_frame().ok_or("invalid capture frame")?;
+
+        result.push(Record {
+            timestamp,
+            direction,
+            frame,
+        });
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::{Header, Mode, Protocol};
+
+    fn frame(protocol: Protocol, mode: Mode, payload: &[u8]) -> Frame {
+        Frame {
+            header: Header {
+                timestamp: 42,
+                mode,
+                protocol,
+                payload_len: payload.len() as u16,
+            },
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn write_read_round_trip() {
+        let path = std::env::temp_dir().join(format!("capture-{}.cap", std::process::id()));
+        let frames = [
+            (
+                Direction::Inbound,
+                frame(Protocol::LocalStateQuery, Mode::Initiator, &[0x81, 0x08]),
+            ),
+            (
+                Direction::Outbound,
+                frame(Protocol::LocalStateQuery, Mode::Responder, &[0x81, 0x01]),
+            ),
+            (
+                Direction::Outbound,
+                frame(Protocol::ChainSync, Mode::Responder, &[]),
+            ),
+        ];
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        let mut writer = CaptureWriter::create(&path, usize::MAX).await.unwrap();
+        for (direction, frame) in &frames {
+            assert!(writer.write(*direction, frame).await.unwrap());
+        }
+        writer.close().await.unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records = read(&bytes).unwrap();
+
+        assert_eq!(records.len(), frames.len());
+        for (record, (direction, frame)) in records.iter().zip(&frames) {
+            assert_eq!(record.direction, *direction);
+            assert_eq!(record.frame, *frame);
+            assert!(record.timestamp >= started);
+        }
+    }
+
+    #[test]
+    fn read_rejects_invalid_files() {
+        assert!(read(b"NOTACAPT").is_err());
+        assert_eq!(read(MAGIC).unwrap().len(), 0);
+
+        let mut truncated = MAGIC.to_vec();
+        truncated.extend_from_slice(&[0; RECORD_HEADER_LEN - 1]);
+        assert!(read(&truncated).is_err());
+
+        let mut direction = MAGIC.to_vec();
+        direction.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 8]);
+        direction.extend_from_slice(&[0; 8]);
+        assert!(read(&direction).is_err());
+
+        let mut short_frame = MAGIC.to_vec();
+        short_frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4]);
+        short_frame.extend_from_slice(&[0; 4]);
+        assert!(read(&short_frame).is_err());
+    }
+
+    #[tokio::test]
+    async fn write_stops_at_max_bytes() {
+        let path = std::env::temp_dir().join(format!("capture-max-{}.cap", std::process::id()));
+        let frame = frame(Protocol::ChainSync, Mode::Responder, &[0; 10]);
+        let record_len = RECORD_HEADER_LEN + frame.encode().len();
+
+        // Room for two records and part of a third one.
+        let max_bytes = MAGIC.len() + 2 * record_len + record_len / 2;
+        let mut writer = CaptureWriter::create(&path, max_bytes).await.unwrap();
+        assert!(writer.write(Direction::Outbound, &frame).await.unwrap());
+        assert!(writer.write(Direction::Outbound, &frame).await.unwrap());
+        assert!(!writer.write(Direction::Outbound, &frame).await.unwrap());
+        writer.close().await.unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(bytes.len() <= max_bytes);
+        assert_eq!(read(&bytes).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,180 @@
+use openssl::{
+    hash::MessageDigest,
+    stack::Stack,
+    x509::{store::X509StoreBuilder, X509StoreContext, X509},
+};
+use pingora::tls::ssl::SslRef;
+
+/// Certificate a client presented on the TLS handshake, with the intermediates it sent.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    certificate: X509,
+    chain: Vec<X509>,
+}
+impl ClientCertificate {
+    pub fn from_ssl(ssl: &SslRef) -> Option<Self> {
+        let certificate = ssl.peer_certificate()?;
+        let chain = ssl
+            .peer_cert_chain()
+            .map(|chain| chain.iter().map(|c| c.to_owned()).collect())
+            .unwrap_or_default();
+        Some(Self { certificate, chain })
+    }
+
+    /// SHA-256 of the DER certificate.
+    pub fn fingerprint(&self) -> Vec<u8> {
+        self.certificate
+            .digest(MessageDigest::sha256())
+            .map(|digest| digest.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Whether the certificate chains up to one of the CAs.
+    pub fn issued_by(&self, cas: &[X509]) -> bool {
+        let verify = || -> Result<bool, openssl::error::ErrorStack> {
+            let mut store = X509StoreBuilder::new()?;
+            for ca in cas {
+                store.add_cert(ca.clone())?;
+            }
+            let store = store.build();
+
+            let mut chain = Stack::new()?;
+            for certificate in &self.chain {
+                chain.push(certificate.clone())?;
+            }
+
+            let mut context = X509StoreContext::new()?;
+            context.init(&store, &self.certificate, &chain, |c| c.verify_cert())
+        };
+        verify().unwrap_or(false)
+    }
+}
+
+/// Parses a hex fingerprint, with or without colons between the bytes.
+pub fn parse_fingerprint(value: &str) -> Result<Vec<u8>, String> {
+    let fingerprint = hex::decode(value.replace(':', "")).map_err(|err| err.to_string())?;
+    if fingerprint.len() != 32 {
+        return Err("fingerprint must be a SHA-256 hash".into());
+    }
+    Ok(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::{PKey, Private},
+        x509::{extension::BasicConstraints, X509Name},
+    };
+
+    use super::*;
+
+    /// Certificate for `name`, signed by `issuer` or by itself.
+    fn certificate(name: &str, issuer: Option<&(X509, PKey<Private>)>) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((ca, ca_key)) => {
+                builder.set_issuer_name(ca.subject_name()).unwrap();
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                builder.append_extension(ca).unwrap();
+                builder.set_issuer_name(&subject).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn parse_fingerprint_formats() {
+        let hex = "a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90";
+        let fingerprint = hex::decode(hex).unwrap();
+        assert_eq!(parse_fingerprint(hex).unwrap(), fingerprint);
+        assert_eq!(parse_fingerprint(&hex.to_uppercase()).unwrap(), fingerprint);
+
+        let colons = fingerprint
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn parse_fingerprint_invalid() {
+        assert!(parse_fingerprint("").is_err());
+        assert!(parse_fingerprint("a1b2c3").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+        assert!(parse_fingerprint(&"a1".repeat(31)).is_err());
+        assert!(parse_fingerprint(&"a1".repeat(33)).is_err());
+        assert!(parse_fingerprint(&format!("{}a", "a1".repeat(32))).is_err());
+    }
+
+    #[test]
+    fn fingerprint_is_sha256_of_der() {
+        let (certificate, _) = certificate("client", None);
+        let der = certificate.to_der().unwrap();
+        let client = ClientCertificate {
+            certificate,
+            chain: vec![],
+        };
+
+        let fingerprint = client.fingerprint();
+        assert_eq!(
+            fingerprint,
+            openssl::sha::sha256(&der).to_vec(),
+            "fingerprint must be the SHA-256 of the DER"
+        );
+        assert_eq!(
+            parse_fingerprint(&hex::encode(&fingerprint)).unwrap(),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn issued_by_checks_the_chain() {
+        let ca = certificate("ca", None);
+        let other_ca = certificate("other ca", None);
+        let (leaf, _) = certificate("client", Some(&ca));
+        let client = ClientCertificate {
+            certificate: leaf,
+            chain: vec![],
+        };
+
+        assert!(client.issued_by(std::slice::from_ref(&ca.0)));
+        assert!(client.issued_by(&[other_ca.0.clone(), ca.0.clone()]));
+        assert!(!client.issued_by(std::slice::from_ref(&other_ca.0)));
+        assert!(!client.issued_by(&[]));
+
+        // A self-signed client certificate isn't issued by the CA.
+        let (own, _) = certificate("client", None);
+        let client = ClientCertificate {
+            certificate: own,
+            chain: vec![],
+        };
+        assert!(!client.issued_by(&[ca.0]));
+    }
+}
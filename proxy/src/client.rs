@@ -0,0 +1,106 @@
+use std::{error::Error, time::Duration};
+
+use pingora::{connectors::TransportConnector, protocols::Stream, upstreams::peer::BasicPeer};
+use tokio::{
+    io::AsyncWriteExt,
+    net::lookup_host,
+    time::{error::Elapsed, timeout},
+};
+
+use crate::{
+    handshake,
+    mux::{FrameDecoder, Header, MessageDecoder, Mode, Protocol},
+    proxy::{read_message, write_message},
+};
+
+/// N2C session the proxy opens to an instance by itself, for the endpoints that don't carry mux
+/// frames from the client.
+pub struct NodeSession {
+    io: Stream,
+    frames: FrameDecoder,
+    messages: MessageDecoder,
+}
+impl NodeSession {
+    /// Connects to the instance and agrees on a version for the network magic.
+    pub async fn connect(
+        connector: &TransportConnector,
+        instance: &str,
+        magic: u64,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let node_addr = lookup_host(instance)
+            .await?
+            .next()
+            .ok_or("node address not found")?;
+        let io = connector
+            .new_stream(&BasicPeer::new(&node_addr.to_string()))
+            .await?;
+
+        let mut session = Self {
+            io,
+            frames: FrameDecoder::new(),
+            messages: MessageDecoder::new(),
+        };
+        let reply = session
+            .request(Protocol::Handshake, &handshake::propose(magic))
+            .await?;
+        if handshake::accepted_version(&reply)?.is_none() {
+            return Err("handshake refused by the node".into());
+        }
+
+        Ok(session)
+    }
+
+    pub async fn send(
+        &mut self,
+        protocol: Protocol,
+        message: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let header = Header {
+            timestamp: 0,
+            mode: Mode::Initiator,
+            protocol,
+            payload_len: 0,
+        };
+        write_message(&mut self.io, &header, message).await;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    pub async fn receive(
+        &mut self,
+        protocol: Protocol,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        read_message(&mut self.io, &mut self.frames, &mut self.messages, protocol).await
+    }
+
+    /// Like `receive`, for replies the node only sends once there is something new.
+    pub async fn wait(
+        &mut self,
+        protocol: Protocol,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        loop {
+            match self.receive(protocol).await {
+                Err(err) if err.is::<Elapsed>() => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Like `wait`, giving up after `limit`.
+    pub async fn wait_for(
+        &mut self,
+        protocol: Protocol,
+        limit: Duration,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        timeout(limit, self.wait(protocol)).await?
+    }
+
+    pub async fn request(
+        &mut self,
+        protocol: Protocol,
+        message: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        self.send(protocol, message).await?;
+        self.receive(protocol).await
+    }
+}
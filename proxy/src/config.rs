@@ -1,8 +1,17 @@
-use std::{env, path::PathBuf, time::Duration};
+use std::{collections::HashMap, env, net::IpAddr, path::PathBuf, time::Duration};
+
+use ipnet::IpNet;
+use pallas_network::miniprotocols::{MAINNET_MAGIC, PREPROD_MAGIC, PREVIEW_MAGIC, SANCHONET_MAGIC};
+
+use crate::mux::MAX_SEGMENT_PAYLOAD_LEN;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub proxy_addr: String,
+    pub proxy_ws_addr: Option<String>,
+    pub submit_api_addr: Option<String>,
+    pub jsonrpc_addr: Option<String>,
+    pub mempool_addr: Option<String>,
     pub proxy_namespace: String,
     pub proxy_tiers_path: PathBuf,
     pub proxy_tiers_poll_interval: Duration,
@@ -11,11 +20,32 @@ pub struct Config {
     pub ssl_key_path: String,
     pub node_port: u16,
     pub node_dns: String,
+    pub network_magics: HashMap<String, u64>,
+    pub state_query_cache: Vec<String>,
+    pub chain_sync_window: usize,
+    pub chain_sync_failover: bool,
+    pub capture_path: Option<PathBuf>,
+    pub capture_namespaces: Vec<String>,
+    pub capture_max_bytes: usize,
+    pub max_sdu_payload_len: usize,
+    pub max_message_len: usize,
+    pub handshake_timeout: Duration,
+    pub relay_networks: HashMap<String, u16>,
+    pub relay_node_release: String,
+    pub relay_node_port: u16,
+    pub relay_max_connections_per_ip: Option<usize>,
+    pub relay_max_peer_metrics: usize,
+    pub relay_allow: Vec<IpNet>,
+    pub relay_deny: Vec<IpNet>,
 }
 impl Config {
     pub fn new() -> Self {
         Self {
             proxy_addr: env::var("PROXY_ADDR").expect("PROXY_ADDR must be set"),
+            proxy_ws_addr: env::var("PROXY_WS_ADDR").ok(),
+            submit_api_addr: env::var("SUBMIT_API_ADDR").ok(),
+            jsonrpc_addr: env::var("JSONRPC_ADDR").ok(),
+            mempool_addr: env::var("MEMPOOL_ADDR").ok(),
             proxy_namespace: env::var("PROXY_NAMESPACE").expect("PROXY_NAMESPACE must be set"),
             proxy_tiers_path: env::var("PROXY_TIERS_PATH")
                 .map(|v| v.into())
@@ -36,7 +66,108 @@ impl Config {
                 .parse()
                 .expect("NODE_PORT must a number"),
             node_dns: env::var("NODE_DNS").expect("NODE_DNS must be set"),
+            network_magics: env::var("NETWORK_MAGICS")
+                .map(|v| parse_network_magics(&v))
+                .unwrap_or_else(|_| default_network_magics()),
+            state_query_cache: env::var("STATE_QUERY_CACHE")
+                .map(|v| {
+                    v.split(',')
+                        .map(|v| v.trim().to_string())
+                        .filter(|v| !v.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            chain_sync_window: env::var("CHAIN_SYNC_WINDOW")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("CHAIN_SYNC_WINDOW must be a number of blocks. eg: 100")
+                })
+                .unwrap_or_default(),
+            chain_sync_failover: env::var("CHAIN_SYNC_FAILOVER")
+                .map(|v| {
+                    v.parse::<bool>()
+                        .expect("CHAIN_SYNC_FAILOVER must be true or false")
+                })
+                .unwrap_or_default(),
+            capture_path: env::var("CAPTURE_PATH").map(|v| v.into()).ok(),
+            capture_namespaces: env::var("CAPTURE_NAMESPACES")
+                .map(|v| {
+                    v.split(',')
+                        .map(|v| v.trim().to_string())
+                        .filter(|v| !v.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            capture_max_bytes: env::var("CAPTURE_MAX_BYTES")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("CAPTURE_MAX_BYTES must be a number of bytes. eg: 104857600")
+                })
+                .unwrap_or(100 * 1024 * 1024),
+            max_sdu_payload_len: env::var("MAX_SDU_PAYLOAD_LEN")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("MAX_SDU_PAYLOAD_LEN must be a number of bytes. eg: 12288")
+                })
+                .unwrap_or(MAX_SEGMENT_PAYLOAD_LEN),
+            max_message_len: env::var("MAX_MESSAGE_LEN")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("MAX_MESSAGE_LEN must be a number of bytes. eg: 65536")
+                })
+                .unwrap_or(65536),
+            handshake_timeout: env::var("HANDSHAKE_TIMEOUT")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("HANDSHAKE_TIMEOUT must be a number in seconds. eg: 10"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(10)),
+            relay_networks: env::var("RELAY_NETWORKS")
+                .map(|v| parse_relay_networks(&v))
+                .unwrap_or_default(),
+            relay_node_release: env::var("RELAY_NODE_RELEASE").unwrap_or("stable".into()),
+            relay_node_port: env::var("RELAY_NODE_PORT")
+                .map(|v| v.parse().expect("RELAY_NODE_PORT must a number"))
+                .unwrap_or(3000),
+            relay_max_connections_per_ip: env::var("RELAY_MAX_CONNECTIONS_PER_IP")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("RELAY_MAX_CONNECTIONS_PER_IP must be a number. eg: 10")
+                })
+                .ok(),
+            relay_max_peer_metrics: env::var("RELAY_MAX_PEER_METRICS")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("RELAY_MAX_PEER_METRICS must be a number. eg: 1000")
+                })
+                .unwrap_or(1000),
+            relay_allow: env::var("RELAY_ALLOW")
+                .map(|v| parse_peers(&v))
+                .unwrap_or_default(),
+            relay_deny: env::var("RELAY_DENY")
+                .map(|v| parse_peers(&v))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Config with only the variables that must be set.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        for (key, value) in [
+            ("PROXY_ADDR", "0.0.0.0:5000"),
+            ("PROXY_NAMESPACE", "ftr-node"),
+            ("PROXY_TIERS_PATH", "tiers.toml"),
+            ("PROMETHEUS_ADDR", "0.0.0.0:9090"),
+            ("SSL_CRT_PATH", "localhost.crt"),
+            ("SSL_KEY_PATH", "localhost.key"),
+            ("NODE_PORT", "3307"),
+            ("NODE_DNS", "ftr-node.svc.cluster.local"),
+        ] {
+            env::set_var(key, value);
         }
+        Self::new()
     }
 }
 impl Default for Config {
@@ -44,3 +175,96 @@ impl Default for Config {
         Self::new()
     }
 }
+
+fn default_network_magics() -> HashMap<String, u64> {
+    HashMap::from([
+        ("mainnet".into(), MAINNET_MAGIC),
+        ("preprod".into(), PREPROD_MAGIC),
+        ("preview".into(), PREVIEW_MAGIC),
+        ("sanchonet".into(), SANCHONET_MAGIC),
+    ])
+}
+
+fn parse_network_magics(value: &str) -> HashMap<String, u64> {
+    value
+        .split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| {
+            let (network, magic) = v
+                .split_once('=')
+                .expect("NETWORK_MAGICS must be a list of network=magic. eg: mainnet=764824073");
+            let magic = magic
+                .trim()
+                .parse::<u64>()
+                .expect("NETWORK_MAGICS magic must be a number");
+            (network.trim().to_string(), magic)
+        })
+        .collect()
+}
+
+fn parse_relay_networks(value: &str) -> HashMap<String, u16> {
+    value
+        .split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| {
+            let (network, port) = v
+                .split_once('=')
+                .expect("RELAY_NETWORKS must be a list of network=port. eg: mainnet=3000");
+            let port = port
+                .trim()
+                .parse::<u16>()
+                .expect("RELAY_NETWORKS port must be a number");
+            (network.trim().to_string(), port)
+        })
+        .collect()
+}
+
+// Peers are CIDRs or single addresses. eg: 10.0.0.0/8,192.168.1.10
+fn parse_peers(value: &str) -> Vec<IpNet> {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            v.parse::<IpNet>()
+                .or_else(|_| v.parse::<IpAddr>().map(IpNet::from))
+                .unwrap_or_else(|_| panic!("invalid relay peer {v}, it must be an ip or a cidr"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mux::{Frame, FrameDecoder, FrameError, Header, Mode, Protocol};
+
+    use super::*;
+
+    /// Frame of `len` bytes as the proxy decodes it from the client.
+    fn client_frame(len: usize) -> Frame {
+        let header = Header {
+            timestamp: 0,
+            mode: Mode::Initiator,
+            protocol: Protocol::LocalStateQuery,
+            payload_len: len as u16,
+        };
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&header.encode());
+        decoder.extend(&vec![0; len]);
+        decoder.next_frame().unwrap()
+    }
+
+    #[test]
+    fn oversized_sdu_rejected_by_default() {
+        let config = Config::for_tests();
+        assert_eq!(config.max_sdu_payload_len, MAX_SEGMENT_PAYLOAD_LEN);
+
+        let frame = client_frame(MAX_SEGMENT_PAYLOAD_LEN);
+        assert!(frame.validate_inbound(config.max_sdu_payload_len).is_ok());
+
+        let frame = client_frame(MAX_SEGMENT_PAYLOAD_LEN + 1);
+        assert!(matches!(
+            frame.validate_inbound(config.max_sdu_payload_len),
+            Err(FrameError::Oversized)
+        ));
+    }
+}
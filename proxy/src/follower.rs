@@ -0,0 +1,394 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use pallas_codec::minicbor::{decode, Decoder};
+use pallas_crypto::hash::Hasher;
+use pallas_network::{
+    facades::NodeClient,
+    miniprotocols::{
+        chainsync::{BlockContent, NextResponse, Tip},
+        handshake::{n2c::VersionTable, Confirmation},
+        Point,
+    },
+    multiplexer::Bearer,
+};
+use tokio::sync::{watch, RwLock};
+use tracing::{error, info};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Time without consumers after which the follower closes its session.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Chain-sync update received by the follower, replayed to the consumers served from the window.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Forward(BlockContent),
+    Backward(Point),
+}
+
+pub enum Next {
+    Event(Event, Tip),
+    Await,
+    /// The events the consumer needs are not in the window anymore.
+    Behind,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    /// Sequence number of the first event.
+    offset: u64,
+    events: VecDeque<Event>,
+    /// Points of the current chain, with the sequence number of the event that follows each one.
+    chain: VecDeque<(Point, u64)>,
+    tip: Option<Tip>,
+}
+impl Window {
+    fn end(&self) -> u64 {
+        self.offset + self.events.len() as u64
+    }
+
+    fn roll_forward(&mut self, block: BlockContent, point: Point, tip: Tip, size: usize) {
+        self.events.push_back(Event::Forward(block));
+        self.chain.push_back((point, self.end()));
+        self.tip = Some(tip);
+
+        while self.events.len() > size {
+            self.events.pop_front();
+            self.offset += 1;
+        }
+        while self
+            .chain
+            .front()
+            .is_some_and(|(_, sequence)| *sequence < self.offset)
+        {
+            self.chain.pop_front();
+        }
+    }
+
+    fn roll_backward(&mut self, point: Point, tip: Tip) {
+        match self.chain.iter().rposition(|(p, _)| *p == point) {
+            Some(position) => {
+                self.chain.truncate(position + 1);
+                self.events.push_back(Event::Backward(point));
+                self.chain[position].1 = self.end();
+            }
+            // The rollback goes past the window, so it starts over from the point.
+            None => {
+                self.reset();
+                self.chain.push_back((point, self.end()));
+            }
+        }
+        self.tip = Some(tip);
+    }
+
+    // Skips a sequence number, so every consumer served from the old window falls behind.
+    fn reset(&mut self) {
+        self.offset = self.end() + 1;
+        self.events.clear();
+        self.chain.clear();
+        self.tip = None;
+    }
+}
+
+/// Upstream chain-sync session shared by the consumers of an instance. It follows the tip and
+/// keeps a window with the last events, so consumers that intersect inside of it are served by
+/// the proxy instead of the node. It stops once no consumer is served from it or tried to
+/// intersect for `IDLE_TIMEOUT`.
+pub struct Follower {
+    window: RwLock<Window>,
+    sequence: watch::Sender<u64>,
+    last_used: Mutex<Instant>,
+    stopped: AtomicBool,
+}
+impl Follower {
+    fn new() -> Self {
+        Self {
+            window: RwLock::default(),
+            sequence: watch::Sender::new(0),
+            last_used: Mutex::new(Instant::now()),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn spawn(address: String, magic: u64, size: usize) -> Arc<Self> {
+        let follower = Arc::new(Self::new());
+        tokio::spawn(follower.clone().run(address, magic, size));
+        follower
+    }
+
+    /// Stopped followers are replaced by a new one for the next consumer.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Time since the follower was last used, `None` while consumers are served from it.
+    fn idle_for(&self) -> Option<Duration> {
+        if self.sequence.receiver_count() > 0 {
+            return None;
+        }
+        Some(self.last_used.lock().unwrap().elapsed())
+    }
+
+    fn is_idle(&self) -> bool {
+        self.idle_for().is_some_and(|idle| idle >= IDLE_TIMEOUT)
+    }
+
+    /// Notified every time the window changes.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.sequence.subscribe()
+    }
+
+    /// Finds the first of the points on the window, with the sequence number to resume from it.
+    pub async fn intersect(&self, points: &[Point]) -> Option<(Point, u64, Tip)> {
+        *self.last_used.lock().unwrap() = Instant::now();
+        let window = self.window.read().await;
+        let tip = window.tip.clone()?;
+        points.iter().find_map(|point| {
+            window
+                .chain
+                .iter()
+                .find(|(p, _)| p == point)
+                .map(|(p, sequence)| (p.clone(), *sequence, tip.clone()))
+        })
+    }
+
+    pub async fn next(&self, sequence: u64) -> Next {
+        let window = self.window.read().await;
+        if sequence < window.offset {
+            return Next::Behind;
+        }
+        let Some(tip) = window.tip.clone() else {
+            return Next::Await;
+        };
+        match window.events.get((sequence - window.offset) as usize) {
+            Some(event) => Next::Event(event.clone(), tip),
+            None => Next::Await,
+        }
+    }
+
+    pub async fn tip(&self) -> Option<Tip> {
+        self.window.read().await.tip.clone()
+    }
+
+    async fn run(self: Arc<Self>, address: String, magic: u64, size: usize) {
+        loop {
+            if let Err(err) = self.follow(&address, magic, size).await {
+                error!(
+                    error = err.to_string(),
+                    address, "chain-sync follower error"
+                );
+            }
+
+            let mut window = self.window.write().await;
+            window.reset();
+            self.sequence.send_replace(window.end());
+            drop(window);
+
+            if self.is_idle() {
+                self.stopped.store(true, Ordering::Relaxed);
+                info!(address, "chain-sync follower stopped without consumers");
+                return;
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+
+    async fn follow(&self, address: &str, magic: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let bearer = Bearer::connect_tcp(address).await?;
+        let mut client = NodeClient::new(bearer);
+
+        let versions = VersionTable::v10_and_above(magic);
+        if let Confirmation::Rejected(reason) = client.handshake().handshake(versions).await? {
+            return Err(format!("handshake refused: {reason:?}").into());
+        }
+
+        let point = client.chainsync().intersect_tip().await?;
+        info!(address, ?point, "chain-sync follower started");
+
+        loop {
+            let response = client.chainsync().request_or_await_next().await?;
+
+            let mut window = self.window.write().await;
+            match response {
+                NextResponse::RollForward(block, tip) => {
+                    let point = block_point(&block)?;
+                    window.roll_forward(block, point, tip, size);
+                }
+                NextResponse::RollBackward(point, tip) => window.roll_backward(point, tip),
+                NextResponse::Await => continue,
+            }
+            self.sequence.send_replace(window.end());
+            drop(window);
+
+            if self.is_idle() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// N2C blocks are wrapped as `[era, block]`. Since Shelley the block starts with the header,
+// `[header_body, signature]`, the header body has the slot as its second item and the block hash
+// is the hash of the header.
+pub fn block_point(block: &[u8]) -> Result<Point, decode::Error> {
+    let mut d = Decoder::new(block);
+    d.array()?;
+    if d.u16()? < 2 {
+        return Err(decode::Error::message("byron blocks are not supported"));
+    }
+    d.array()?;
+
+    let start = d.position();
+    d.array()?;
+    d.array()?;
+    d.skip()?;
+    let slot = d.u64()?;
+
+    d.set_position(start);
+    d.skip()?;
+    let hash = Hasher::<256>::hash(&block[start..d.position()]);
+
+    Ok(Point::Specific(slot, hash.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas_codec::minicbor::Encoder;
+
+    use super::*;
+
+    fn point(slot: u64) -> Point {
+        Point::Specific(slot, vec![slot as u8; 32])
+    }
+
+    fn tip(slot: u64) -> Tip {
+        Tip(point(slot), slot)
+    }
+
+    fn forward(window: &mut Window, slot: u64, size: usize) {
+        window.roll_forward(BlockContent(vec![slot as u8]), point(slot), tip(slot), size);
+    }
+
+    fn chain(window: &Window) -> Vec<(Point, u64)> {
+        window.chain.iter().cloned().collect()
+    }
+
+    #[test]
+    fn window_keeps_the_last_events() {
+        let mut window = Window::default();
+        for slot in 1..=4 {
+            forward(&mut window, slot, 2);
+        }
+
+        assert_eq!(window.offset, 2);
+        assert_eq!(window.end(), 4);
+        assert!(matches!(&window.events[0], Event::Forward(block) if block.0 == [3]));
+        // Each point resumes from the event after it, the ones whose next event left the window
+        // can't be resumed from.
+        assert_eq!(
+            chain(&window),
+            [(point(2), 2), (point(3), 3), (point(4), 4)]
+        );
+        assert_eq!(window.tip, Some(tip(4)));
+    }
+
+    #[test]
+    fn window_rollback_inside() {
+        let mut window = Window::default();
+        for slot in 1..=3 {
+            forward(&mut window, slot, 10);
+        }
+        window.roll_backward(point(2), tip(2));
+
+        assert!(matches!(window.events.back(), Some(Event::Backward(p)) if *p == point(2)));
+        // Consumers intersecting at the rollback point resume after the rollback.
+        assert_eq!(chain(&window), [(point(1), 1), (point(2), 4)]);
+        assert_eq!(window.tip, Some(tip(2)));
+
+        forward(&mut window, 4, 10);
+        assert_eq!(
+            chain(&window),
+            [(point(1), 1), (point(2), 4), (point(4), 5)]
+        );
+    }
+
+    #[test]
+    fn window_rollback_past_the_window() {
+        let mut window = Window::default();
+        for slot in 5..=6 {
+            forward(&mut window, slot, 10);
+        }
+        window.roll_backward(point(1), tip(1));
+
+        // The sequence number after the old window is skipped.
+        assert_eq!(window.offset, 3);
+        assert!(window.events.is_empty());
+        assert_eq!(chain(&window), [(point(1), 3)]);
+        assert_eq!(window.tip, Some(tip(1)));
+    }
+
+    #[tokio::test]
+    async fn reset_leaves_consumers_behind() {
+        let follower = Follower::new();
+        {
+            let mut window = follower.window.write().await;
+            forward(&mut window, 1, 10);
+            forward(&mut window, 2, 10);
+        }
+        assert!(matches!(
+            follower.next(1).await,
+            Next::Event(Event::Forward(_), _)
+        ));
+        assert!(matches!(follower.next(2).await, Next::Await));
+
+        follower.window.write().await.reset();
+        assert_eq!(follower.window.read().await.offset, 3);
+        assert!(matches!(follower.next(2).await, Next::Behind));
+        // Nothing is served until the follower is back at the tip.
+        assert!(matches!(follower.next(3).await, Next::Await));
+        assert!(follower.tip().await.is_none());
+        assert!(follower.intersect(&[point(1)]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idle_without_consumers() {
+        let follower = Follower::new();
+        assert!(follower.idle_for().is_some());
+        assert!(!follower.is_idle());
+
+        let updates = follower.subscribe();
+        assert!(follower.idle_for().is_none());
+        drop(updates);
+
+        *follower.last_used.lock().unwrap() = Instant::now() - IDLE_TIMEOUT;
+        assert!(follower.is_idle());
+        follower.intersect(&[point(1)]).await;
+        assert!(!follower.is_idle());
+    }
+
+    #[test]
+    fn point_of_a_block() {
+        let mut e = Encoder::new(Vec::new());
+        e.array(2).unwrap();
+        e.array(2).unwrap().u64(10).unwrap().u64(1234).unwrap();
+        e.bytes(b"signature").unwrap();
+        let header = e.into_writer();
+        let hash = Hasher::<256>::hash(&header);
+
+        let block = [&[0x82, 0x07, 0x82][..], &header, &[0x80]].concat();
+        assert_eq!(
+            block_point(&block).unwrap(),
+            Point::Specific(1234, hash.to_vec())
+        );
+
+        assert!(block_point(&[0x82, 0x01, 0x80]).is_err());
+        assert!(block_point(&[0x82, 0x07, 0x80]).is_err());
+    }
+}
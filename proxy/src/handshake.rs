@@ -0,0 +1,189 @@
+use pallas_codec::{
+    minicbor::{self, data::Type, decode, Decoder},
+    utils::AnyCbor,
+};
+use pallas_network::miniprotocols::handshake::{
+    n2c::VersionTable, Message, NetworkMagic, RefuseReason, VersionNumber,
+};
+
+/// Versions proposed by the client on `MsgProposeVersions`, with the network magic of each one.
+#[derive(Debug, Clone)]
+pub struct Proposal {
+    pub versions: Vec<(VersionNumber, NetworkMagic)>,
+}
+impl Proposal {
+    /// Returns `None` when the message is a valid handshake message other than a proposal.
+    pub fn decode(message: &[u8]) -> Result<Option<Self>, decode::Error> {
+        let message: Message<AnyCbor> = minicbor::decode(message)?;
+        let Message::Propose(table) = message else {
+            return Ok(None);
+        };
+
+        let mut versions = table
+            .values
+            .iter()
+            .map(|(version, data)| Ok((*version, decode_magic(data.raw_bytes())?)))
+            .collect::<Result<Vec<_>, decode::Error>>()?;
+        versions.sort();
+
+        Ok(Some(Self { versions }))
+    }
+
+    pub fn highest_version(&self) -> VersionNumber {
+        self.versions
+            .last()
+            .map(|(version, _)| *version)
+            .unwrap_or_default()
+    }
+
+    /// The first magic proposed that is different from the expected one.
+    pub fn mismatched_magic(&self, expected: NetworkMagic) -> Option<NetworkMagic> {
+        self.versions
+            .iter()
+            .map(|(_, magic)| *magic)
+            .find(|magic| *magic != expected)
+    }
+}
+
+// N2C version data is the bare network magic until v15, and `[magic, query]` after it.
+fn decode_magic(data: &[u8]) -> Result<NetworkMagic, decode::Error> {
+    let mut decoder = Decoder::new(data);
+    if decoder.datatype()? == Type::Array {
+        decoder.array()?;
+    }
+    decoder.u64()
+}
+
+/// Version accepted by the node on `MsgAcceptVersion`.
+pub fn accepted_version(message: &[u8]) -> Result<Option<VersionNumber>, decode::Error> {
+    let message: Message<AnyCbor> = minicbor::decode(message)?;
+    match message {
+        Message::Accept(version, _) => Ok(Some(version)),
+        _ => Ok(None),
+    }
+}
+
+/// `MsgProposeVersions` with the N2C versions known by pallas, for the sessions the proxy opens.
+pub fn propose(magic: NetworkMagic) -> Vec<u8> {
+    minicbor::to_vec(Message::Propose(VersionTable::v10_and_above(magic))).unwrap()
+}
+
+pub fn refuse(version: VersionNumber, reason: &str) -> Vec<u8> {
+    let message: Message<AnyCbor> =
+        Message::Refuse(RefuseReason::Refused(version, reason.to_string()));
+    minicbor::to_vec(message).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas_codec::minicbor::Encoder;
+    use pallas_network::miniprotocols::{MAINNET_MAGIC, PREPROD_MAGIC};
+
+    use super::*;
+
+    const V10: VersionNumber = 32778;
+    const V16: VersionNumber = 32784;
+
+    /// `MsgProposeVersions` with v16 data as `[magic, query]` and v10 data as the bare magic.
+    fn proposal(v16_magic: NetworkMagic, v10_magic: NetworkMagic) -> Vec<u8> {
+        let mut e = Encoder::new(Vec::new());
+        e.array(2).unwrap().u16(0).unwrap().map(2).unwrap();
+        e.u64(V16).unwrap();
+        e.array(2)
+            .unwrap()
+            .u64(v16_magic)
+            .unwrap()
+            .bool(false)
+            .unwrap();
+        e.u64(V10).unwrap().u64(v10_magic).unwrap();
+        e.into_writer()
+    }
+
+    #[test]
+    fn proposal_with_both_version_data() {
+        let proposal = Proposal::decode(&proposal(MAINNET_MAGIC, MAINNET_MAGIC))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            proposal.versions,
+            [(V10, MAINNET_MAGIC), (V16, MAINNET_MAGIC)]
+        );
+        assert_eq!(proposal.highest_version(), V16);
+        assert_eq!(proposal.mismatched_magic(MAINNET_MAGIC), None);
+        assert_eq!(
+            proposal.mismatched_magic(PREPROD_MAGIC),
+            Some(MAINNET_MAGIC)
+        );
+    }
+
+    #[test]
+    fn proposal_with_mismatched_magic() {
+        let proposal = Proposal::decode(&proposal(MAINNET_MAGIC, PREPROD_MAGIC))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            proposal.versions,
+            [(V10, PREPROD_MAGIC), (V16, MAINNET_MAGIC)]
+        );
+        assert_eq!(
+            proposal.mismatched_magic(MAINNET_MAGIC),
+            Some(PREPROD_MAGIC)
+        );
+        assert_eq!(
+            proposal.mismatched_magic(PREPROD_MAGIC),
+            Some(MAINNET_MAGIC)
+        );
+    }
+
+    #[test]
+    fn proposal_of_the_proxy() {
+        let proposal = Proposal::decode(&propose(PREPROD_MAGIC)).unwrap().unwrap();
+        assert_eq!(proposal.versions.first(), Some(&(V10, PREPROD_MAGIC)));
+        assert_eq!(proposal.mismatched_magic(PREPROD_MAGIC), None);
+    }
+
+    #[test]
+    fn other_messages() {
+        // `MsgAcceptVersion` with v16 data.
+        let mut e = Encoder::new(Vec::new());
+        e.array(3).unwrap().u16(1).unwrap().u64(V16).unwrap();
+        e.array(2)
+            .unwrap()
+            .u64(MAINNET_MAGIC)
+            .unwrap()
+            .bool(false)
+            .unwrap();
+        let accept = e.into_writer();
+
+        assert!(Proposal::decode(&accept).unwrap().is_none());
+        assert_eq!(accepted_version(&accept).unwrap(), Some(V16));
+        assert_eq!(
+            accepted_version(&proposal(MAINNET_MAGIC, MAINNET_MAGIC)).unwrap(),
+            None
+        );
+        assert!(Proposal::decode(&[0x82, 0x09]).is_err());
+    }
+
+    #[test]
+    fn refuse_round_trip() {
+        // The proxy refuses with the highest version proposed by the client.
+        let proposal = Proposal::decode(&proposal(PREPROD_MAGIC, PREPROD_MAGIC))
+            .unwrap()
+            .unwrap();
+        let reply = refuse(proposal.highest_version(), "denied");
+
+        // `[2, [2, version, reason]]`
+        let mut expected = vec![0x82, 0x02, 0x83, 0x02, 0x19, 0x80, 0x10, 0x66];
+        expected.extend_from_slice(b"denied");
+        assert_eq!(reply, expected);
+
+        let message: Message<AnyCbor> = minicbor::decode(&reply).unwrap();
+        let Message::Refuse(RefuseReason::Refused(version, reason)) = message else {
+            panic!("expected MsgRefuse with a refused reason, got {message:?}");
+        };
+        assert_eq!(version, V16);
+        assert_eq!(reason, "denied");
+        assert!(Proposal::decode(&reply).unwrap().is_none());
+        assert_eq!(accepted_version(&reply).unwrap(), None);
+    }
+}
@@ -0,0 +1,1344 @@
+use std::{collections::VecDeque, error::Error, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bech32::{Bech32, Hrp};
+use pallas_codec::minicbor::{self, data::Type, decode, Decoder, Encoder};
+use pallas_network::miniprotocols::{
+    chainsync::{BlockContent, Message as ChainSyncMessage, Tip},
+    Point,
+};
+use pingora::{
+    apps::ServerApp, connectors::TransportConnector, protocols::Stream, server::ShutdownWatch,
+};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    pin, select,
+    time::{sleep, timeout},
+};
+use tracing::{error, warn};
+
+use crate::{
+    client::NodeSession,
+    config::Config,
+    follower,
+    mux::{Direction, Protocol},
+    proxy::{budget, throttle},
+    query::{self, Query, StateQuery},
+    websocket::{
+        self, Request, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_CONTINUATION, OPCODE_PING, OPCODE_PONG,
+        OPCODE_TEXT,
+    },
+    Consumer, State, Tier,
+};
+
+/// Time the client has to send the request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the node has to answer a state query. Queries like the UTxO of an address can take the
+/// node much longer than a read on the other protocols.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest request body or WebSocket message read from the client.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+const METHODS: &[&str] = &[
+    "queryNetwork/tip",
+    "queryLedgerState/epoch",
+    "queryLedgerState/protocolParameters",
+    "queryLedgerState/utxo",
+    "findIntersection",
+    "nextBlock",
+];
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The node couldn't be reached or replied something unexpected.
+const NODE_ERROR: i64 = -32000;
+/// The tier doesn't allow the mini-protocol or the query, or the budget ran out.
+const DENIED: i64 = -32001;
+const INTERSECTION_NOT_FOUND: i64 = -32002;
+/// Chain-sync keeps its position on the session of a WebSocket connection.
+const WEBSOCKET_REQUIRED: i64 = -32003;
+
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+impl From<Box<dyn Error + Send + Sync>> for RpcError {
+    fn from(value: Box<dyn Error + Send + Sync>) -> Self {
+        Self::new(NODE_ERROR, value.to_string())
+    }
+}
+impl From<decode::Error> for RpcError {
+    fn from(value: decode::Error) -> Self {
+        Self::new(NODE_ERROR, format!("invalid reply from the node: {value}"))
+    }
+}
+
+/// JSON-RPC gateway for clients without an Ouroboros implementation. Requests are translated to
+/// N2C mini-protocol sessions against the consumer's instance, with the same token, tier limits
+/// and metrics as the TLS listener. WebSocket connections keep their session between requests.
+pub struct JsonRpcApp {
+    client_connector: TransportConnector,
+    host_regex: Regex,
+    config: Arc<Config>,
+    state: Arc<State>,
+}
+impl JsonRpcApp {
+    pub fn new(config: Arc<Config>, state: Arc<State>) -> Self {
+        Self {
+            client_connector: TransportConnector::new(None),
+            host_regex: Regex::new(r"([\w\d-]+)\..+").unwrap(),
+            config,
+            state,
+        }
+    }
+
+    async fn consumer(&self, host: Option<&str>) -> Option<Consumer> {
+        let token = self.host_regex.captures(host?)?.get(1)?.as_str();
+        let (_hrp, key) = bech32::decode(token).ok()?;
+        self.state
+            .get_consumer(&key)
+            .await
+            .filter(|consumer| consumer.accepts(None))
+    }
+}
+
+#[async_trait]
+impl ServerApp for JsonRpcApp {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut io_client: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let request = match timeout(REQUEST_TIMEOUT, websocket::read_request(&mut io_client)).await
+        {
+            Ok(Ok(request)) => request,
+            Ok(Err(reason)) => {
+                respond(&mut io_client, "400 Bad Request", &json!(reason)).await;
+                return None;
+            }
+            Err(_) => return None,
+        };
+
+        let Some(consumer) = self.consumer(request.host()).await else {
+            respond(&mut io_client, "401 Unauthorized", &json!("unknown token")).await;
+            return None;
+        };
+        let Some(tier) = self.state.tiers.read().await.get(&consumer.tier).cloned() else {
+            error!(consumer = consumer.to_string(), "tier not found");
+            let reason = format!("tier {} not found", consumer.tier);
+            respond(&mut io_client, "500 Internal Server Error", &json!(reason)).await;
+            return None;
+        };
+
+        // HTTP requests hold a connection of the consumer while they run, like WebSocket sessions.
+        let mut session = Session::new(self, consumer, tier);
+        if let Err(reason) = session.admit() {
+            respond(&mut io_client, "429 Too Many Requests", &json!(reason)).await;
+            return None;
+        }
+        session.connected().await;
+        let result = if request.is_upgrade() {
+            match websocket::accept(&mut io_client, &request).await {
+                Ok(()) => session.websocket(&mut io_client, request.received).await,
+                Err(reason) => {
+                    respond(&mut io_client, "400 Bad Request", &json!(reason)).await;
+                    Ok(())
+                }
+            }
+        } else {
+            session.http(&mut io_client, request).await
+        };
+        session.disconnected().await;
+
+        if let Err(err) = result {
+            warn!(
+                error = err.to_string(),
+                consumer = session.consumer.to_string(),
+                "json-rpc connection error"
+            );
+        }
+
+        None
+    }
+}
+
+/// Requests of a client connection, with the node session they share.
+struct Session<'a> {
+    app: &'a JsonRpcApp,
+    consumer: Consumer,
+    tier: Tier,
+    instance: String,
+    node: Option<NodeSession>,
+}
+impl<'a> Session<'a> {
+    fn new(app: &'a JsonRpcApp, consumer: Consumer, tier: Tier) -> Self {
+        let instance = format!(
+            "node-{}-{}.{}:{}",
+            consumer.network, consumer.version, app.config.node_dns, app.config.node_port
+        );
+        Self {
+            app,
+            consumer,
+            tier,
+            instance,
+            node: None,
+        }
+    }
+
+    async fn http(
+        &mut self,
+        io: &mut Stream,
+        request: Request,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if request.method != "POST" {
+            respond(io, "405 Method Not Allowed", &json!("method not allowed")).await;
+            return Ok(());
+        }
+        let Some(len) = request
+            .header("content-length")
+            .and_then(|value| value.parse::<usize>().ok())
+        else {
+            respond(io, "411 Length Required", &json!("missing content length")).await;
+            return Ok(());
+        };
+        if len > MAX_REQUEST_LEN {
+            respond(io, "413 Payload Too Large", &json!("request too large")).await;
+            return Ok(());
+        }
+
+        let mut body = request.received;
+        let mut buf = [0; 1024];
+        while body.len() < len {
+            let bytes = timeout(REQUEST_TIMEOUT, io.read(&mut buf)).await??;
+            if bytes == 0 {
+                return Err("connection closed".into());
+            }
+            body.extend_from_slice(&buf[0..bytes]);
+        }
+        body.truncate(len);
+
+        let response = self.handle(&body, false).await;
+        respond(io, "200 OK", &response).await;
+        Ok(())
+    }
+
+    /// Checks the tier has a connection left for the client.
+    fn admit(&self) -> Result<(), String> {
+        if self.consumer.active_connections < self.tier.max_connections {
+            return Ok(());
+        }
+        self.app.state.metrics.count_total_connections_denied(
+            &self.consumer,
+            &self.app.config.proxy_namespace,
+            &self.instance,
+        );
+        Err(format!(
+            "connection limit {} reached for tier {}",
+            self.tier.max_connections, self.tier.name
+        ))
+    }
+
+    async fn connected(&self) {
+        let state = &self.app.state;
+        self.consumer.inc_connections(state.clone()).await;
+        state.metrics.inc_total_connections(
+            &self.consumer,
+            &self.app.config.proxy_namespace,
+            &self.instance,
+        );
+    }
+
+    async fn disconnected(&mut self) {
+        let state = self.app.state.clone();
+        self.consumer.dec_connections(state.clone()).await;
+        state.metrics.dec_total_connections(
+            &self.consumer,
+            &self.app.config.proxy_namespace,
+            &self.instance,
+        );
+    }
+
+    /// Serves the requests of a WebSocket session. Requests that wait on the node, like
+    /// `nextBlock` at the tip, keep answering pings, and end with the session when the client
+    /// closes it, is idle for too long or the validity window of the port ends.
+    async fn websocket(
+        &mut self,
+        io: &mut Stream,
+        received: Vec<u8>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let app = self.app;
+        let mut client = WebSocketClient::new(io, received);
+        if !client.decode().await? {
+            return Ok(());
+        }
+
+        let mut message = Vec::new();
+        loop {
+            while let Some((fin, payload)) = client.messages.pop_front() {
+                message.extend(payload);
+                if message.len() > MAX_REQUEST_LEN {
+                    return Err("websocket message too large".into());
+                }
+                if !fin {
+                    continue;
+                }
+
+                let idle_timeout = self.tier.idle_timeout;
+                let mut consumer = self.consumer.clone();
+                let response = {
+                    let handle = self.handle(&message, true);
+                    pin!(handle);
+                    loop {
+                        let wake = select! {
+                            response = &mut handle => break response,
+                            wake = client.read(idle_timeout, &consumer) => wake?,
+                        };
+                        if !client.wake(wake, app, &mut consumer).await? {
+                            return Ok(());
+                        }
+                    }
+                };
+                self.consumer = consumer;
+                message.clear();
+
+                let response = serde_json::to_vec(&response)?;
+                client.send(OPCODE_TEXT, &response).await?;
+            }
+
+            let wake = client.read(self.tier.idle_timeout, &self.consumer).await?;
+            if !client.wake(wake, app, &mut self.consumer).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn handle(&mut self, body: &[u8], websocket: bool) -> Value {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = RpcError::new(PARSE_ERROR, err.to_string());
+                return response(Value::Null, "", Err(error));
+            }
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            let error = RpcError::new(INVALID_REQUEST, "missing method");
+            return response(id, "", Err(error));
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let result = match method {
+            "queryNetwork/tip" => self.tip().await,
+            "queryLedgerState/epoch" => self.epoch().await,
+            "queryLedgerState/protocolParameters" => self.protocol_parameters().await,
+            "queryLedgerState/utxo" => self.utxo(&params).await,
+            "findIntersection" | "nextBlock" if !websocket => Err(RpcError::new(
+                WEBSOCKET_REQUIRED,
+                format!("{method} needs a websocket connection"),
+            )),
+            "findIntersection" => self.find_intersection(&params).await,
+            "nextBlock" => self.next_block().await,
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {method}"),
+            )),
+        };
+
+        let method_label = METHODS
+            .iter()
+            .find(|known| **known == method)
+            .unwrap_or(&"unknown");
+        let result_label = if result.is_ok() { "ok" } else { "error" };
+        self.app.state.metrics.count_total_jsonrpc_requests(
+            &self.consumer,
+            &self.app.config.proxy_namespace,
+            &self.instance,
+            method_label,
+            result_label,
+        );
+
+        response(id, method, result)
+    }
+
+    fn allows(&self, protocol: Protocol) -> Result<(), RpcError> {
+        if self.tier.allows(&protocol) && self.consumer.allows(&protocol) {
+            return Ok(());
+        }
+
+        self.app.state.metrics.count_total_protocols_denied(
+            &self.consumer,
+            &self.app.config.proxy_namespace,
+            &self.instance,
+            &protocol,
+        );
+        Err(RpcError::new(
+            DENIED,
+            format!("{protocol} is not allowed for tier {}", self.tier.name),
+        ))
+    }
+
+    async fn send(&mut self, protocol: Protocol, message: &[u8]) -> Result<(), RpcError> {
+        if self.node.is_none() {
+            let magic = *self
+                .app
+                .config
+                .network_magics
+                .get(&self.consumer.network)
+                .ok_or_else(|| RpcError::new(NODE_ERROR, "unknown network"))?;
+            let node =
+                NodeSession::connect(&self.app.client_connector, &self.instance, magic).await?;
+            self.node = Some(node);
+        }
+
+        let node = self.node.as_mut().unwrap();
+        if let Err(err) = node.send(protocol, message).await {
+            self.node = None;
+            return Err(err.into());
+        }
+        self.count(protocol, Direction::Inbound, message.len());
+        Ok(())
+    }
+
+    /// Takes the next message of the node. With `wait` the node may take as long as it needs,
+    /// like on chain-sync `MsgAwaitReply`.
+    async fn receive(&mut self, protocol: Protocol, wait: bool) -> Result<Vec<u8>, RpcError> {
+        let Some(node) = self.node.as_mut() else {
+            return Err(RpcError::new(NODE_ERROR, "no session with the node"));
+        };
+        let result = match (wait, protocol) {
+            (true, _) => node.wait(protocol).await,
+            (false, Protocol::LocalStateQuery) => node.wait_for(protocol, QUERY_TIMEOUT).await,
+            (false, _) => node.receive(protocol).await,
+        };
+        let message = match result {
+            Ok(message) => message,
+            // The session is left in an unknown state, the next request opens a new one.
+            Err(err) => {
+                self.node = None;
+                return Err(err.into());
+            }
+        };
+
+        throttle(
+            &self.app.state,
+            &self.app.state.limiter,
+            &self.consumer,
+            &self.tier.rates,
+            message.len(),
+        )
+        .await;
+        self.count(protocol, Direction::Outbound, message.len());
+        Ok(message)
+    }
+
+    async fn request(&mut self, protocol: Protocol, message: &[u8]) -> Result<Vec<u8>, RpcError> {
+        self.send(protocol, message).await?;
+        self.receive(protocol, false).await
+    }
+
+    fn count(&self, protocol: Protocol, direction: Direction, bytes: usize) {
+        let metrics = &self.app.state.metrics;
+        let namespace = &self.app.config.proxy_namespace;
+        metrics.count_total_packages_bytes(&self.consumer, namespace, &self.instance, bytes);
+        metrics.count_total_protocol_bytes(
+            &self.consumer,
+            namespace,
+            &self.instance,
+            &protocol,
+            &direction,
+            bytes,
+        );
+        metrics.count_total_protocol_messages(
+            &self.consumer,
+            namespace,
+            &self.instance,
+            &protocol,
+            &direction,
+            1,
+        );
+    }
+
+    /// Runs `queries` on the volatile tip, returning their results.
+    async fn state_queries(&mut self, queries: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, RpcError> {
+        self.allows(Protocol::LocalStateQuery)?;
+        let reply = self
+            .request(Protocol::LocalStateQuery, &query::acquire_tip())
+            .await?;
+        if StateQuery::decode(&reply)? != StateQuery::Acquired {
+            return Err(RpcError::new(
+                NODE_ERROR,
+                "the node failed to acquire the tip",
+            ));
+        }
+
+        let mut results = Vec::new();
+        let mut error = None;
+        for message in queries {
+            match self.state_query(message).await {
+                Ok(result) => results.push(result),
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
+            }
+        }
+
+        if self.node.is_some() {
+            self.send(Protocol::LocalStateQuery, &query::release())
+                .await?;
+        }
+        match error {
+            Some(err) => Err(err),
+            None => Ok(results),
+        }
+    }
+
+    /// Sends a `MsgQuery` once the tier query policy allows it, as on N2C connections.
+    async fn state_query(&mut self, message: &[u8]) -> Result<Vec<u8>, RpcError> {
+        let query = Query::decode(message)?
+            .ok_or_else(|| RpcError::new(NODE_ERROR, "invalid local-state-query message"))?;
+        let metrics = &self.app.state.metrics;
+        let namespace = &self.app.config.proxy_namespace;
+        metrics.count_total_state_queries(&self.consumer, namespace, &self.instance, &query);
+
+        let reason = if self.tier.denies_query(&query) {
+            Some("denied")
+        } else if !budget(
+            &self.app.state,
+            &self.app.state.query_limiter,
+            &self.consumer,
+            &self.tier.query_rates,
+            self.tier.query_cost(&query),
+        )
+        .await
+        {
+            Some("budget")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            metrics.count_total_state_queries_denied(
+                &self.consumer,
+                namespace,
+                &self.instance,
+                &query,
+                reason,
+            );
+            return Err(RpcError::new(
+                DENIED,
+                format!(
+                    "query {} is {reason} for tier {}",
+                    query.name, self.tier.name
+                ),
+            ));
+        }
+
+        match StateQuery::decode(&self.request(Protocol::LocalStateQuery, message).await?)? {
+            StateQuery::Result(result) => Ok(result),
+            _ => Err(RpcError::new(
+                NODE_ERROR,
+                "unexpected local-state-query reply",
+            )),
+        }
+    }
+
+    /// Runs a block query of the current era, returning the era and the query result.
+    async fn ledger_query(&mut self, query: &[u8]) -> Result<(u16, Vec<u8>), RpcError> {
+        let era = self.state_queries(&[query::get_current_era()]).await?;
+        let era = Decoder::new(&era[0]).u16()?;
+        let result = self
+            .state_queries(&[query::era_query(era, query)])
+            .await?
+            .remove(0);
+
+        // The result is `[result]`, or the eras when the era changed between both queries.
+        let mut d = Decoder::new(&result);
+        if d.array()? != Some(1) {
+            return Err(RpcError::new(NODE_ERROR, "the era changed, try again"));
+        }
+        Ok((era, result[d.position()..].to_vec()))
+    }
+
+    async fn tip(&mut self) -> Result<Value, RpcError> {
+        let result = self.state_queries(&[query::get_chain_point()]).await?;
+        let point: Point = minicbor::decode(&result[0])?;
+        Ok(point_json(&point))
+    }
+
+    async fn epoch(&mut self) -> Result<Value, RpcError> {
+        let (_era, result) = self.ledger_query(&block_query(1)).await?;
+        Ok(json!(Decoder::new(&result).u64()?))
+    }
+
+    async fn protocol_parameters(&mut self) -> Result<Value, RpcError> {
+        let (era, result) = self.ledger_query(&block_query(3)).await?;
+        Ok(json!({
+            "era": query::era_name(era),
+            "parameters": cbor_json(&mut Decoder::new(&result))?,
+        }))
+    }
+
+    async fn utxo(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let addresses = params
+            .get("addresses")
+            .and_then(Value::as_array)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing addresses"))?;
+
+        let mut e = Encoder::new(Vec::new());
+        e.array(2).unwrap().u16(6).unwrap();
+        e.array(addresses.len() as u64).unwrap();
+        for address in addresses {
+            let (_hrp, bytes) = address
+                .as_str()
+                .and_then(|address| bech32::decode(address).ok())
+                .ok_or_else(|| {
+                    RpcError::new(INVALID_PARAMS, format!("invalid address {address}"))
+                })?;
+            e.bytes(&bytes).unwrap();
+        }
+
+        let (_era, result) = self.ledger_query(&e.into_writer()).await?;
+        Ok(utxo_json(&result)?)
+    }
+
+    async fn find_intersection(&mut self, params: &Value) -> Result<Value, RpcError> {
+        self.allows(Protocol::ChainSync)?;
+        let points = params
+            .get("points")
+            .and_then(Value::as_array)
+            .and_then(|points| points.iter().map(parse_point).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "invalid points"))?;
+
+        let message = minicbor::to_vec(ChainSyncMessage::<BlockContent>::FindIntersect(points))
+            .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+        let reply = self.request(Protocol::ChainSync, &message).await?;
+        match minicbor::decode::<ChainSyncMessage<BlockContent>>(&reply)? {
+            ChainSyncMessage::IntersectFound(point, tip) => Ok(json!({
+                "intersection": point_json(&point),
+                "tip": tip_json(&tip),
+            })),
+            ChainSyncMessage::IntersectNotFound(tip) => Err(RpcError {
+                code: INTERSECTION_NOT_FOUND,
+                message: "none of the points are on the chain".into(),
+                data: Some(json!({ "tip": tip_json(&tip) })),
+            }),
+            _ => Err(RpcError::new(NODE_ERROR, "unexpected chain-sync reply")),
+        }
+    }
+
+    async fn next_block(&mut self) -> Result<Value, RpcError> {
+        self.allows(Protocol::ChainSync)?;
+        let message = minicbor::to_vec(ChainSyncMessage::<BlockContent>::RequestNext).unwrap();
+        self.send(Protocol::ChainSync, &message).await?;
+
+        let mut wait = false;
+        loop {
+            let reply = self.receive(Protocol::ChainSync, wait).await?;
+            match minicbor::decode::<ChainSyncMessage<BlockContent>>(&reply)? {
+                ChainSyncMessage::AwaitReply => wait = true,
+                ChainSyncMessage::RollForward(block, tip) => {
+                    return Ok(json!({
+                        "direction": "forward",
+                        "block": block_json(&block)?,
+                        "tip": tip_json(&tip),
+                    }))
+                }
+                ChainSyncMessage::RollBackward(point, tip) => {
+                    return Ok(json!({
+                        "direction": "backward",
+                        "point": point_json(&point),
+                        "tip": tip_json(&tip),
+                    }))
+                }
+                _ => return Err(RpcError::new(NODE_ERROR, "unexpected chain-sync reply")),
+            }
+        }
+    }
+}
+
+/// What ended a wait on the client.
+enum Wake {
+    Read(usize),
+    Idle,
+    Expired,
+}
+
+/// Client side of a WebSocket session. Control frames are answered as soon as they arrive, and
+/// the data frames are queued until the session takes them.
+struct WebSocketClient<'a> {
+    io: &'a mut Stream,
+    received: Vec<u8>,
+    buf: [u8; 1024],
+    /// Data frames, with whether they end their message.
+    messages: VecDeque<(bool, Vec<u8>)>,
+}
+impl<'a> WebSocketClient<'a> {
+    fn new(io: &'a mut Stream, received: Vec<u8>) -> Self {
+        Self {
+            io,
+            received,
+            buf: [0; 1024],
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Reads the client until it sends something, is idle for `idle_timeout` or the validity
+    /// window of the consumer ends. Nothing is lost when the read is cancelled.
+    async fn read(
+        &mut self,
+        idle_timeout: Option<Duration>,
+        consumer: &Consumer,
+    ) -> std::io::Result<Wake> {
+        let expires_in = consumer.expires_in();
+        let read = async {
+            let read = self.io.read(&mut self.buf);
+            match idle_timeout {
+                Some(idle_timeout) => timeout(idle_timeout, read).await.ok(),
+                None => Some(read.await),
+            }
+        };
+        let expiry = async move {
+            match expires_in {
+                Some(left) => sleep(left).await,
+                None => std::future::pending().await,
+            }
+        };
+        select! {
+            bytes = read => match bytes {
+                Some(bytes) => Ok(Wake::Read(bytes?)),
+                None => Ok(Wake::Idle),
+            },
+            _ = expiry => Ok(Wake::Expired),
+        }
+    }
+
+    /// Handles what ended a read, returning whether the session goes on. The consumer is read
+    /// again when its validity window ends, since it may have been extended.
+    async fn wake(
+        &mut self,
+        wake: Wake,
+        app: &JsonRpcApp,
+        consumer: &mut Consumer,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        match wake {
+            Wake::Read(0) => Ok(false),
+            Wake::Read(bytes) => {
+                self.received.extend_from_slice(&self.buf[0..bytes]);
+                self.decode().await
+            }
+            Wake::Idle => self.close().await,
+            Wake::Expired => match app.state.get_consumer(&consumer.key).await {
+                Some(refreshed) => {
+                    *consumer = refreshed;
+                    Ok(true)
+                }
+                None => self.close().await,
+            },
+        }
+    }
+
+    /// Takes the frames received, returning whether the session goes on.
+    async fn decode(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        while let Some((opcode, fin, payload)) = websocket::decode(&mut self.received)? {
+            match opcode {
+                OPCODE_CLOSE => return self.close().await,
+                OPCODE_PING => self.send(OPCODE_PONG, &payload).await?,
+                OPCODE_CONTINUATION | OPCODE_BINARY | OPCODE_TEXT => {
+                    self.messages.push_back((fin, payload))
+                }
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+
+    async fn send(&mut self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        self.io
+            .write_all(&websocket::encode(opcode, payload))
+            .await?;
+        self.io.flush().await
+    }
+
+    async fn close(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.send(OPCODE_CLOSE, &[]).await?;
+        Ok(false)
+    }
+}
+
+fn response(id: Value, method: &str, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "method": method, "result": result, "id": id}),
+        Err(err) => {
+            let mut error = json!({"code": err.code, "message": err.message});
+            if let Some(data) = err.data {
+                error["data"] = data;
+            }
+            json!({"jsonrpc": "2.0", "method": method, "error": error, "id": id})
+        }
+    }
+}
+
+async fn respond(io: &mut Stream, status: &str, body: &Value) {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = io.write_all(response.as_bytes()).await;
+    let _ = io.flush().await;
+}
+
+fn block_query(tag: u16) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(1).unwrap().u16(tag).unwrap();
+    e.into_writer()
+}
+
+fn point_json(point: &Point) -> Value {
+    match point {
+        Point::Origin => json!("origin"),
+        Point::Specific(slot, hash) => json!({"slot": slot, "id": hex::encode(hash)}),
+    }
+}
+
+fn tip_json(tip: &Tip) -> Value {
+    match &tip.0 {
+        Point::Origin => json!("origin"),
+        Point::Specific(slot, hash) => {
+            json!({"slot": slot, "id": hex::encode(hash), "height": tip.1})
+        }
+    }
+}
+
+/// Points are `"origin"` or `{"slot": <slot>, "id": <hex block hash>}`.
+fn parse_point(value: &Value) -> Option<Point> {
+    if value.as_str() == Some("origin") {
+        return Some(Point::Origin);
+    }
+    let slot = value.get("slot")?.as_u64()?;
+    let hash = hex::decode(value.get("id")?.as_str()?).ok()?;
+    Some(Point::Specific(slot, hash))
+}
+
+// N2C blocks are `[tag, block]`, Byron has tags 0 (boundary blocks) and 1, so the tag is one
+// ahead of the era index from then on.
+fn block_json(block: &[u8]) -> Result<Value, decode::Error> {
+    let mut d = Decoder::new(block);
+    d.array()?;
+    let tag = d.u16()?;
+    let mut value = json!({
+        "era": query::era_name(tag.saturating_sub(1)),
+        "cbor": hex::encode(&block[d.position()..]),
+    });
+    if let Ok(Point::Specific(slot, hash)) = follower::block_point(block) {
+        value["slot"] = json!(slot);
+        value["id"] = json!(hex::encode(hash));
+    }
+    Ok(value)
+}
+
+// `GetUTxOByAddress` returns a map from `[tx id, index]` to the output.
+fn utxo_json(result: &[u8]) -> Result<Value, decode::Error> {
+    let mut d = Decoder::new(result);
+    let mut utxos = Vec::new();
+    let mut remaining = d.map()?;
+    while has_next(&mut d, &mut remaining)? {
+        d.array()?;
+        let id = hex::encode(d.bytes()?);
+        let index = d.u64()?;
+        let mut utxo = output_json(&mut d)?;
+        utxo["transaction"] = json!({ "id": id });
+        utxo["index"] = json!(index);
+        utxos.push(utxo);
+    }
+    Ok(Value::Array(utxos))
+}
+
+// Outputs are `{0: address, 1: value, ?2: datum option, ?3: script ref}` since Babbage, and
+// `[address, value, ?datum hash]` before.
+fn output_json(d: &mut Decoder) -> Result<Value, decode::Error> {
+    let start = d.position();
+    let mut output = Map::new();
+    if matches!(d.datatype()?, Type::Map | Type::MapIndef) {
+        let mut remaining = d.map()?;
+        while has_next(d, &mut remaining)? {
+            match d.u8()? {
+                0 => {
+                    output.insert("address".into(), address_json(d.bytes()?));
+                }
+                1 => {
+                    output.insert("value".into(), value_json(d)?);
+                }
+                2 => {
+                    d.array()?;
+                    if d.u8()? == 0 {
+                        output.insert("datumHash".into(), json!(hex::encode(d.bytes()?)));
+                    } else {
+                        d.tag()?;
+                        output.insert("datum".into(), json!(hex::encode(d.bytes()?)));
+                    }
+                }
+                3 => {
+                    d.tag()?;
+                    output.insert("script".into(), json!(hex::encode(d.bytes()?)));
+                }
+                _ => d.skip()?,
+            }
+        }
+    } else {
+        let mut remaining = d.array()?;
+        if has_next(d, &mut remaining)? {
+            output.insert("address".into(), address_json(d.bytes()?));
+        }
+        if has_next(d, &mut remaining)? {
+            output.insert("value".into(), value_json(d)?);
+        }
+        if has_next(d, &mut remaining)? {
+            output.insert("datumHash".into(), json!(hex::encode(d.bytes()?)));
+        }
+        while has_next(d, &mut remaining)? {
+            d.skip()?;
+        }
+    }
+    output.insert(
+        "cbor".into(),
+        json!(hex::encode(&d.input()[start..d.position()])),
+    );
+    Ok(Value::Object(output))
+}
+
+/// Shelley addresses as bech32, Byron ones as hex.
+fn address_json(address: &[u8]) -> Value {
+    let Some(header) = address.first() else {
+        return json!("");
+    };
+    let hrp = match (header >> 4, header & 0x0f) {
+        (8, _) => return json!(hex::encode(address)),
+        (_, 1) => Hrp::parse_unchecked("addr"),
+        _ => Hrp::parse_unchecked("addr_test"),
+    };
+    match bech32::encode::<Bech32>(hrp, address) {
+        Ok(address) => json!(address),
+        Err(_) => json!(hex::encode(address)),
+    }
+}
+
+/// Values are the lovelace or `[lovelace, {policy: {asset name: quantity}}]`.
+fn value_json(d: &mut Decoder) -> Result<Value, decode::Error> {
+    let mut value = Map::new();
+    if !matches!(d.datatype()?, Type::Array | Type::ArrayIndef) {
+        value.insert("ada".into(), json!({ "lovelace": d.u64()? }));
+        return Ok(Value::Object(value));
+    }
+
+    let mut items = d.array()?;
+    if has_next(d, &mut items)? {
+        value.insert("ada".into(), json!({ "lovelace": d.u64()? }));
+    }
+    if has_next(d, &mut items)? {
+        let mut policies = d.map()?;
+        while has_next(d, &mut policies)? {
+            let policy = hex::encode(d.bytes()?);
+            let mut assets = Map::new();
+            let mut remaining = d.map()?;
+            while has_next(d, &mut remaining)? {
+                let name = hex::encode(d.bytes()?);
+                assets.insert(name, json!(d.u64()?));
+            }
+            value.insert(policy, Value::Object(assets));
+        }
+    }
+    while has_next(d, &mut items)? {
+        d.skip()?;
+    }
+    Ok(Value::Object(value))
+}
+
+/// Renders any CBOR item as JSON. Bytes are hex, map keys that aren't text are rendered as JSON
+/// text, rationals (tag 30) are `"<numerator>/<denominator>"` and other tags only keep their
+/// content.
+fn cbor_json(d: &mut Decoder) -> Result<Value, decode::Error> {
+    let value = match d.datatype()? {
+        Type::Bool => json!(d.bool()?),
+        Type::Null => {
+            d.null()?;
+            Value::Null
+        }
+        Type::Undefined => {
+            d.undefined()?;
+            Value::Null
+        }
+        Type::U8 | Type::U16 | Type::U32 | Type::U64 => json!(d.u64()?),
+        Type::I8 | Type::I16 | Type::I32 | Type::I64 => json!(d.i64()?),
+        Type::Int => json!(i128::from(d.int()?).to_string()),
+        Type::F32 | Type::F64 => json!(d.f64()?),
+        Type::Simple => json!(d.simple()?),
+        Type::Bytes => json!(hex::encode(d.bytes()?)),
+        Type::BytesIndef => {
+            let mut bytes = Vec::new();
+            for chunk in d.bytes_iter()? {
+                bytes.extend_from_slice(chunk?);
+            }
+            json!(hex::encode(bytes))
+        }
+        Type::String => json!(d.str()?),
+        Type::StringIndef => {
+            let mut text = String::new();
+            for chunk in d.str_iter()? {
+                text.push_str(chunk?);
+            }
+            json!(text)
+        }
+        Type::Array | Type::ArrayIndef => {
+            let mut items = Vec::new();
+            let mut remaining = d.array()?;
+            while has_next(d, &mut remaining)? {
+                items.push(cbor_json(d)?);
+            }
+            Value::Array(items)
+        }
+        Type::Map | Type::MapIndef => {
+            let mut entries = Map::new();
+            let mut remaining = d.map()?;
+            while has_next(d, &mut remaining)? {
+                let key = match cbor_json(d)? {
+                    Value::String(key) => key,
+                    key => key.to_string(),
+                };
+                entries.insert(key, cbor_json(d)?);
+            }
+            Value::Object(entries)
+        }
+        Type::Tag => match d.tag()?.as_u64() {
+            30 => {
+                d.array()?;
+                json!(format!("{}/{}", d.u64()?, d.u64()?))
+            }
+            _ => cbor_json(d)?,
+        },
+        _ => {
+            d.skip()?;
+            Value::Null
+        }
+    };
+    Ok(value)
+}
+
+/// Whether a definite or indefinite array or map has another item, taking the break at the end
+/// of indefinite ones.
+fn has_next(d: &mut Decoder, remaining: &mut Option<u64>) -> Result<bool, decode::Error> {
+    match remaining {
+        Some(0) => Ok(false),
+        Some(count) => {
+            *count -= 1;
+            Ok(true)
+        }
+        None if d.datatype()? == Type::Break => {
+            d.set_position(d.position() + 1);
+            Ok(false)
+        }
+        None => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas_crypto::hash::Hasher;
+
+    use super::*;
+
+    fn decode_json(cbor: &str) -> Value {
+        let cbor = hex::decode(cbor).unwrap();
+        cbor_json(&mut Decoder::new(&cbor)).unwrap()
+    }
+
+    #[test]
+    fn cbor_items() {
+        // RFC 8949 appendix A examples.
+        assert_eq!(decode_json("1903e8"), json!(1000));
+        assert_eq!(decode_json("20"), json!(-1));
+        assert_eq!(
+            decode_json("3bffffffffffffffff"),
+            json!("-18446744073709551616")
+        );
+        assert_eq!(decode_json("f5"), json!(true));
+        assert_eq!(decode_json("f6"), Value::Null);
+        assert_eq!(decode_json("4401020304"), json!("01020304"));
+        assert_eq!(decode_json("5f42010243030405ff"), json!("0102030405"));
+        assert_eq!(
+            decode_json("7f657374726561646d696e67ff"),
+            json!("streaming")
+        );
+        assert_eq!(
+            decode_json("9f018202039f0405ffff"),
+            json!([1, [2, 3], [4, 5]])
+        );
+        assert_eq!(
+            decode_json("bf61610161629f0203ffff"),
+            json!({"a": 1, "b": [2, 3]})
+        );
+        // Keys that aren't text are rendered as JSON text.
+        assert_eq!(decode_json("a201020304"), json!({"1": 2, "3": 4}));
+        // Rationals are kept as a fraction, other tags only keep their content.
+        assert_eq!(decode_json("d81e820103"), json!("1/3"));
+        assert_eq!(decode_json("c11a514b67b0"), json!(1363896240));
+    }
+
+    #[test]
+    fn next_item() {
+        let cbor = hex::decode("820102").unwrap();
+        let mut d = Decoder::new(&cbor);
+        let mut remaining = d.array().unwrap();
+        let mut items = Vec::new();
+        while has_next(&mut d, &mut remaining).unwrap() {
+            items.push(d.u8().unwrap());
+        }
+        assert_eq!(items, [1, 2]);
+
+        let cbor = hex::decode("9f0102ff03").unwrap();
+        let mut d = Decoder::new(&cbor);
+        let mut remaining = d.array().unwrap();
+        let mut items = Vec::new();
+        while has_next(&mut d, &mut remaining).unwrap() {
+            items.push(d.u8().unwrap());
+        }
+        assert_eq!(items, [1, 2]);
+        // The break is taken with the array.
+        assert_eq!(d.u8().unwrap(), 3);
+    }
+
+    const KEY_HASH: &str = "9493315cd92eb5d8c4304e67b7e16ae36d61d34502694657811a2c8e";
+
+    fn address(header: u8) -> Vec<u8> {
+        [vec![header], hex::decode(KEY_HASH).unwrap()].concat()
+    }
+
+    #[test]
+    fn addresses() {
+        // CIP-19 enterprise address test vectors.
+        assert_eq!(
+            address_json(&address(0x61)),
+            json!("addr1vx2fxv2umyhttkxyxp8x0dlpdt3k6cwng5pxj3jhsydzers66hrl8")
+        );
+        assert_eq!(
+            address_json(&address(0x60)),
+            json!("addr_test1vz2fxv2umyhttkxyxp8x0dlpdt3k6cwng5pxj3jhsydzerspjrlsz")
+        );
+        let byron = address(0x82);
+        assert_eq!(address_json(&byron), json!(hex::encode(&byron)));
+        assert_eq!(address_json(&[]), json!(""));
+    }
+
+    #[test]
+    fn values() {
+        let cbor = hex::decode("1a000f4240").unwrap();
+        assert_eq!(
+            value_json(&mut Decoder::new(&cbor)).unwrap(),
+            json!({"ada": {"lovelace": 1000000}})
+        );
+
+        let mut e = Encoder::new(Vec::new());
+        e.array(2).unwrap().u64(2000000).unwrap();
+        e.map(1).unwrap().bytes(&[0xaa; 28]).unwrap();
+        e.map(1).unwrap().bytes(b"token").unwrap().u64(5).unwrap();
+        let cbor = e.into_writer();
+        assert_eq!(
+            value_json(&mut Decoder::new(&cbor)).unwrap(),
+            json!({
+                "ada": {"lovelace": 2000000},
+                hex::encode([0xaa; 28]): {hex::encode("token"): 5},
+            })
+        );
+    }
+
+    #[test]
+    fn utxos() {
+        let tx_id = [0x11; 32];
+        let mut e = Encoder::new(Vec::new());
+        e.map(2).unwrap();
+        // Babbage output with an inline datum.
+        e.array(2).unwrap().bytes(&tx_id).unwrap().u64(0).unwrap();
+        let babbage = e.writer().len();
+        e.map(3).unwrap();
+        e.u8(0).unwrap().bytes(&address(0x61)).unwrap();
+        e.u8(1).unwrap().u64(1000000).unwrap();
+        e.u8(2).unwrap().array(2).unwrap().u8(1).unwrap();
+        e.tag(minicbor::data::Tag::new(24)).unwrap();
+        e.bytes(&[0x01]).unwrap();
+        let babbage = hex::encode(&e.writer()[babbage..]);
+        // Legacy output with a datum hash.
+        e.array(2).unwrap().bytes(&tx_id).unwrap().u64(1).unwrap();
+        let legacy = e.writer().len();
+        e.array(3).unwrap().bytes(&address(0x61)).unwrap();
+        e.u64(2000000).unwrap().bytes(&[0x22; 32]).unwrap();
+        let legacy = hex::encode(&e.writer()[legacy..]);
+
+        let address = "addr1vx2fxv2umyhttkxyxp8x0dlpdt3k6cwng5pxj3jhsydzers66hrl8";
+        assert_eq!(
+            utxo_json(&e.into_writer()).unwrap(),
+            json!([
+                {
+                    "transaction": {"id": hex::encode(tx_id)},
+                    "index": 0,
+                    "address": address,
+                    "value": {"ada": {"lovelace": 1000000}},
+                    "datum": "01",
+                    "cbor": babbage,
+                },
+                {
+                    "transaction": {"id": hex::encode(tx_id)},
+                    "index": 1,
+                    "address": address,
+                    "value": {"ada": {"lovelace": 2000000}},
+                    "datumHash": hex::encode([0x22; 32]),
+                    "cbor": legacy,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn points() {
+        let hash = vec![0xab; 32];
+        let point = Point::Specific(42, hash.clone());
+        let value = point_json(&point);
+        assert_eq!(value, json!({"slot": 42, "id": hex::encode(&hash)}));
+        assert_eq!(parse_point(&value), Some(point.clone()));
+        assert_eq!(point_json(&Point::Origin), json!("origin"));
+        assert_eq!(parse_point(&json!("origin")), Some(Point::Origin));
+        assert_eq!(parse_point(&json!({"slot": 42, "id": "not hex"})), None);
+        assert_eq!(parse_point(&json!({"id": hex::encode(&hash)})), None);
+
+        assert_eq!(
+            tip_json(&Tip(point, 7)),
+            json!({"slot": 42, "id": hex::encode(&hash), "height": 7})
+        );
+        assert_eq!(tip_json(&Tip(Point::Origin, 0)), json!("origin"));
+    }
+
+    #[test]
+    fn blocks() {
+        // `[header body, signature]` where the header body starts with the block number and slot.
+        let mut e = Encoder::new(Vec::new());
+        e.array(2).unwrap();
+        e.array(2).unwrap().u64(10).unwrap().u64(1234).unwrap();
+        e.bytes(b"signature").unwrap();
+        let header = e.into_writer();
+        let hash = Hasher::<256>::hash(&header);
+
+        // A Conway block, tagged 7 on N2C.
+        let block = [&[0x82, 0x07, 0x82][..], &header, &[0x80]].concat();
+        assert_eq!(
+            block_json(&block).unwrap(),
+            json!({
+                "era": "conway",
+                "slot": 1234,
+                "id": hex::encode(hash),
+                "cbor": hex::encode(&block[2..]),
+            })
+        );
+
+        // Byron blocks don't have a point.
+        let block = hex::decode("820180").unwrap();
+        assert_eq!(
+            block_json(&block).unwrap(),
+            json!({"era": "byron", "cbor": "80"})
+        );
+    }
+
+    #[test]
+    fn responses() {
+        assert_eq!(
+            response(json!(1), "queryNetwork/tip", Ok(json!("origin"))),
+            json!({"jsonrpc": "2.0", "method": "queryNetwork/tip", "result": "origin", "id": 1})
+        );
+
+        let mut error = RpcError::new(INTERSECTION_NOT_FOUND, "no intersection");
+        error.data = Some(json!({"tip": "origin"}));
+        assert_eq!(
+            response(json!("a"), "findIntersection", Err(error)),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "findIntersection",
+                "error": {"code": -32002, "message": "no intersection", "data": {"tip": "origin"}},
+                "id": "a",
+            })
+        );
+    }
+
+    fn tier(extra: &str) -> Tier {
+        toml::from_str(&format!(
+            r#"
+name = "0"
+max_connections = 1
+rates = [{{ limit = 1024, interval = "1s" }}]
+{extra}
+"#
+        ))
+        .unwrap()
+    }
+
+    async fn handle(tier: Tier, body: &str, websocket: bool) -> Value {
+        let app = JsonRpcApp::new(Arc::new(Config::for_tests()), State::shared());
+        let consumer = Consumer {
+            namespace: "prj-test".into(),
+            port_name: "jsonrpc".into(),
+            ..Default::default()
+        };
+        let mut session = Session::new(&app, consumer, tier);
+        session.handle(body.as_bytes(), websocket).await
+    }
+
+    fn error_code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn dispatch_errors() {
+        let response = handle(tier(""), "{", false).await;
+        assert_eq!(error_code(&response), PARSE_ERROR);
+        assert_eq!(response["id"], Value::Null);
+
+        let response = handle(tier(""), r#"{"id": 1}"#, false).await;
+        assert_eq!(error_code(&response), INVALID_REQUEST);
+        assert_eq!(response["id"], json!(1));
+
+        let response = handle(
+            tier(""),
+            r#"{"method": "submitTransaction", "id": 2}"#,
+            false,
+        )
+        .await;
+        assert_eq!(error_code(&response), METHOD_NOT_FOUND);
+
+        for method in ["findIntersection", "nextBlock"] {
+            let body = json!({"method": method, "id": 3}).to_string();
+            let response = handle(tier(""), &body, false).await;
+            assert_eq!(error_code(&response), WEBSOCKET_REQUIRED);
+            assert_eq!(response["method"], json!(method));
+        }
+
+        let body = r#"{"method": "queryLedgerState/utxo", "params": {}, "id": 4}"#;
+        let response = handle(tier(""), body, false).await;
+        assert_eq!(error_code(&response), INVALID_PARAMS);
+
+        // Denied before the proxy connects to the node.
+        let tier = tier(r#"allowed_protocols = ["chain-sync"]"#);
+        let response = handle(tier, r#"{"method": "queryNetwork/tip", "id": 5}"#, false).await;
+        assert_eq!(error_code(&response), DENIED);
+    }
+}
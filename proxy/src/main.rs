@@ -1,32 +1,71 @@
-use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
+use std::{collections::HashMap, error::Error, net::IpAddr, sync::Arc, time::Duration};
 
 use auth::AuthBackgroundService;
+use cache::QueryCache;
+use certificate::{parse_fingerprint, ClientCertificate};
+use chrono::{DateTime, Utc};
 use dotenv::dotenv;
+use follower::Follower;
+use jsonrpc::JsonRpcApp;
 use leaky_bucket::RateLimiter;
+use mempool::MempoolApp;
+use openssl::x509::X509;
 use operator::{kube::ResourceExt, CardanoNodePort};
 use pingora::{
+    listeners::{tls::TlsSettings, Listeners},
     server::{configuration::Opt, Server},
     services::{background::background_service, listening::Service},
+    tls::ssl::SslVerifyMode,
 };
 use prometheus::{opts, register_int_counter_vec, register_int_gauge_vec};
 use proxy::ProxyApp;
 use regex::Regex;
+use relay::RelayApp;
 use serde::{Deserialize, Deserializer};
+use submit_api::SubmitApiApp;
 use tiers::TierBackgroundService;
 use tokio::sync::RwLock;
 use tracing::Level;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use websocket::WebSocketApp;
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    mux::{Direction, Protocol},
+    query::Query,
+    submission::Tx,
+};
 
 mod auth;
+mod cache;
+mod capture;
+mod certificate;
+mod client;
 mod config;
+mod follower;
+mod handshake;
+mod jsonrpc;
+mod mempool;
+mod monitor;
+mod mux;
 mod proxy;
+mod query;
+mod relay;
+mod replay;
+mod submission;
+mod submit_api;
 mod tiers;
+mod websocket;
 
 fn main() {
     dotenv().ok();
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|command| command == "replay") {
+        replay::main(&args[2..]);
+        return;
+    }
+
     let env_filter = EnvFilter::builder()
         .with_default_directive(Level::INFO.into())
         .with_env_var("RUST_LOG")
@@ -56,19 +95,94 @@ fn main() {
     );
     server.add_service(tier_background_service);
 
-    // Proxy listener using Service::with_listeners for TLS
+    // Proxy listener using Service::with_listeners for TLS. Client certificates are requested but
+    // checked by the proxy against the port, so clients without one still connect with the token.
+    let mut tls_settings =
+        TlsSettings::intermediate(&config.ssl_crt_path, &config.ssl_key_path).unwrap();
+    tls_settings.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+    let mut tls_listeners = Listeners::new();
+    tls_listeners.add_tls_with_settings(&config.proxy_addr, None, tls_settings);
     let tls_proxy_service = Service::with_listeners(
         "TLS Proxy Service".to_string(),
-        pingora::listeners::Listeners::tls(
-            &config.proxy_addr,
-            &config.ssl_crt_path,
-            &config.ssl_key_path,
-        )
-        .unwrap(),
+        tls_listeners,
         ProxyApp::new(config.clone(), state.clone()),
     );
     server.add_service(tls_proxy_service);
 
+    // WebSocket listener for clients that can't open raw TLS sockets
+    if let Some(proxy_ws_addr) = &config.proxy_ws_addr {
+        let websocket_service = Service::with_listeners(
+            "WebSocket Proxy Service".to_string(),
+            pingora::listeners::Listeners::tls(
+                proxy_ws_addr,
+                &config.ssl_crt_path,
+                &config.ssl_key_path,
+            )
+            .unwrap(),
+            WebSocketApp::new(ProxyApp::new(config.clone(), state.clone())),
+        );
+        server.add_service(websocket_service);
+    }
+
+    // cardano-submit-api compatible endpoint
+    if let Some(submit_api_addr) = &config.submit_api_addr {
+        let submit_api_service = Service::with_listeners(
+            "Submit API Service".to_string(),
+            pingora::listeners::Listeners::tls(
+                submit_api_addr,
+                &config.ssl_crt_path,
+                &config.ssl_key_path,
+            )
+            .unwrap(),
+            SubmitApiApp::new(config.clone(), state.clone()),
+        );
+        server.add_service(submit_api_service);
+    }
+
+    // JSON-RPC gateway
+    if let Some(jsonrpc_addr) = &config.jsonrpc_addr {
+        let jsonrpc_service = Service::with_listeners(
+            "JSON-RPC Service".to_string(),
+            pingora::listeners::Listeners::tls(
+                jsonrpc_addr,
+                &config.ssl_crt_path,
+                &config.ssl_key_path,
+            )
+            .unwrap(),
+            JsonRpcApp::new(config.clone(), state.clone()),
+        );
+        server.add_service(jsonrpc_service);
+    }
+
+    // Mempool event stream
+    if let Some(mempool_addr) = &config.mempool_addr {
+        let mempool_service = Service::with_listeners(
+            "Mempool Service".to_string(),
+            pingora::listeners::Listeners::tls(
+                mempool_addr,
+                &config.ssl_crt_path,
+                &config.ssl_key_path,
+            )
+            .unwrap(),
+            MempoolApp::new(config.clone(), state.clone()),
+        );
+        server.add_service(mempool_service);
+    }
+
+    // Node-to-node listeners, one per network
+    for (network, port) in &config.relay_networks {
+        let magic = *config
+            .network_magics
+            .get(network)
+            .unwrap_or_else(|| panic!("network magic of {network} must be set"));
+        let mut relay_service = Service::new(
+            format!("Relay {network} Service"),
+            RelayApp::new(network, magic, config.clone(), state.clone()),
+        );
+        relay_service.add_tcp(&format!("0.0.0.0:{port}"));
+        server.add_service(relay_service);
+    }
+
     // Prometheus endpoint — use the helper from pingora_core
     let mut prometheus_service_http = Service::prometheus_http_service();
     prometheus_service_http.add_tcp(&config.prometheus_addr);
@@ -82,6 +196,13 @@ pub struct State {
     metrics: Metrics,
     consumers: RwLock<HashMap<Vec<u8>, Consumer>>,
     limiter: RwLock<HashMap<Vec<u8>, Vec<Arc<RateLimiter>>>>,
+    query_limiter: RwLock<HashMap<Vec<u8>, Vec<Arc<RateLimiter>>>>,
+    tx_limiter: RwLock<HashMap<Vec<u8>, Vec<Arc<RateLimiter>>>>,
+    query_cache: RwLock<QueryCache>,
+    followers: RwLock<HashMap<String, Arc<Follower>>>,
+    relay_connections: RwLock<HashMap<IpAddr, usize>>,
+    /// Connections left to capture for each consumer.
+    captures: RwLock<HashMap<Vec<u8>, usize>>,
     tiers: RwLock<HashMap<String, Tier>>,
 }
 impl State {
@@ -89,20 +210,76 @@ impl State {
         Self::default()
     }
 
+    /// State shared by the tests, since the metrics can only be registered once.
+    #[cfg(test)]
+    pub fn shared() -> Arc<Self> {
+        static STATE: std::sync::LazyLock<Arc<State>> = std::sync::LazyLock::new(Default::default);
+        STATE.clone()
+    }
+
+    /// Ports outside their validity window aren't found, so their new connections are refused.
     pub async fn get_consumer(&self, key: &[u8]) -> Option<Consumer> {
-        let consumers = self.consumers.read().await.clone();
-        consumers.get(key).cloned()
+        let consumers = self.consumers.read().await;
+        let consumer = match consumers.get(key) {
+            Some(consumer) => Some(consumer),
+            None => consumers
+                .values()
+                .find(|consumer| consumer.accepts_key(key)),
+        };
+        consumer.filter(|consumer| consumer.is_valid()).cloned()
+    }
+    /// Key the limiters of the port are under. It's the current key of the port, also for the
+    /// connections opened with a key rotated since, so the port has a single set of buckets.
+    pub async fn limiter_key(&self, consumer: &Consumer) -> Vec<u8> {
+        let consumers = self.consumers.read().await;
+        if consumers.contains_key(&consumer.key) {
+            return consumer.key.clone();
+        }
+        consumers
+            .values()
+            .find(|current| current.is_port(consumer))
+            .map(|current| current.key.clone())
+            .unwrap_or_else(|| consumer.key.clone())
+    }
+    /// Port of the certificate fingerprint. When several ports share the fingerprint none is
+    /// returned, and the token in the host tells which one the connection is for.
+    pub async fn get_consumer_by_fingerprint(&self, fingerprint: &[u8]) -> Option<Consumer> {
+        let consumers = self.consumers.read().await;
+        let mut matching = consumers
+            .values()
+            .filter(|consumer| consumer.client_fingerprint.as_deref() == Some(fingerprint));
+        let consumer = matching.next()?;
+        if matching.next().is_some() {
+            return None;
+        }
+        Some(consumer)
+            .filter(|consumer| consumer.is_valid())
+            .cloned()
     }
 }
 
+/// Port annotation with the number of connections of the consumer to capture.
+const CAPTURE_ANNOTATION: &str = "demeter.run/capture-connections";
+
 #[derive(Debug, Clone, Default)]
 pub struct Consumer {
     namespace: String,
     port_name: String,
     tier: String,
     key: Vec<u8>,
+    /// Keys replaced by a token rotation, accepted until their grace period ends.
+    rotated_keys: Vec<(Vec<u8>, DateTime<Utc>)>,
     network: String,
     version: String,
+    read_only: bool,
+    /// Connections to capture, requested with the capture annotation on the port.
+    capture: Option<usize>,
+    /// SHA-256 fingerprint of the client certificate the port requires.
+    client_fingerprint: Option<Vec<u8>>,
+    /// CAs that issue the client certificates the port accepts.
+    client_cas: Vec<X509>,
+    valid_from: Option<DateTime<Utc>>,
+    valid_until: Option<DateTime<Utc>>,
     active_connections: usize,
 }
 impl Consumer {
@@ -110,48 +287,148 @@ impl Consumer {
         let network = crd.spec.network.to_string();
         let version = crd.spec.version.to_string();
         let tier = crd.spec.throughput_tier.to_string();
-        let key = crd.status.as_ref().unwrap().auth_token.clone();
+        let read_only = crd.spec.read_only.unwrap_or_default();
+        let status = crd.status.as_ref().unwrap();
+        let key = status.auth_token.clone();
         let namespace = crd.metadata.namespace.as_ref().unwrap().clone();
         let port_name = crd.name_any();
+        let capture = crd
+            .annotations()
+            .get(CAPTURE_ANNOTATION)
+            .and_then(|value| value.parse().ok());
+
+        let client_certificate = crd.spec.client_certificate.clone().unwrap_or_default();
+        let client_fingerprint = client_certificate
+            .fingerprint
+            .as_deref()
+            .map(parse_fingerprint)
+            .transpose()?;
+        let client_cas = match &client_certificate.ca {
+            Some(ca) => X509::stack_from_pem(ca.as_bytes())?,
+            None => Vec::new(),
+        };
+
+        let valid_from = parse_time(crd.spec.valid_from.as_deref())?;
+        let valid_until = parse_time(crd.spec.valid_until.as_deref())?;
 
         let (_hrp, key) = bech32::decode(&key)?;
+        let mut rotated_keys = Vec::new();
+        for token in &status.auth_tokens {
+            let Some(not_after) = &token.not_after else {
+                continue;
+            };
+            let not_after = DateTime::parse_from_rfc3339(not_after)?.with_timezone(&Utc);
+            let (_hrp, rotated_key) = bech32::decode(&token.token)?;
+            rotated_keys.push((rotated_key, not_after));
+        }
 
         Ok(Self {
             namespace,
             port_name,
             tier,
             key,
+            rotated_keys,
             network,
             version,
+            read_only,
+            capture,
+            client_fingerprint,
+            client_cas,
+            valid_from,
+            valid_until,
             active_connections: 0,
         })
     }
+    /// Whether the time is within the validity window of the port.
+    pub fn is_valid(&self) -> bool {
+        self.is_valid_at(Utc::now())
+    }
+    fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.valid_from.is_none_or(|from| from <= now)
+            && self.valid_until.is_none_or(|until| now < until)
+    }
+    /// Time left until the validity window of the port ends.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.expires_in_at(Utc::now())
+    }
+    fn expires_in_at(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.valid_until
+            .map(|until| (until - now).to_std().unwrap_or_default())
+    }
+    /// Ports with a client certificate only accept connections presenting it, or one issued by
+    /// their CAs.
+    pub fn accepts(&self, certificate: Option<&ClientCertificate>) -> bool {
+        if self.client_fingerprint.is_none() && self.client_cas.is_empty() {
+            return true;
+        }
+        let Some(certificate) = certificate else {
+            return false;
+        };
+
+        self.client_fingerprint
+            .as_ref()
+            .is_some_and(|fingerprint| *fingerprint == certificate.fingerprint())
+            || (!self.client_cas.is_empty() && certificate.issued_by(&self.client_cas))
+    }
+    /// The current key, or a rotated one still in its grace period.
+    pub fn accepts_key(&self, key: &[u8]) -> bool {
+        self.key == key
+            || self
+                .rotated_keys
+                .iter()
+                .any(|(rotated_key, not_after)| rotated_key == key && *not_after > Utc::now())
+    }
+    /// Whether both are the same port, even if its key was rotated in between.
+    fn is_port(&self, other: &Consumer) -> bool {
+        self.namespace == other.namespace && self.port_name == other.port_name
+    }
+    /// Read-only consumers can't submit transactions.
+    pub fn allows(&self, protocol: &Protocol) -> bool {
+        !(self.read_only && *protocol == Protocol::LocalTxSubmission)
+    }
     pub async fn inc_connections(&self, state: Arc<State>) {
-        state
-            .consumers
-            .write()
-            .await
-            .entry(self.key.clone())
-            .and_modify(|c| c.active_connections += 1);
+        if let Some(consumer) = self.current_mut(&mut *state.consumers.write().await) {
+            consumer.active_connections += 1;
+        }
     }
     pub async fn dec_connections(&mut self, state: Arc<State>) {
-        state
-            .consumers
-            .write()
-            .await
-            .entry(self.key.clone())
-            .and_modify(|c| c.active_connections -= 1);
+        if let Some(consumer) = self.current_mut(&mut *state.consumers.write().await) {
+            consumer.active_connections -= 1;
+        }
     }
     pub async fn get_active_connections(&self, state: Arc<State>) -> usize {
-        state
-            .consumers
-            .read()
-            .await
-            .get(&self.key)
-            .map(|c| c.active_connections)
-            .unwrap_or_default()
+        let consumers = state.consumers.read().await;
+        match consumers.get(&self.key) {
+            Some(consumer) => consumer.active_connections,
+            None => consumers
+                .values()
+                .find(|consumer| consumer.is_port(self))
+                .map(|c| c.active_connections)
+                .unwrap_or_default(),
+        }
+    }
+    /// Entry of the port in the state, under its current key.
+    fn current_mut<'a>(
+        &self,
+        consumers: &'a mut HashMap<Vec<u8>, Consumer>,
+    ) -> Option<&'a mut Consumer> {
+        if consumers.contains_key(&self.key) {
+            return consumers.get_mut(&self.key);
+        }
+        consumers
+            .values_mut()
+            .find(|consumer| consumer.is_port(self))
     }
 }
+fn parse_time(time: Option<&str>) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+    let Some(time) = time else {
+        return Ok(None);
+    };
+    Ok(Some(
+        DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc),
+    ))
+}
+
 impl std::fmt::Display for Consumer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}.{}", self.namespace, self.port_name)
@@ -163,6 +440,48 @@ pub struct Tier {
     name: String,
     rates: Vec<TierRate>,
     max_connections: usize,
+    #[serde(default, deserialize_with = "deserialize_protocols")]
+    allowed_protocols: Option<Vec<Protocol>>,
+    #[serde(default)]
+    denied_queries: Vec<String>,
+    #[serde(default)]
+    query_costs: HashMap<String, usize>,
+    #[serde(default)]
+    query_rates: Vec<TierRate>,
+    max_tx_size: Option<usize>,
+    #[serde(default)]
+    tx_rates: Vec<TierRate>,
+    #[serde(default, deserialize_with = "deserialize_protocol_weights")]
+    protocol_weights: HashMap<Protocol, usize>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    max_session_lifetime: Option<Duration>,
+}
+impl Tier {
+    /// Tiers without `allowed_protocols` allow every mini-protocol. The handshake is always allowed.
+    pub fn allows(&self, protocol: &Protocol) -> bool {
+        match &self.allowed_protocols {
+            Some(allowed) => *protocol == Protocol::Handshake || allowed.contains(protocol),
+            None => true,
+        }
+    }
+    pub fn denies_query(&self, query: &Query) -> bool {
+        self.denied_queries.iter().any(|name| name == query.name)
+    }
+    /// Whether the tier limits state queries, so they must be decoded to be let through.
+    pub fn has_query_policy(&self) -> bool {
+        !self.denied_queries.is_empty()
+            || !self.query_costs.is_empty()
+            || !self.query_rates.is_empty()
+    }
+    /// Queries without a configured cost are charged 1 from the query budget.
+    pub fn query_cost(&self, query: &Query) -> usize {
+        self.query_costs.get(query.name).copied().unwrap_or(1)
+    }
+    pub fn exceeds_tx_size(&self, tx: &Tx) -> bool {
+        self.max_tx_size.is_some_and(|max| tx.size > max)
+    }
 }
 #[derive(Debug, Clone, Deserialize)]
 pub struct TierRate {
@@ -193,11 +512,63 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
     }
 }
 
+pub fn deserialize_optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_duration")] Duration);
+
+    let value: Option<Wrapper> = Deserialize::deserialize(deserializer)?;
+    Ok(value.map(|Wrapper(duration)| duration))
+}
+
+pub fn deserialize_protocols<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<Protocol>>, D::Error> {
+    let value: Option<Vec<String>> = Deserialize::deserialize(deserializer)?;
+    value
+        .map(|protocols| {
+            protocols
+                .iter()
+                .map(|protocol| protocol.parse::<Protocol>())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .map_err(<D::Error as serde::de::Error>::custom)
+}
+
+pub fn deserialize_protocol_weights<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<Protocol, usize>, D::Error> {
+    let value: HashMap<String, usize> = Deserialize::deserialize(deserializer)?;
+    value
+        .into_iter()
+        .map(|(protocol, weight)| Ok((protocol.parse::<Protocol>()?, weight)))
+        .collect::<Result<HashMap<_, _>, String>>()
+        .map_err(<D::Error as serde::de::Error>::custom)
+}
+
 #[derive(Debug, Clone)]
 pub struct Metrics {
     total_packages_bytes: prometheus::IntCounterVec,
+    total_protocol_bytes: prometheus::IntCounterVec,
+    total_protocol_messages: prometheus::IntCounterVec,
     total_connections: prometheus::IntGaugeVec,
     total_connections_denied: prometheus::IntCounterVec,
+    total_protocols_denied: prometheus::IntCounterVec,
+    total_state_queries: prometheus::IntCounterVec,
+    total_state_queries_denied: prometheus::IntCounterVec,
+    total_txs_denied: prometheus::IntCounterVec,
+    total_state_query_cache: prometheus::IntCounterVec,
+    total_chain_sync_intersections: prometheus::IntCounterVec,
+    total_failovers: prometheus::IntCounterVec,
+    total_connections_closed: prometheus::IntCounterVec,
+    total_frames_rejected: prometheus::IntCounterVec,
+    total_relay_connections: prometheus::IntGaugeVec,
+    total_relay_bytes: prometheus::IntCounterVec,
+    total_relay_connections_denied: prometheus::IntCounterVec,
+    total_http_submissions: prometheus::IntCounterVec,
+    total_jsonrpc_requests: prometheus::IntCounterVec,
 }
 impl Metrics {
     pub fn new() -> Self {
@@ -213,6 +584,38 @@ impl Metrics {
         )
         .unwrap();
 
+        let total_protocol_bytes = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_protocol_bytes",
+                "Total bytes transferred by mini-protocol"
+            ),
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "tier",
+                "protocol",
+                "direction"
+            ]
+        )
+        .unwrap();
+
+        let total_protocol_messages = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_protocol_messages",
+                "Total messages transferred by mini-protocol"
+            ),
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "tier",
+                "protocol",
+                "direction"
+            ]
+        )
+        .unwrap();
+
         let total_connections_denied = register_int_counter_vec!(
             opts!(
                 "node_proxy_total_connections_denied",
@@ -222,10 +625,173 @@ impl Metrics {
         )
         .unwrap();
 
+        let total_protocols_denied = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_protocols_denied",
+                "Total frames denied because the mini-protocol is not allowed for the consumer"
+            ),
+            &["consumer", "namespace", "instance", "tier", "protocol"]
+        )
+        .unwrap();
+
+        let total_state_queries = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_state_queries",
+                "Total local-state-query queries by query and era"
+            ),
+            &["consumer", "namespace", "instance", "tier", "query", "era"]
+        )
+        .unwrap();
+
+        let total_state_queries_denied = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_state_queries_denied",
+                "Total local-state-query queries denied by the tier"
+            ),
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "tier",
+                "query",
+                "reason"
+            ]
+        )
+        .unwrap();
+
+        let total_txs_denied = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_txs_denied",
+                "Total transactions denied by the tier"
+            ),
+            &["consumer", "namespace", "instance", "tier", "reason"]
+        )
+        .unwrap();
+
+        let total_state_query_cache = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_state_query_cache",
+                "Total local-state-query queries looked up on the cache"
+            ),
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "tier",
+                "query",
+                "result"
+            ]
+        )
+        .unwrap();
+
+        let total_chain_sync_intersections = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_chain_sync_intersections",
+                "Total chain-sync intersections by who serves the session"
+            ),
+            &["consumer", "namespace", "instance", "tier", "served"]
+        )
+        .unwrap();
+
+        let total_failovers = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_failovers",
+                "Total chain-sync sessions moved to another node"
+            ),
+            &["consumer", "namespace", "instance", "tier", "result"]
+        )
+        .unwrap();
+
+        let total_connections_closed = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_connections_closed",
+                "Total connections closed by close reason"
+            ),
+            &["consumer", "namespace", "instance", "tier", "close_reason"]
+        )
+        .unwrap();
+
+        let total_frames_rejected = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_frames_rejected",
+                "Total client connections closed because of malformed traffic"
+            ),
+            &["consumer", "namespace", "instance", "tier", "reason"]
+        )
+        .unwrap();
+
+        let total_relay_connections = register_int_gauge_vec!(
+            opts!(
+                "node_proxy_total_relay_connections",
+                "Total node-to-node connections by peer"
+            ),
+            &["network", "peer"]
+        )
+        .unwrap();
+
+        let total_relay_bytes = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_relay_bytes",
+                "Total node-to-node bytes transferred by peer"
+            ),
+            &["network", "peer", "direction"]
+        )
+        .unwrap();
+
+        let total_relay_connections_denied = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_relay_connections_denied",
+                "Total node-to-node connections denied by peer"
+            ),
+            &["network", "peer", "reason"]
+        )
+        .unwrap();
+
+        let total_http_submissions = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_http_submissions",
+                "Total transactions submitted on the HTTP endpoint by result"
+            ),
+            &["consumer", "namespace", "instance", "tier", "result"]
+        )
+        .unwrap();
+
+        let total_jsonrpc_requests = register_int_counter_vec!(
+            opts!(
+                "node_proxy_total_jsonrpc_requests",
+                "Total requests on the JSON-RPC gateway by method and result"
+            ),
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "tier",
+                "method",
+                "result"
+            ]
+        )
+        .unwrap();
+
         Self {
             total_packages_bytes,
+            total_protocol_bytes,
+            total_protocol_messages,
             total_connections,
             total_connections_denied,
+            total_protocols_denied,
+            total_state_queries,
+            total_state_queries_denied,
+            total_txs_denied,
+            total_state_query_cache,
+            total_chain_sync_intersections,
+            total_failovers,
+            total_connections_closed,
+            total_frames_rejected,
+            total_relay_connections,
+            total_relay_bytes,
+            total_relay_connections_denied,
+            total_http_submissions,
+            total_jsonrpc_requests,
         }
     }
 
@@ -246,6 +812,48 @@ impl Metrics {
             ])
             .inc_by(value as u64)
     }
+    pub fn count_total_protocol_bytes(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        protocol: &Protocol,
+        direction: &Direction,
+        value: usize,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_protocol_bytes
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                protocol.to_string().as_str(),
+                direction.to_string().as_str(),
+            ])
+            .inc_by(value as u64)
+    }
+    pub fn count_total_protocol_messages(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        protocol: &Protocol,
+        direction: &Direction,
+        value: usize,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_protocol_messages
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                protocol.to_string().as_str(),
+                direction.to_string().as_str(),
+            ])
+            .inc_by(value as u64)
+    }
     pub fn inc_total_connections(&self, consumer: &Consumer, namespace: &str, instance: &str) {
         let consumer_label = consumer.to_string();
         self.total_connections
@@ -284,9 +892,394 @@ impl Metrics {
             ])
             .inc()
     }
+    pub fn count_total_protocols_denied(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        protocol: &Protocol,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_protocols_denied
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                protocol.to_string().as_str(),
+            ])
+            .inc()
+    }
+    pub fn count_total_state_queries(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        query: &Query,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_state_queries
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                query.name,
+                query.era_name(),
+            ])
+            .inc()
+    }
+    pub fn count_total_state_queries_denied(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        query: &Query,
+        reason: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_state_queries_denied
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                query.name,
+                reason,
+            ])
+            .inc()
+    }
+    pub fn count_total_txs_denied(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        reason: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_txs_denied
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                reason,
+            ])
+            .inc()
+    }
+    pub fn count_total_state_query_cache(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        query: &Query,
+        result: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_state_query_cache
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                query.name,
+                result,
+            ])
+            .inc()
+    }
+    pub fn count_total_chain_sync_intersections(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        served: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_chain_sync_intersections
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                served,
+            ])
+            .inc()
+    }
+    pub fn count_total_failovers(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        result: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_failovers
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                result,
+            ])
+            .inc()
+    }
+
+    pub fn count_total_connections_closed(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        close_reason: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_connections_closed
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                close_reason,
+            ])
+            .inc()
+    }
+
+    pub fn count_total_frames_rejected(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        reason: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_frames_rejected
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                reason,
+            ])
+            .inc()
+    }
+
+    pub fn count_total_http_submissions(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        result: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_http_submissions
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                result,
+            ])
+            .inc()
+    }
+
+    pub fn count_total_jsonrpc_requests(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        method: &str,
+        result: &str,
+    ) {
+        let consumer_label = consumer.to_string();
+        self.total_jsonrpc_requests
+            .with_label_values(&[
+                consumer_label.as_str(),
+                namespace,
+                instance,
+                consumer.tier.as_str(),
+                method,
+                result,
+            ])
+            .inc()
+    }
+
+    pub fn inc_total_relay_connections(&self, network: &str, peer: &str) {
+        self.total_relay_connections
+            .with_label_values(&[network, peer])
+            .inc()
+    }
+    pub fn dec_total_relay_connections(&self, network: &str, peer: &str) {
+        self.total_relay_connections
+            .with_label_values(&[network, peer])
+            .dec()
+    }
+    /// Drops the series of a relay peer label once it has no connections left.
+    pub fn remove_relay_peer(&self, network: &str, peer: &str) {
+        let _ = self
+            .total_relay_connections
+            .remove_label_values(&[network, peer]);
+        for direction in [Direction::Inbound, Direction::Outbound] {
+            let _ = self.total_relay_bytes.remove_label_values(&[
+                network,
+                peer,
+                &direction.to_string(),
+            ]);
+        }
+    }
+    pub fn count_total_relay_bytes(
+        &self,
+        network: &str,
+        peer: &str,
+        direction: &str,
+        value: usize,
+    ) {
+        self.total_relay_bytes
+            .with_label_values(&[network, peer, direction])
+            .inc_by(value as u64)
+    }
+    pub fn count_total_relay_connections_denied(&self, network: &str, peer: &str, reason: &str) {
+        self.total_relay_connections_denied
+            .with_label_values(&[network, peer, reason])
+            .inc()
+    }
 }
+
 impl Default for Metrics {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Consumer of a port. Tests share the state, so each uses its own ports.
+    fn consumer(port: &str, key: &[u8]) -> Consumer {
+        Consumer {
+            namespace: "prj-test".into(),
+            port_name: port.into(),
+            key: key.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn limiter_key_follows_rotation() {
+        let state = State::shared();
+        let old = consumer("rotated", b"old");
+        state
+            .consumers
+            .write()
+            .await
+            .insert(old.key.clone(), old.clone());
+        assert_eq!(state.limiter_key(&old).await, b"old");
+
+        // The port was rotated, connections opened with the old key share the new buckets.
+        let mut current = consumer("rotated", b"new");
+        current.rotated_keys = vec![(b"old".to_vec(), Utc::now() + chrono::Duration::hours(1))];
+        {
+            let mut consumers = state.consumers.write().await;
+            consumers.remove(&old.key);
+            consumers.insert(current.key.clone(), current.clone());
+        }
+        assert_eq!(state.limiter_key(&old).await, b"new");
+        assert_eq!(state.limiter_key(&current).await, b"new");
+
+        let other = consumer("not-rotated", b"not-rotated");
+        assert_eq!(state.limiter_key(&other).await, b"not-rotated");
+    }
+
+    #[test]
+    fn validity_window_boundaries() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        let mut port = consumer("validity", b"validity");
+        assert!(port.is_valid_at(now));
+        assert_eq!(port.expires_in_at(now), None);
+
+        // `validFrom` is inclusive and `validUntil` exclusive.
+        port.valid_from = Some(now);
+        port.valid_until = Some(now + hour);
+        assert!(!port.is_valid_at(now - chrono::Duration::milliseconds(1)));
+        assert!(port.is_valid_at(now));
+        assert!(port.is_valid_at(now + hour - chrono::Duration::milliseconds(1)));
+        assert!(!port.is_valid_at(now + hour));
+
+        assert_eq!(port.expires_in_at(now), Some(Duration::from_secs(3600)));
+        assert_eq!(port.expires_in_at(now + hour), Some(Duration::ZERO));
+        assert_eq!(port.expires_in_at(now + hour + hour), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn extended_validity_keeps_the_connection() {
+        let state = State::shared();
+        let mut port = consumer("extended", b"extended");
+        port.valid_until = Some(Utc::now() - chrono::Duration::seconds(1));
+        state
+            .consumers
+            .write()
+            .await
+            .insert(port.key.clone(), port.clone());
+
+        // Connections re-read the port when `validUntil` is reached, and close without it.
+        assert_eq!(port.expires_in(), Some(Duration::ZERO));
+        assert!(state.get_consumer(&port.key).await.is_none());
+
+        // The port was updated with a later `validUntil`, so the connection goes on.
+        let mut extended = port.clone();
+        extended.valid_until = Some(Utc::now() + chrono::Duration::hours(1));
+        state
+            .consumers
+            .write()
+            .await
+            .insert(extended.key.clone(), extended);
+        let renewed = state.get_consumer(&port.key).await.unwrap();
+        assert!(renewed.expires_in().unwrap() > Duration::from_secs(3500));
+    }
+
+    fn tier(extra: &str) -> Tier {
+        toml::from_str(&format!(
+            r#"
+name = "0"
+max_connections = 1
+rates = [{{ limit = 1024, interval = "1s" }}]
+{extra}
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn query_policy() {
+        assert!(!tier("").has_query_policy());
+        assert!(tier(r#"denied_queries = ["DebugEpochState"]"#).has_query_policy());
+        assert!(tier("query_costs = { GetUTxOByAddress = 10 }").has_query_policy());
+        assert!(tier("[[query_rates]]\nlimit = 10\ninterval = \"1m\"").has_query_policy());
+    }
+
+    #[tokio::test]
+    async fn fingerprint_shared_by_ports_needs_the_token() {
+        let state = State::shared();
+        let mut port = consumer("certificate", b"certificate");
+        port.client_fingerprint = Some(vec![1; 32]);
+        state
+            .consumers
+            .write()
+            .await
+            .insert(port.key.clone(), port.clone());
+
+        let found = state.get_consumer_by_fingerprint(&[1; 32]).await;
+        assert_eq!(found.map(|c| c.key), Some(b"certificate".to_vec()));
+        assert!(state.get_consumer_by_fingerprint(&[2; 32]).await.is_none());
+
+        let mut other = consumer("same-certificate", b"same-certificate");
+        other.namespace = "prj-other".into();
+        other.client_fingerprint = Some(vec![1; 32]);
+        state
+            .consumers
+            .write()
+            .await
+            .insert(other.key.clone(), other);
+        assert!(state.get_consumer_by_fingerprint(&[1; 32]).await.is_none());
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp, connectors::TransportConnector, protocols::Stream, server::ShutdownWatch,
+};
+use regex::Regex;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    select,
+    time::{interval, timeout},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    client::NodeSession,
+    config::Config,
+    monitor::{self, Monitor},
+    mux::{Direction, Protocol},
+    proxy::{expired, throttle, CloseReason},
+    websocket, Consumer, State, Tier,
+};
+
+/// Time the client has to send the request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Comments sent while the mempool doesn't change, so proxies in between keep the stream open.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Streams the mempool of the consumer's instance as Server-Sent Events, following it with a
+/// local-tx-monitor session. The stream counts as a connection of the consumer.
+pub struct MempoolApp {
+    client_connector: TransportConnector,
+    host_regex: Regex,
+    config: Arc<Config>,
+    state: Arc<State>,
+}
+impl MempoolApp {
+    pub fn new(config: Arc<Config>, state: Arc<State>) -> Self {
+        Self {
+            client_connector: TransportConnector::new(None),
+            host_regex: Regex::new(r"([\w\d-]+)\..+").unwrap(),
+            config,
+            state,
+        }
+    }
+
+    async fn consumer(&self, host: Option<&str>) -> Option<Consumer> {
+        let token = self.host_regex.captures(host?)?.get(1)?.as_str();
+        let (_hrp, key) = bech32::decode(token).ok()?;
+        self.state
+            .get_consumer(&key)
+            .await
+            .filter(|consumer| consumer.accepts(None))
+    }
+
+    /// Checks the consumer can open the stream, failing with the response status and reason.
+    async fn admit(
+        &self,
+        consumer: &Consumer,
+        tier: &Tier,
+        instance: &str,
+    ) -> Result<NodeSession, (&'static str, String)> {
+        let namespace = &self.config.proxy_namespace;
+        let protocol = Protocol::LocalTxMonitor;
+        if !tier.allows(&protocol) || !consumer.allows(&protocol) {
+            self.state
+                .metrics
+                .count_total_protocols_denied(consumer, namespace, instance, &protocol);
+            let reason = format!("{protocol} is not allowed for tier {}", tier.name);
+            return Err(("403 Forbidden", reason));
+        }
+        if consumer.active_connections >= tier.max_connections {
+            self.state
+                .metrics
+                .count_total_connections_denied(consumer, namespace, instance);
+            let reason = format!(
+                "connection limit {} reached for tier {}",
+                tier.max_connections, tier.name
+            );
+            return Err(("429 Too Many Requests", reason));
+        }
+
+        let magic = *self
+            .config
+            .network_magics
+            .get(&consumer.network)
+            .ok_or(("502 Bad Gateway", "unknown network".to_string()))?;
+        NodeSession::connect(&self.client_connector, instance, magic)
+            .await
+            .map_err(|err| ("502 Bad Gateway", err.to_string()))
+    }
+}
+
+#[async_trait]
+impl ServerApp for MempoolApp {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut io_client: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let request = match timeout(REQUEST_TIMEOUT, websocket::read_request(&mut io_client)).await
+        {
+            Ok(Ok(request)) => request,
+            Ok(Err(reason)) => {
+                respond(&mut io_client, "400 Bad Request", &reason).await;
+                return None;
+            }
+            Err(_) => return None,
+        };
+        if request.method != "GET" {
+            respond(
+                &mut io_client,
+                "405 Method Not Allowed",
+                "method not allowed",
+            )
+            .await;
+            return None;
+        }
+
+        let Some(consumer) = self.consumer(request.host()).await else {
+            respond(&mut io_client, "401 Unauthorized", "unknown token").await;
+            return None;
+        };
+        let Some(tier) = self.state.tiers.read().await.get(&consumer.tier).cloned() else {
+            error!(consumer = consumer.to_string(), "tier not found");
+            let reason = format!("tier {} not found", consumer.tier);
+            respond(&mut io_client, "500 Internal Server Error", &reason).await;
+            return None;
+        };
+        let instance = format!(
+            "node-{}-{}.{}:{}",
+            consumer.network, consumer.version, self.config.node_dns, self.config.node_port
+        );
+
+        let node = match self.admit(&consumer, &tier, &instance).await {
+            Ok(node) => node,
+            Err((status, reason)) => {
+                warn!(
+                    consumer = consumer.to_string(),
+                    reason, "mempool stream denied"
+                );
+                respond(&mut io_client, status, &reason).await;
+                return None;
+            }
+        };
+
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+        if io_client.write_all(head.as_bytes()).await.is_err() {
+            return None;
+        }
+
+        let namespace = &self.config.proxy_namespace;
+        consumer.inc_connections(self.state.clone()).await;
+        self.state
+            .metrics
+            .inc_total_connections(&consumer, namespace, &instance);
+
+        let mut watcher = Watcher {
+            app: self,
+            consumer,
+            tier: &tier,
+            instance: &instance,
+            node,
+            started: Instant::now(),
+            last_activity: Instant::now(),
+        };
+        let close_reason = match watcher.stream(&mut io_client).await {
+            Ok(reason) => reason,
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    consumer = watcher.consumer.to_string(),
+                    "mempool stream error"
+                );
+                CloseReason::Error
+            }
+        };
+        let mut consumer = watcher.consumer;
+        self.state.metrics.count_total_connections_closed(
+            &consumer,
+            namespace,
+            &instance,
+            &close_reason.to_string(),
+        );
+
+        consumer.dec_connections(self.state.clone()).await;
+        self.state
+            .metrics
+            .dec_total_connections(&consumer, namespace, &instance);
+        info!(
+            consumer = consumer.to_string(),
+            close_reason = close_reason.to_string(),
+            "mempool stream closed"
+        );
+
+        None
+    }
+}
+
+/// Local-tx-monitor session of a stream.
+struct Watcher<'a> {
+    app: &'a MempoolApp,
+    consumer: Consumer,
+    tier: &'a Tier,
+    instance: &'a str,
+    node: NodeSession,
+    started: Instant,
+    last_activity: Instant,
+}
+impl Watcher<'_> {
+    /// Sends a `snapshot` event each time the mempool changes, followed by the txs `added` and
+    /// `removed` since the previous one.
+    async fn stream(
+        &mut self,
+        io: &mut Stream,
+    ) -> Result<CloseReason, Box<dyn Error + Send + Sync>> {
+        let mut keepalive = interval(KEEPALIVE_INTERVAL);
+        let mut mempool = HashSet::new();
+        let mut buf = [0; 1024];
+
+        self.send(&monitor::acquire()).await?;
+        loop {
+            let expiration = self.expiration();
+            select! {
+                reply = self.node.wait(Protocol::LocalTxMonitor) => {
+                    self.last_activity = Instant::now();
+                    let reply = reply?;
+                    self.received(reply.len()).await;
+                    let Monitor::Acquired(slot) = Monitor::decode(&reply)? else {
+                        return Err("unexpected local-tx-monitor reply".into());
+                    };
+
+                    let (sizes, txs) = self.snapshot().await?;
+                    let events = snapshot_events(slot, &sizes, &mempool, &txs);
+                    io.write_all(events.as_bytes()).await?;
+                    io.flush().await?;
+                    mempool = txs;
+
+                    // Waits for the mempool to change.
+                    self.send(&monitor::acquire()).await?;
+                }
+                read = io.read(&mut buf) => {
+                    if read? == 0 {
+                        return Ok(CloseReason::ClientClosed);
+                    }
+                    self.last_activity = Instant::now();
+                }
+                _ = keepalive.tick() => {
+                    io.write_all(b": keep-alive\n\n").await?;
+                    io.flush().await?;
+                }
+                reason = expired(expiration) => {
+                    // The validity window of the port may have been extended since the stream
+                    // started.
+                    if let CloseReason::TokenExpired = reason {
+                        if let Some(consumer) = self.app.state.get_consumer(&self.consumer.key).await {
+                            self.consumer = consumer;
+                            continue;
+                        }
+                    }
+                    return Ok(reason);
+                }
+            }
+        }
+    }
+
+    /// When the stream goes past the idle timeout or the session lifetime of the tier, or the
+    /// validity window of the port. Keep-alive comments don't count as activity.
+    fn expiration(&self) -> Option<(Instant, CloseReason)> {
+        let idle = self
+            .tier
+            .idle_timeout
+            .map(|timeout| (self.last_activity + timeout, CloseReason::IdleTimeout));
+        let lifetime = self
+            .tier
+            .max_session_lifetime
+            .map(|lifetime| (self.started + lifetime, CloseReason::MaxLifetime));
+        let validity = self
+            .consumer
+            .expires_in()
+            .map(|left| (Instant::now() + left, CloseReason::TokenExpired));
+        idle.into_iter()
+            .chain(lifetime)
+            .chain(validity)
+            .min_by_key(|(at, _)| *at)
+    }
+
+    /// Sizes and tx ids of the acquired snapshot.
+    async fn snapshot(
+        &mut self,
+    ) -> Result<(monitor::Sizes, HashSet<String>), Box<dyn Error + Send + Sync>> {
+        let Monitor::Sizes(sizes) = Monitor::decode(&self.request(&monitor::get_sizes()).await?)?
+        else {
+            return Err("unexpected local-tx-monitor reply".into());
+        };
+
+        let mut txs = HashSet::new();
+        loop {
+            match Monitor::decode(&self.request(&monitor::next_tx()).await?)? {
+                Monitor::NextTx(Some(tx)) => txs.extend(tx.hash),
+                Monitor::NextTx(None) => return Ok((sizes, txs)),
+                _ => return Err("unexpected local-tx-monitor reply".into()),
+            }
+        }
+    }
+
+    async fn request(&mut self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        self.send(message).await?;
+        let reply = self.node.receive(Protocol::LocalTxMonitor).await?;
+        self.received(reply.len()).await;
+        Ok(reply)
+    }
+
+    async fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.node.send(Protocol::LocalTxMonitor, message).await?;
+        self.count(Direction::Inbound, message.len());
+        Ok(())
+    }
+
+    /// Node messages are charged from the consumer byte limits, as on N2C connections.
+    async fn received(&self, bytes: usize) {
+        throttle(
+            &self.app.state,
+            &self.app.state.limiter,
+            &self.consumer,
+            &self.tier.rates,
+            bytes,
+        )
+        .await;
+        self.count(Direction::Outbound, bytes);
+    }
+
+    fn count(&self, direction: Direction, bytes: usize) {
+        let metrics = &self.app.state.metrics;
+        let namespace = &self.app.config.proxy_namespace;
+        let protocol = Protocol::LocalTxMonitor;
+        metrics.count_total_packages_bytes(&self.consumer, namespace, self.instance, bytes);
+        metrics.count_total_protocol_bytes(
+            &self.consumer,
+            namespace,
+            self.instance,
+            &protocol,
+            &direction,
+            bytes,
+        );
+        metrics.count_total_protocol_messages(
+            &self.consumer,
+            namespace,
+            self.instance,
+            &protocol,
+            &direction,
+            1,
+        );
+    }
+}
+
+/// `snapshot` event of the mempool, followed by the txs `added` and `removed` since the previous
+/// one when there are any.
+fn snapshot_events(
+    slot: u64,
+    sizes: &monitor::Sizes,
+    previous: &HashSet<String>,
+    txs: &HashSet<String>,
+) -> String {
+    let mut events = event(
+        "snapshot",
+        &json!({
+            "slot": slot,
+            "capacity": sizes.capacity,
+            "size": sizes.size,
+            "count": sizes.count,
+        }),
+    );
+    let (added, removed) = monitor::diff(previous, txs);
+    if !added.is_empty() {
+        events.push_str(&event("added", &json!({"slot": slot, "txs": added})));
+    }
+    if !removed.is_empty() {
+        events.push_str(&event("removed", &json!({"slot": slot, "txs": removed})));
+    }
+    events
+}
+
+fn event(name: &str, data: &Value) -> String {
+    format!("event: {name}\ndata: {data}\n\n")
+}
+
+async fn respond(io: &mut Stream, status: &str, reason: &str) {
+    let body = json!(reason).to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = io.write_all(response.as_bytes()).await;
+    let _ = io.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txs(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn events_of_a_snapshot() {
+        let sizes = monitor::Sizes {
+            capacity: 4096,
+            size: 100,
+            count: 2,
+        };
+        let snapshot =
+            "event: snapshot\ndata: {\"capacity\":4096,\"count\":2,\"size\":100,\"slot\":7}\n\n";
+
+        assert_eq!(
+            snapshot_events(7, &sizes, &txs(&["a"]), &txs(&["a"])),
+            snapshot
+        );
+        assert_eq!(
+            snapshot_events(7, &sizes, &txs(&["a", "b"]), &txs(&["b", "c"])),
+            format!(
+                "{snapshot}{}{}",
+                "event: added\ndata: {\"slot\":7,\"txs\":[\"c\"]}\n\n",
+                "event: removed\ndata: {\"slot\":7,\"txs\":[\"a\"]}\n\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+
+use pallas_codec::minicbor::{decode, Decoder, Encoder};
+
+use crate::submission::Tx;
+
+/// Local-tx-monitor replies the proxy reads to follow the mempool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Monitor {
+    /// `MsgAcquired` with the slot of the snapshot.
+    Acquired(u64),
+    /// `MsgReplyNextTx`, `None` once every tx of the snapshot was returned.
+    NextTx(Option<Tx>),
+    Sizes(Sizes),
+}
+impl Monitor {
+    pub fn decode(message: &[u8]) -> Result<Self, decode::Error> {
+        let mut d = Decoder::new(message);
+        let len = d.array()?;
+
+        match d.u16()? {
+            2 => Ok(Monitor::Acquired(d.u64()?)),
+            6 if len == Some(1) => Ok(Monitor::NextTx(None)),
+            6 => Ok(Monitor::NextTx(Some(Tx::decode(&mut d)?))),
+            10 => {
+                d.array()?;
+                Ok(Monitor::Sizes(Sizes {
+                    capacity: d.u32()?,
+                    size: d.u32()?,
+                    count: d.u32()?,
+                }))
+            }
+            _ => Err(decode::Error::message(
+                "unexpected local-tx-monitor message",
+            )),
+        }
+    }
+}
+
+/// Mempool capacity and size in bytes, and the number of txs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sizes {
+    pub capacity: u32,
+    pub size: u32,
+    pub count: u32,
+}
+
+/// Txs of a snapshot that weren't in the previous one, and the ones that left it, sorted.
+pub fn diff(previous: &HashSet<String>, txs: &HashSet<String>) -> (Vec<String>, Vec<String>) {
+    let mut added: Vec<String> = txs.difference(previous).cloned().collect();
+    let mut removed: Vec<String> = previous.difference(txs).cloned().collect();
+    added.sort();
+    removed.sort();
+    (added, removed)
+}
+
+/// `MsgAcquire`, or `MsgAwaitAcquire` once acquired, which waits for the mempool to change.
+pub fn acquire() -> Vec<u8> {
+    message(1)
+}
+
+pub fn next_tx() -> Vec<u8> {
+    message(5)
+}
+
+pub fn get_sizes() -> Vec<u8> {
+    message(9)
+}
+
+fn message(tag: u16) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(1).unwrap().u16(tag).unwrap();
+    e.into_writer()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txs(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        // The first snapshot adds every tx already in the mempool.
+        let (added, removed) = diff(&txs(&[]), &txs(&["b", "a"]));
+        assert_eq!(added, ["a", "b"]);
+        assert!(removed.is_empty());
+
+        let (added, removed) = diff(&txs(&["a", "b"]), &txs(&["b", "d", "c"]));
+        assert_eq!(added, ["c", "d"]);
+        assert_eq!(removed, ["a"]);
+
+        let (added, removed) = diff(&txs(&["a"]), &txs(&["a"]));
+        assert!(added.is_empty() && removed.is_empty());
+
+        let (added, removed) = diff(&txs(&["a", "b"]), &txs(&[]));
+        assert!(added.is_empty());
+        assert_eq!(removed, ["a", "b"]);
+    }
+
+    #[test]
+    fn replies() {
+        assert_eq!(
+            Monitor::decode(&[0x82, 0x02, 0x18, 0x2a]).unwrap(),
+            Monitor::Acquired(42)
+        );
+        assert_eq!(
+            Monitor::decode(&[0x81, 0x06]).unwrap(),
+            Monitor::NextTx(None)
+        );
+        assert_eq!(
+            Monitor::decode(&[0x82, 0x0a, 0x83, 0x19, 0x10, 0x00, 0x18, 0x64, 0x02]).unwrap(),
+            Monitor::Sizes(Sizes {
+                capacity: 4096,
+                size: 100,
+                count: 2,
+            })
+        );
+        assert!(Monitor::decode(&[0x81, 0x03]).is_err());
+    }
+}
@@ -0,0 +1,867 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    str::FromStr,
+};
+
+use pallas_codec::minicbor::decode;
+
+/// Size of the Ouroboros mux SDU header: timestamp (4), mode + protocol id (2) and payload
+/// length (2).
+pub const HEADER_LEN: usize = 8;
+
+/// Largest payload used when the proxy segments a message by itself. It's the SDU size used by
+/// the node on N2C bearers.
+pub const MAX_SEGMENT_PAYLOAD_LEN: usize = 12288;
+
+const MODE_MASK: u16 = 0x8000;
+const PROTOCOL_MASK: u16 = 0x7fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Initiator,
+    Responder,
+}
+impl Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Initiator => write!(f, "initiator"),
+            Mode::Responder => write!(f, "responder"),
+        }
+    }
+}
+
+/// Side of the proxy the traffic comes from. Inbound traffic is sent by the client to the node and
+/// outbound traffic is sent by the node to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Inbound => write!(f, "inbound"),
+            Direction::Outbound => write!(f, "outbound"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Handshake,
+    ChainSync,
+    LocalTxSubmission,
+    LocalStateQuery,
+    LocalTxMonitor,
+    Unknown(u16),
+}
+impl Protocol {
+    pub fn id(&self) -> u16 {
+        match self {
+            Protocol::Handshake => 0,
+            Protocol::ChainSync => 5,
+            Protocol::LocalTxSubmission => 6,
+            Protocol::LocalStateQuery => 7,
+            Protocol::LocalTxMonitor => 9,
+            Protocol::Unknown(id) => *id,
+        }
+    }
+}
+impl From<u16> for Protocol {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Protocol::Handshake,
+            5 => Protocol::ChainSync,
+            6 => Protocol::LocalTxSubmission,
+            7 => Protocol::LocalStateQuery,
+            9 => Protocol::LocalTxMonitor,
+            id => Protocol::Unknown(id),
+        }
+    }
+}
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "handshake" => Ok(Protocol::Handshake),
+            "chain-sync" => Ok(Protocol::ChainSync),
+            "local-tx-submission" => Ok(Protocol::LocalTxSubmission),
+            "local-state-query" => Ok(Protocol::LocalStateQuery),
+            "local-tx-monitor" => Ok(Protocol::LocalTxMonitor),
+            _ => Err(format!("unknown mini-protocol {s}")),
+        }
+    }
+}
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Handshake => write!(f, "handshake"),
+            Protocol::ChainSync => write!(f, "chain-sync"),
+            Protocol::LocalTxSubmission => write!(f, "local-tx-submission"),
+            Protocol::LocalStateQuery => write!(f, "local-state-query"),
+            Protocol::LocalTxMonitor => write!(f, "local-tx-monitor"),
+            Protocol::Unknown(id) => write!(f, "unknown-{id}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub timestamp: u32,
+    pub mode: Mode,
+    pub protocol: Protocol,
+    pub payload_len: u16,
+}
+impl Header {
+    pub fn decode(bytes: &[u8; HEADER_LEN]) -> Self {
+        let timestamp = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let protocol = u16::from_be_bytes([bytes[4], bytes[5]]);
+        let payload_len = u16::from_be_bytes([bytes[6], bytes[7]]);
+
+        let mode = match protocol & MODE_MASK {
+            0 => Mode::Initiator,
+            _ => Mode::Responder,
+        };
+
+        Self {
+            timestamp,
+            mode,
+            protocol: Protocol::from(protocol & PROTOCOL_MASK),
+            payload_len,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mode = match self.mode {
+            Mode::Initiator => 0,
+            Mode::Responder => MODE_MASK,
+        };
+        let protocol = (self.protocol.id() & PROTOCOL_MASK) | mode;
+
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[4..6].copy_from_slice(&protocol.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.payload_len.to_be_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+impl Frame {
+    /// Splits a mini-protocol message into as many frames as needed.
+    pub fn segments(protocol: Protocol, mode: Mode, timestamp: u32, message: &[u8]) -> Vec<Self> {
+        message
+            .chunks(MAX_SEGMENT_PAYLOAD_LEN)
+            .map(|chunk| Self {
+                header: Header {
+                    timestamp,
+                    mode,
+                    protocol,
+                    payload_len: chunk.len() as u16,
+                },
+                payload: chunk.to_vec(),
+            })
+            .collect()
+    }
+
+    pub fn size(&self) -> usize {
+        HEADER_LEN + self.payload.len()
+    }
+
+    /// Checks a frame sent by the client, which is the initiator of every N2C mini-protocol.
+    pub fn validate_inbound(&self, max_payload_len: usize) -> Result<(), FrameError> {
+        if self.payload.len() > max_payload_len {
+            return Err(FrameError::Oversized);
+        }
+        if let Protocol::Unknown(_) = self.header.protocol {
+            return Err(FrameError::UnknownProtocol);
+        }
+        if self.header.mode != Mode::Initiator {
+            return Err(FrameError::WrongDirection);
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend_from_slice(&self.header.encode());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Reason the traffic of a client is rejected before it reaches the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    Oversized,
+    UnknownProtocol,
+    WrongDirection,
+    /// A mini-protocol other than the handshake was used before the handshake.
+    MissingHandshake,
+    HandshakeTimeout,
+    /// The payloads don't decode as mini-protocol messages.
+    InvalidMessage,
+}
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Oversized => write!(f, "oversized"),
+            FrameError::UnknownProtocol => write!(f, "unknown_protocol"),
+            FrameError::WrongDirection => write!(f, "wrong_direction"),
+            FrameError::MissingHandshake => write!(f, "missing_handshake"),
+            FrameError::HandshakeTimeout => write!(f, "handshake_timeout"),
+            FrameError::InvalidMessage => write!(f, "invalid_message"),
+        }
+    }
+}
+
+/// Reassembles mux frames from a byte stream. Reads from the socket can end in the middle of a
+/// header or a payload, so the bytes are kept until a whole frame is available.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        if self.buffer.len() < HEADER_LEN {
+            return None;
+        }
+
+        let header = Header::decode(self.buffer[0..HEADER_LEN].try_into().unwrap());
+        let frame_len = HEADER_LEN + header.payload_len as usize;
+        if self.buffer.len() < frame_len {
+            return None;
+        }
+
+        let payload = self.buffer[HEADER_LEN..frame_len].to_vec();
+        self.buffer.drain(0..frame_len);
+
+        Some(Frame { header, payload })
+    }
+}
+
+/// Reassembles mini-protocol messages from frame payloads. A message can be split across many
+/// frames and a frame can carry many messages, so the payloads are buffered per protocol until a
+/// complete CBOR item is available.
+#[derive(Debug, Default)]
+pub struct MessageDecoder {
+    buffers: HashMap<Protocol, MessageBuffer>,
+    /// Largest message accepted, unlimited when `None`.
+    max_len: Option<usize>,
+}
+impl MessageDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails once a message grows past `max_len` bytes, instead of buffering it.
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self {
+            max_len: Some(max_len),
+            ..Default::default()
+        }
+    }
+
+    /// Whether there is no partial message buffered for the protocol.
+    pub fn is_empty(&self, protocol: &Protocol) -> bool {
+        self.buffers
+            .get(protocol)
+            .is_none_or(|buffer| buffer.bytes.is_empty())
+    }
+
+    pub fn push(&mut self, frame: &Frame) -> Result<Vec<Vec<u8>>, decode::Error> {
+        let buffer = self.buffers.entry(frame.header.protocol).or_default();
+        buffer.bytes.extend_from_slice(&frame.payload);
+
+        let result = buffer.messages(self.max_len);
+        if result.is_err() {
+            *buffer = MessageBuffer::default();
+        }
+        result
+    }
+}
+
+/// Bytes of a protocol and the progress of the CBOR item at their start, so each frame only scans
+/// its own bytes.
+#[derive(Debug, Default)]
+struct MessageBuffer {
+    bytes: Vec<u8>,
+    position: usize,
+    /// Items left in each array or map the scan is in, `None` for indefinite lengths.
+    open: Vec<Option<u64>>,
+}
+impl MessageBuffer {
+    fn messages(&mut self, max_len: Option<usize>) -> Result<Vec<Vec<u8>>, decode::Error> {
+        let mut messages = Vec::new();
+        let mut start = 0;
+        while self.position < self.bytes.len() {
+            match self.scan(start, max_len)? {
+                Some(end) => {
+                    messages.push(self.bytes[start..end].to_vec());
+                    start = end;
+                }
+                None => break,
+            }
+        }
+        self.bytes.drain(0..start);
+        self.position -= start;
+
+        if max_len.is_some_and(|max_len| self.bytes.len() > max_len) {
+            return Err(decode::Error::message("message too large"));
+        }
+        Ok(messages)
+    }
+
+    /// Scans the item starting at `start`, returning where it ends or `None` when more bytes are
+    /// needed.
+    fn scan(
+        &mut self,
+        start: usize,
+        max_len: Option<usize>,
+    ) -> Result<Option<usize>, decode::Error> {
+        loop {
+            let Some(&initial) = self.bytes.get(self.position) else {
+                return Ok(None);
+            };
+            let major = initial >> 5;
+            let info = initial & 0x1f;
+            let argument_len = match info {
+                0..=23 => 0,
+                24 => 1,
+                25 => 2,
+                26 => 4,
+                27 => 8,
+                31 if matches!(major, 2..=5 | 7) => 0,
+                _ => return Err(decode::Error::message("invalid cbor additional info")),
+            };
+            let Some(argument) = self
+                .bytes
+                .get(self.position + 1..self.position + 1 + argument_len)
+            else {
+                return Ok(None);
+            };
+            let argument = match info {
+                0..=23 => info as u64,
+                _ => argument.iter().fold(0, |n, b| (n << 8) | *b as u64),
+            };
+            let header_end = self.position + 1 + argument_len;
+
+            let complete = match (major, info) {
+                (2..=5, 31) => {
+                    self.open.push(None);
+                    false
+                }
+                (0 | 1, _) | (7, 0..=27) => true,
+                (2 | 3, _) => {
+                    let end = (header_end as u64).saturating_add(argument);
+                    if max_len.is_some_and(|max_len| end - start as u64 > max_len as u64) {
+                        return Err(decode::Error::message("message too large"));
+                    }
+                    if end > self.bytes.len() as u64 {
+                        return Ok(None);
+                    }
+                    self.position = end as usize;
+                    self.item_done();
+                    if self.open.is_empty() {
+                        return Ok(Some(self.position));
+                    }
+                    continue;
+                }
+                (4, _) | (5, _) => {
+                    let items = if major == 5 {
+                        argument.saturating_mul(2)
+                    } else {
+                        argument
+                    };
+                    if items > 0 {
+                        self.open.push(Some(items));
+                    }
+                    items == 0
+                }
+                // A tag is followed by the tagged item.
+                (6, _) => false,
+                (7, _) => {
+                    if self.open.pop() != Some(None) {
+                        return Err(decode::Error::message("unexpected cbor break"));
+                    }
+                    true
+                }
+                _ => unreachable!(),
+            };
+
+            self.position = header_end;
+            if complete {
+                self.item_done();
+                if self.open.is_empty() {
+                    return Ok(Some(self.position));
+                }
+            }
+        }
+    }
+
+    /// Counts a finished item in the containers it's in, closing the ones it completes.
+    fn item_done(&mut self) {
+        while let Some(Some(items)) = self.open.last_mut() {
+            *items -= 1;
+            if *items > 0 {
+                return;
+            }
+            self.open.pop();
+        }
+    }
+}
+
+/// Queues the frames sent to the client by mini-protocol and picks the next one with deficit round
+/// robin, so each protocol gets a share of the bandwidth by its weight and a big chain-sync block
+/// doesn't hold the small replies of the other protocols.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    weights: HashMap<Protocol, usize>,
+    queues: HashMap<Protocol, VecDeque<Frame>>,
+    deficits: HashMap<Protocol, usize>,
+    /// Protocols with queued frames, the first one has the turn.
+    active: VecDeque<Protocol>,
+    size: usize,
+}
+impl Scheduler {
+    /// Protocols without a weight have weight 1.
+    pub fn new(weights: HashMap<Protocol, usize>) -> Self {
+        Self {
+            weights,
+            ..Default::default()
+        }
+    }
+
+    /// Bytes of the queued frames.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        let protocol = frame.header.protocol;
+        self.size += frame.size();
+
+        let queue = self.queues.entry(protocol).or_default();
+        if queue.is_empty() {
+            self.active.push_back(protocol);
+        }
+        queue.push_back(frame);
+    }
+
+    /// The frame `pop` returns next. Calling it again without a `pop` returns the same frame.
+    pub fn peek(&mut self) -> Option<&Frame> {
+        loop {
+            let protocol = *self.active.front()?;
+            let size = self.queues[&protocol].front()?.size();
+            let deficit = self.deficits.entry(protocol).or_default();
+            if *deficit >= size {
+                return self.queues[&protocol].front();
+            }
+
+            let weight = self.weights.get(&protocol).copied().unwrap_or(1).max(1);
+            *deficit += weight * (HEADER_LEN + MAX_SEGMENT_PAYLOAD_LEN);
+            self.active.rotate_left(1);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.peek()?;
+
+        let protocol = *self.active.front()?;
+        let queue = self.queues.get_mut(&protocol)?;
+        let frame = queue.pop_front()?;
+        self.size -= frame.size();
+
+        let deficit = self.deficits.entry(protocol).or_default();
+        *deficit -= frame.size();
+        if queue.is_empty() {
+            *deficit = 0;
+            self.active.pop_front();
+        }
+
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// N2C client traffic: the handshake, a local-state-query acquire, `GetCurrentEra` and release,
+    /// and a chain-sync `MsgFindIntersect` at the origin, one message per frame.
+    const CLIENT_TRAFFIC: &str = concat!(
+        "0000000a00000009",
+        "8200a11980108202f4",
+        "0000001400070002",
+        "8108",
+        "0000001e00070008",
+        "8203820082028101",
+        "0000002800050004",
+        "82048180",
+        "0000003200070002",
+        "8105",
+    );
+
+    /// Bytes of a hex string.
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn client_traffic() -> Vec<u8> {
+        from_hex(CLIENT_TRAFFIC)
+    }
+
+    fn frame(protocol: Protocol, payload: &[u8]) -> Frame {
+        Frame {
+            header: Header {
+                timestamp: 0,
+                mode: Mode::Initiator,
+                protocol,
+                payload_len: payload.len() as u16,
+            },
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn decode_all(decoder: &mut FrameDecoder) -> Vec<Frame> {
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        for mode in [Mode::Initiator, Mode::Responder] {
+            for protocol in [0, 5, 6, 7, 9, 42, PROTOCOL_MASK] {
+                let header = Header {
+                    timestamp: 0xdead_beef,
+                    mode,
+                    protocol: Protocol::from(protocol),
+                    payload_len: 12288,
+                };
+                assert_eq!(Header::decode(&header.encode()), header);
+            }
+        }
+    }
+
+    #[test]
+    fn header_decode() {
+        let header = Header::decode(&[0, 0, 0, 1, 0x80, 5, 0x30, 0]);
+        assert_eq!(header.timestamp, 1);
+        assert_eq!(header.mode, Mode::Responder);
+        assert_eq!(header.protocol, Protocol::ChainSync);
+        assert_eq!(header.payload_len, 12288);
+    }
+
+    #[test]
+    fn frame_decoder_whole_stream() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&client_traffic());
+        let frames = decode_all(&mut decoder);
+
+        let protocols: Vec<Protocol> = frames.iter().map(|f| f.header.protocol).collect();
+        assert_eq!(
+            protocols,
+            vec![
+                Protocol::Handshake,
+                Protocol::LocalStateQuery,
+                Protocol::LocalStateQuery,
+                Protocol::ChainSync,
+                Protocol::LocalStateQuery,
+            ]
+        );
+        assert_eq!(frames[1].payload, vec![0x81, 0x08]);
+        let encoded: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+        assert_eq!(encoded, client_traffic());
+    }
+
+    #[test]
+    fn frame_decoder_split_at_every_offset() {
+        let traffic = client_traffic();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&traffic);
+        let expected = decode_all(&mut decoder);
+
+        for offset in 0..=traffic.len() {
+            let mut decoder = FrameDecoder::new();
+            decoder.extend(&traffic[..offset]);
+            let mut frames = decode_all(&mut decoder);
+            decoder.extend(&traffic[offset..]);
+            frames.extend(decode_all(&mut decoder));
+            assert_eq!(frames, expected, "split at {offset}");
+        }
+    }
+
+    #[test]
+    fn frame_decoder_byte_by_byte() {
+        let traffic = client_traffic();
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for byte in &traffic {
+            decoder.extend(&[*byte]);
+            frames.extend(decode_all(&mut decoder));
+        }
+        assert_eq!(frames.len(), 5);
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn frame_decoder_random_reads() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let traffic = client_traffic().repeat(20);
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let mut decoder = FrameDecoder::new();
+            let mut frames = Vec::new();
+            let mut rest = traffic.as_slice();
+            while !rest.is_empty() {
+                let (read, tail) = rest.split_at(rng.random_range(1..=rest.len().min(64)));
+                decoder.extend(read);
+                frames.extend(decode_all(&mut decoder));
+                rest = tail;
+            }
+            assert_eq!(frames.len(), 100);
+        }
+    }
+
+    #[test]
+    fn message_decoder_message_across_frames() {
+        let message = from_hex("8203820082028101");
+        let mut decoder = MessageDecoder::new();
+
+        for chunk in message[..6].chunks(2) {
+            let messages = decoder
+                .push(&frame(Protocol::LocalStateQuery, chunk))
+                .unwrap();
+            assert!(messages.is_empty());
+            assert!(!decoder.is_empty(&Protocol::LocalStateQuery));
+        }
+        let messages = decoder
+            .push(&frame(Protocol::LocalStateQuery, &message[6..]))
+            .unwrap();
+        assert_eq!(messages, vec![message]);
+        assert!(decoder.is_empty(&Protocol::LocalStateQuery));
+    }
+
+    #[test]
+    fn message_decoder_messages_in_one_frame() {
+        let acquire = vec![0x81, 0x08];
+        let query = from_hex("8203820082028101");
+        let release = vec![0x81, 0x05];
+        let payload = [acquire.clone(), query.clone(), release.clone()].concat();
+
+        let mut decoder = MessageDecoder::new();
+        let messages = decoder
+            .push(&frame(Protocol::LocalStateQuery, &payload))
+            .unwrap();
+        assert_eq!(messages, vec![acquire, query, release]);
+    }
+
+    #[test]
+    fn message_decoder_protocols_apart() {
+        let mut decoder = MessageDecoder::new();
+        let query = decoder
+            .push(&frame(Protocol::LocalStateQuery, &[0x82, 0x03]))
+            .unwrap();
+        let intersect = decoder
+            .push(&frame(Protocol::ChainSync, &[0x82, 0x04, 0x81, 0x80]))
+            .unwrap();
+        assert!(query.is_empty());
+        assert_eq!(intersect, vec![vec![0x82, 0x04, 0x81, 0x80]]);
+        assert!(!decoder.is_empty(&Protocol::LocalStateQuery));
+    }
+
+    #[test]
+    fn message_decoder_invalid_cbor() {
+        let mut decoder = MessageDecoder::new();
+        assert!(decoder
+            .push(&frame(Protocol::LocalStateQuery, &[0x1c, 0x00]))
+            .is_err());
+        assert!(decoder.is_empty(&Protocol::LocalStateQuery));
+    }
+
+    #[test]
+    fn message_decoder_cbor_items_split_anywhere() {
+        // Maps, tags, indefinite lengths, floats, long strings and nesting.
+        let items = [
+            "a2010203820405",
+            "d8799f0102ff",
+            "9f9f01ff80bf0102ffff",
+            "5f4201024103ff",
+            "fb3ff8000000000000",
+            "f6",
+            "1b0000000100000000",
+        ];
+        let mut items: Vec<Vec<u8>> = items.iter().map(|i| from_hex(i)).collect();
+        let mut nested = from_hex("8301820203a1187b590100");
+        nested.extend([0; 0x100]);
+        items.push(nested);
+        for item in &items {
+            let mut d = pallas_codec::minicbor::Decoder::new(item);
+            d.skip().unwrap();
+            assert_eq!(d.position(), item.len());
+        }
+
+        let payload = items.concat();
+        for offset in 0..=payload.len() {
+            let mut decoder = MessageDecoder::new();
+            let mut messages = decoder
+                .push(&frame(Protocol::LocalStateQuery, &payload[..offset]))
+                .unwrap();
+            messages.extend(
+                decoder
+                    .push(&frame(Protocol::LocalStateQuery, &payload[offset..]))
+                    .unwrap(),
+            );
+            assert_eq!(messages, items, "split at {offset}");
+            assert!(decoder.is_empty(&Protocol::LocalStateQuery));
+        }
+    }
+
+    #[test]
+    fn message_decoder_unexpected_break() {
+        let mut decoder = MessageDecoder::new();
+        assert!(decoder
+            .push(&frame(Protocol::ChainSync, &[0x82, 0x01, 0xff]))
+            .is_err());
+    }
+
+    #[test]
+    fn message_decoder_max_len() {
+        let mut decoder = MessageDecoder::with_max_len(16);
+        let message = [0x82, 0x03, 0x4a, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let messages = decoder
+            .push(&frame(Protocol::LocalStateQuery, &message))
+            .unwrap();
+        assert_eq!(messages, vec![message.to_vec()]);
+
+        // A bytestring declaring 4 GB is refused from its header.
+        let mut decoder = MessageDecoder::with_max_len(16);
+        assert!(decoder
+            .push(&frame(
+                Protocol::LocalTxSubmission,
+                &[0x82, 0x02, 0x5a, 0xff, 0xff, 0xff, 0xff]
+            ))
+            .is_err());
+        assert!(decoder.is_empty(&Protocol::LocalTxSubmission));
+
+        // So is an array that keeps growing past the limit.
+        let mut decoder = MessageDecoder::with_max_len(16);
+        let open = decoder
+            .push(&frame(Protocol::LocalStateQuery, &[0x82, 0x03, 0x9f]))
+            .unwrap();
+        assert!(open.is_empty());
+        assert!(decoder
+            .push(&frame(Protocol::LocalStateQuery, &[0x01; 16]))
+            .is_err());
+    }
+
+    fn full_frame(protocol: Protocol) -> Frame {
+        frame(protocol, &[0; MAX_SEGMENT_PAYLOAD_LEN])
+    }
+
+    #[test]
+    fn scheduler_respects_weights() {
+        let weights = HashMap::from([(Protocol::ChainSync, 1), (Protocol::LocalStateQuery, 3)]);
+        let mut scheduler = Scheduler::new(weights);
+        for _ in 0..20 {
+            scheduler.push(full_frame(Protocol::ChainSync));
+            scheduler.push(full_frame(Protocol::LocalStateQuery));
+        }
+
+        let order: Vec<Protocol> = (0..8)
+            .map(|_| scheduler.pop().unwrap().header.protocol)
+            .collect();
+        let chain_sync = order.iter().filter(|p| **p == Protocol::ChainSync).count();
+        assert_eq!(chain_sync, 2);
+        assert_eq!(order.len() - chain_sync, 6);
+    }
+
+    #[test]
+    fn scheduler_small_replies_skip_chain_sync_backlog() {
+        let mut scheduler = Scheduler::new(HashMap::new());
+        for _ in 0..100 {
+            scheduler.push(full_frame(Protocol::ChainSync));
+        }
+        scheduler.pop();
+        scheduler.push(frame(Protocol::LocalStateQuery, &[0x82, 0x04, 0x00]));
+
+        let position = (0..4)
+            .position(|_| scheduler.pop().unwrap().header.protocol == Protocol::LocalStateQuery);
+        assert!(position.is_some_and(|p| p <= 1), "reply at {position:?}");
+    }
+
+    #[test]
+    fn scheduler_peek_is_pop() {
+        let mut scheduler = Scheduler::new(HashMap::new());
+        scheduler.push(full_frame(Protocol::ChainSync));
+        scheduler.push(frame(Protocol::LocalStateQuery, &[0x81, 0x01]));
+
+        while !scheduler.is_empty() {
+            let peeked = scheduler.peek().cloned();
+            assert_eq!(scheduler.peek().cloned(), peeked);
+            assert_eq!(scheduler.pop(), peeked);
+        }
+    }
+
+    #[test]
+    fn scheduler_resets_when_drained() {
+        let weights = HashMap::from([(Protocol::LocalStateQuery, 4)]);
+        let mut scheduler = Scheduler::new(weights);
+        scheduler.push(full_frame(Protocol::ChainSync));
+        scheduler.push(frame(Protocol::LocalStateQuery, &[0x81, 0x01]));
+        scheduler.push(frame(Protocol::LocalStateQuery, &[0x81, 0x02]));
+        assert_eq!(
+            scheduler.size(),
+            3 * HEADER_LEN + MAX_SEGMENT_PAYLOAD_LEN + 4
+        );
+
+        let mut popped = 0;
+        while scheduler.pop().is_some() {
+            popped += 1;
+        }
+        assert_eq!(popped, 3);
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.size(), 0);
+        assert!(scheduler.deficits.values().all(|deficit| *deficit == 0));
+    }
+
+    #[test]
+    fn decoders_random_bytes() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..1000 {
+            let len = rng.random_range(0..256);
+            let bytes: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+
+            let mut frames = FrameDecoder::new();
+            frames.extend(&bytes);
+            let mut messages = MessageDecoder::new();
+            while let Some(frame) = frames.next_frame() {
+                let _ = messages.push(&frame);
+            }
+        }
+    }
+}
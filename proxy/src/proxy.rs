@@ -2,33 +2,266 @@ use async_trait::async_trait;
 use futures_util::future::join_all;
 use leaky_bucket::RateLimiter;
 use openssl::ssl::NameType;
+use pallas_codec::minicbor::{self, decode};
+use pallas_network::miniprotocols::{
+    chainsync::{BlockContent, Message as ChainSyncMessage},
+    handshake::VersionNumber,
+    Point,
+};
 use pingora::{
     apps::ServerApp, connectors::TransportConnector, protocols::Stream, server::ShutdownWatch,
     upstreams::peer::BasicPeer, Error, Result,
 };
 use rand::{seq::IndexedRandom, SeedableRng};
 use regex::Regex;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error as StdError,
+    fmt::Display,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::lookup_host,
     select,
+    sync::{watch, RwLock},
+    time::timeout,
+};
+use tracing::{error, info, trace, warn};
+
+use crate::{
+    capture::CaptureWriter,
+    certificate::ClientCertificate,
+    config::Config,
+    follower::{self, Event, Follower, Next},
+    handshake::{self, Proposal},
+    mux::{
+        Direction, Frame, FrameDecoder, FrameError, Header, MessageDecoder, Mode, Protocol,
+        Scheduler,
+    },
+    query::{self, Query, StateQuery},
+    submission::{Submission, Tx},
+    Consumer, State, Tier, TierRate,
 };
-use tracing::{error, info};
 
-use crate::{config::Config, Consumer, State, Tier};
+/// Time the proxy waits for a message it needs to go on, like a handshake it has to answer.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes queued for the client after which the proxy stops reading from the node.
+const MAX_SCHEDULED_BYTES: usize = 1024 * 1024;
 
 struct Context {
     consumer: Consumer,
+    tier: Tier,
     namespace: String,
     instance: String,
+    inbound_messages: MessageDecoder,
+    outbound_messages: MessageDecoder,
+    started: Instant,
+    /// Last time a frame was read from either side.
+    last_activity: Instant,
+    /// Tx waiting for the node to accept or reject it. The protocol allows one at a time.
+    pending_tx: Option<PendingTx>,
+    /// Version agreed on the handshake.
+    version: Option<VersionNumber>,
+    acquisition: Acquisition,
+    /// Query forwarded to the node whose result goes to the cache.
+    cacheable_query: Option<Vec<u8>>,
+    edge: Option<EdgeSession>,
+    /// Address of the node the connection is using.
+    upstream: SocketAddr,
+    /// Mini-protocols used by the client.
+    protocols: HashSet<Protocol>,
+    /// `MsgProposeVersions` sent by the client, replayed on failover.
+    handshake: Option<Vec<u8>>,
+    chain_sync: ChainSyncState,
+    /// Raw frames of the connection are recorded while it's set.
+    capture: Option<CaptureWriter>,
 }
 impl Context {
-    pub fn new(consumer: &Consumer, instance: &str, namespace: &str) -> Self {
+    pub fn new(
+        consumer: &Consumer,
+        tier: &Tier,
+        instance: &str,
+        namespace: &str,
+        upstream: SocketAddr,
+        capture: Option<CaptureWriter>,
+        max_message_len: usize,
+    ) -> Self {
         Self {
             consumer: consumer.clone(),
+            tier: tier.clone(),
             namespace: namespace.into(),
             instance: instance.into(),
+            inbound_messages: MessageDecoder::with_max_len(max_message_len),
+            outbound_messages: MessageDecoder::new(),
+            started: Instant::now(),
+            last_activity: Instant::now(),
+            pending_tx: None,
+            version: None,
+            acquisition: Acquisition::Released,
+            cacheable_query: None,
+            edge: None,
+            upstream,
+            protocols: HashSet::new(),
+            handshake: None,
+            chain_sync: ChainSyncState::default(),
+            capture,
+        }
+    }
+
+    /// When the connection goes past the handshake timeout, the idle timeout or the session
+    /// lifetime of the tier, or the validity window of the port.
+    pub fn expiration(&self, handshake_timeout: Duration) -> Option<(Instant, CloseReason)> {
+        let handshake = self.handshake.is_none().then(|| {
+            (
+                self.started + handshake_timeout,
+                CloseReason::HandshakeTimeout,
+            )
+        });
+        let idle = self
+            .tier
+            .idle_timeout
+            .map(|timeout| (self.last_activity + timeout, CloseReason::IdleTimeout));
+        let lifetime = self
+            .tier
+            .max_session_lifetime
+            .map(|lifetime| (self.started + lifetime, CloseReason::MaxLifetime));
+        let validity = self
+            .consumer
+            .expires_in()
+            .map(|left| (Instant::now() + left, CloseReason::TokenExpired));
+        handshake
+            .into_iter()
+            .chain(idle)
+            .chain(lifetime)
+            .chain(validity)
+            .min_by_key(|(at, _)| *at)
+    }
+
+    /// Timestamp of the frames created by the proxy, in microseconds since the connection started.
+    pub fn timestamp(&self) -> u32 {
+        self.started.elapsed().as_micros() as u32
+    }
+}
+
+pub struct PendingTx {
+    pub tx: Tx,
+    pub submitted_at: SystemTime,
+}
+
+/// Local-state-query state of the client, followed to key the cache by the acquired point.
+enum Acquisition {
+    Released,
+    Acquiring(Option<Vec<u8>>),
+    /// The tip was acquired and the proxy asked the node which point it is.
+    ResolvingTip,
+    Acquired(Vec<u8>),
+}
+
+/// Chain-sync position of the client, kept to resume the session on another node.
+struct ChainSyncState {
+    /// Last point delivered to the client, `None` when it couldn't be decoded.
+    point: Option<Point>,
+    /// Requests sent by the client that the node didn't answer yet, in order. Pipelining clients
+    /// can have many.
+    requests: VecDeque<Vec<u8>>,
+    /// The node answered the first request with `MsgAwaitReply`.
+    awaiting: bool,
+}
+impl ChainSyncState {
+    fn answered(&mut self) {
+        self.requests.pop_front();
+        self.awaiting = false;
+    }
+}
+impl Default for ChainSyncState {
+    fn default() -> Self {
+        Self {
+            point: Some(Point::Origin),
+            requests: VecDeque::new(),
+            awaiting: false,
+        }
+    }
+}
+
+/// Chain-sync session served from the shared follower instead of the node.
+struct EdgeSession {
+    follower: Arc<Follower>,
+    updates: watch::Receiver<u64>,
+    next: u64,
+    /// The node rolls back to the intersection on the first request, so the proxy does too.
+    intersection: Option<Point>,
+    /// The client was told to await and is owed a reply.
+    awaiting: bool,
+    /// Requests sent by the client after the awaited one.
+    pipelined: usize,
+}
+impl EdgeSession {
+    fn is_awaiting(edge: &Option<EdgeSession>) -> bool {
+        edge.as_ref().is_some_and(|session| session.awaiting)
+    }
+
+    async fn updated(edge: &mut Option<EdgeSession>) {
+        if let Some(session) = edge {
+            if session.updates.changed().await.is_ok() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+
+    /// Replies to the awaited request and the ones pipelined after it, as far as the follower goes.
+    async fn pending_replies(&mut self) -> Result<Vec<Vec<u8>>, ()> {
+        let mut replies = Vec::new();
+        if self.awaiting {
+            match self.next_reply().await? {
+                Some(reply) => {
+                    replies.push(reply);
+                    self.awaiting = false;
+                }
+                None => return Ok(replies),
+            }
+        }
+
+        while self.pipelined > 0 {
+            self.pipelined -= 1;
+            match self.next_reply().await? {
+                Some(reply) => replies.push(reply),
+                None => {
+                    replies.push(chain_sync_message(ChainSyncMessage::AwaitReply));
+                    self.awaiting = true;
+                    break;
+                }
+            }
+        }
+
+        Ok(replies)
+    }
+
+    async fn next_reply(&mut self) -> Result<Option<Vec<u8>>, ()> {
+        if let Some(point) = self.intersection.take() {
+            let tip = self.follower.tip().await.ok_or(())?;
+            return Ok(Some(chain_sync_message(ChainSyncMessage::RollBackward(
+                point, tip,
+            ))));
+        }
+
+        match self.follower.next(self.next).await {
+            Next::Event(event, tip) => {
+                self.next += 1;
+                let message = match event {
+                    Event::Forward(block) => ChainSyncMessage::RollForward(block, tip),
+                    Event::Backward(point) => ChainSyncMessage::RollBackward(point, tip),
+                };
+                Ok(Some(chain_sync_message(message)))
+            }
+            Next::Await => Ok(None),
+            Next::Behind => Err(()),
         }
     }
 }
@@ -36,6 +269,62 @@ impl Context {
 enum DuplexEvent {
     ClientRead(usize),
     InstanceRead(usize),
+    ClientWrite(Frame),
+    FollowerUpdated,
+    Expired(CloseReason),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CloseReason {
+    ClientClosed,
+    NodeClosed,
+    IdleTimeout,
+    MaxLifetime,
+    HandshakeTimeout,
+    InvalidFrame,
+    Denied,
+    FellBehind,
+    TokenExpired,
+    Error,
+}
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::ClientClosed => write!(f, "client_closed"),
+            CloseReason::NodeClosed => write!(f, "node_closed"),
+            CloseReason::IdleTimeout => write!(f, "idle_timeout"),
+            CloseReason::MaxLifetime => write!(f, "max_lifetime"),
+            CloseReason::HandshakeTimeout => write!(f, "handshake_timeout"),
+            CloseReason::InvalidFrame => write!(f, "invalid_frame"),
+            CloseReason::Denied => write!(f, "denied"),
+            CloseReason::FellBehind => write!(f, "fell_behind"),
+            CloseReason::TokenExpired => write!(f, "token_expired"),
+            CloseReason::Error => write!(f, "error"),
+        }
+    }
+}
+
+enum Action {
+    Forward,
+    /// Forwards another message in place of the one received.
+    Replace {
+        message: Vec<u8>,
+    },
+    /// Answers the sender of the message from the proxy, without forwarding it.
+    Reply {
+        reply: Vec<u8>,
+    },
+    /// Keeps the message from the node, the proxy answers it later.
+    Hold,
+    /// Answers the client from the proxy and closes the connection without reaching the node.
+    Refuse {
+        reply: Vec<u8>,
+        reason: String,
+    },
+    /// Closes the connection when there is no protocol-correct answer the proxy can give.
+    Close {
+        reason: String,
+    },
 }
 
 pub struct ProxyApp {
@@ -67,14 +356,76 @@ impl ProxyApp {
             .metrics
             .inc_total_connections(&ctx.consumer, &ctx.namespace, &ctx.instance);
 
+        let result = self
+            .forward(&mut io_client, &mut io_instance, state.clone(), &mut ctx)
+            .await;
+        let close_reason = match &result {
+            Ok(reason) => *reason,
+            Err(_) => CloseReason::Error,
+        };
+        state.metrics.count_total_connections_closed(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            &close_reason.to_string(),
+        );
+
+        if let Some(pending) = ctx.pending_tx.take() {
+            audit_tx(&ctx.consumer, &ctx.tier, pending, "no_reply", None);
+        }
+        if let Some(capture) = ctx.capture.take() {
+            if let Err(err) = capture.close().await {
+                warn!(
+                    error = err.to_string(),
+                    consumer = ctx.consumer.to_string(),
+                    "failed to write capture"
+                );
+            }
+        }
+
+        ctx.consumer.dec_connections(self.state.clone()).await;
+        state
+            .metrics
+            .dec_total_connections(&ctx.consumer, &ctx.namespace, &ctx.instance);
+
+        let active_connections = ctx.consumer.get_active_connections(state.clone()).await;
+        info!(
+            consumer = ctx.consumer.to_string(),
+            active_connections,
+            close_reason = close_reason.to_string(),
+            "client disconnected"
+        );
+
+        result.map(|_| ())
+    }
+
+    async fn forward(
+        &self,
+        io_client: &mut Stream,
+        io_instance: &mut Stream,
+        state: Arc<State>,
+        ctx: &mut Context,
+    ) -> Result<CloseReason> {
         let mut io_client_buf = [0; 1024];
         let mut io_instance_buf = [0; 1024];
 
+        let mut client_frames = FrameDecoder::new();
+        let mut instance_frames = FrameDecoder::new();
+        let mut scheduler = Scheduler::new(ctx.tier.protocol_weights.clone());
+        let mut charge: Option<Charge> = None;
+
         loop {
             let event: DuplexEvent;
+            let expiration = ctx.expiration(self.config.handshake_timeout);
+            if charge.is_none() {
+                if let Some(frame) = scheduler.pop() {
+                    let consumer = ctx.consumer.clone();
+                    charge = Some(Box::pin(charge_frame(state.clone(), consumer, frame)));
+                }
+            }
 
             select! {
-                n = io_client.read(&mut io_client_buf) => {
+                n = io_client.read(&mut io_client_buf), if scheduler.size() < MAX_SCHEDULED_BYTES => {
                     match n {
                         Ok(b) => event = DuplexEvent::ClientRead(b),
                         Err(err) => {
@@ -83,7 +434,7 @@ impl ProxyApp {
                         },
                     }
                 },
-                n = io_instance.read(&mut io_instance_buf) => {
+                n = io_instance.read(&mut io_instance_buf), if scheduler.size() < MAX_SCHEDULED_BYTES => {
                     match n {
                         Ok(b) => event = DuplexEvent::InstanceRead(b),
                         Err(err) => {
@@ -92,24 +443,87 @@ impl ProxyApp {
                         },
                     }
                 },
+                frame = charged(&mut charge), if charge.is_some() => {
+                    charge = None;
+                    event = DuplexEvent::ClientWrite(frame?);
+                },
+                _ = EdgeSession::updated(&mut ctx.edge), if EdgeSession::is_awaiting(&ctx.edge) => {
+                    event = DuplexEvent::FollowerUpdated;
+                },
+                reason = expired(expiration) => {
+                    event = DuplexEvent::Expired(reason);
+                },
+            }
+
+            if matches!(
+                event,
+                DuplexEvent::ClientRead(_)
+                    | DuplexEvent::InstanceRead(_)
+                    | DuplexEvent::FollowerUpdated
+            ) {
+                ctx.last_activity = Instant::now();
+            }
+
+            // The validity window of the port may have been extended since the connection started.
+            if let DuplexEvent::Expired(CloseReason::TokenExpired) = event {
+                if let Some(consumer) = state.get_consumer(&ctx.consumer.key).await {
+                    ctx.consumer = consumer;
+                    continue;
+                }
             }
 
             match event {
-                DuplexEvent::ClientRead(0) | DuplexEvent::InstanceRead(0) => {
-                    ctx.consumer.dec_connections(self.state.clone()).await;
-                    state.metrics.dec_total_connections(
-                        &ctx.consumer,
-                        &ctx.namespace,
-                        &ctx.instance,
-                    );
+                DuplexEvent::ClientRead(0) => {
+                    return Ok(CloseReason::ClientClosed);
+                }
+                DuplexEvent::InstanceRead(0) => {
+                    if !self.can_failover(ctx) {
+                        return Ok(CloseReason::NodeClosed);
+                    }
+                    match self.failover(ctx, &mut scheduler).await {
+                        Some((io, frames)) => {
+                            *io_instance = io;
+                            instance_frames = frames;
+                        }
+                        None => return Ok(CloseReason::NodeClosed),
+                    }
+                }
+                DuplexEvent::Expired(CloseReason::HandshakeTimeout) => {
+                    self.reject_frame(ctx, FrameError::HandshakeTimeout, None);
+                    return Ok(CloseReason::HandshakeTimeout);
+                }
+                DuplexEvent::Expired(reason) => {
+                    // Frames still queued for the client are dropped, as they'd skip the limiter.
+                    let _ = io_client.shutdown().await;
+                    let _ = io_instance.shutdown().await;
 
-                    let active_connections =
-                        ctx.consumer.get_active_connections(state.clone()).await;
                     info!(
                         consumer = ctx.consumer.to_string(),
-                        active_connections, "client disconnected"
+                        tier = ctx.tier.name,
+                        reason = reason.to_string(),
+                        "connection expired"
                     );
-                    return Ok(());
+                    return Ok(reason);
+                }
+                DuplexEvent::FollowerUpdated => {
+                    let Some(session) = ctx.edge.as_mut() else {
+                        continue;
+                    };
+                    match session.pending_replies().await {
+                        Ok(replies) => {
+                            let header = initiator_header(ctx, Protocol::ChainSync);
+                            for reply in replies {
+                                self.schedule_reply(ctx, &mut scheduler, &header, &reply);
+                            }
+                        }
+                        Err(()) => {
+                            warn!(
+                                consumer = ctx.consumer.to_string(),
+                                "chain-sync consumer fell behind the shared follower, closing connection"
+                            );
+                            return Ok(CloseReason::FellBehind);
+                        }
+                    }
                 }
                 DuplexEvent::ClientRead(bytes) => {
                     state.metrics.count_total_packages_bytes(
@@ -119,12 +533,94 @@ impl ProxyApp {
                         bytes,
                     );
 
-                    let _ = io_instance.write_all(&io_client_buf[0..bytes]).await;
+                    client_frames.extend(&io_client_buf[0..bytes]);
+                    while let Some(frame) = client_frames.next_frame() {
+                        capture(ctx, Direction::Inbound, &frame).await;
+
+                        if let Err(err) = self.check_frame(ctx, &frame) {
+                            self.reject_frame(ctx, err, Some(&frame));
+                            return Ok(CloseReason::InvalidFrame);
+                        }
+
+                        ctx.protocols.insert(frame.header.protocol);
+                        if !ctx.tier.allows(&frame.header.protocol)
+                            || !ctx.consumer.allows(&frame.header.protocol)
+                        {
+                            state.metrics.count_total_protocols_denied(
+                                &ctx.consumer,
+                                &ctx.namespace,
+                                &ctx.instance,
+                                &frame.header.protocol,
+                            );
+                            warn!(
+                                consumer = ctx.consumer.to_string(),
+                                protocol = frame.header.protocol.to_string(),
+                                tier = ctx.tier.name,
+                                read_only = ctx.consumer.read_only,
+                                "mini-protocol not allowed for the consumer, closing connection"
+                            );
+                            let _ = io_instance.flush().await;
+                            return Ok(CloseReason::Denied);
+                        }
+
+                        let messages = match self.observe_frame(ctx, &frame, Direction::Inbound) {
+                            Ok(messages) => messages,
+                            Err(err) => {
+                                warn!(error = err.to_string(), "invalid mini-protocol message");
+                                self.reject_frame(ctx, FrameError::InvalidMessage, Some(&frame));
+                                return Ok(CloseReason::InvalidFrame);
+                            }
+                        };
+
+                        for message in messages {
+                            match self
+                                .inbound_message(ctx, &frame.header.protocol, &message)
+                                .await
+                            {
+                                Action::Forward => {
+                                    write_message(io_instance, &frame.header, &message).await;
+                                }
+                                Action::Replace { message } => {
+                                    write_message(io_instance, &frame.header, &message).await;
+                                }
+                                Action::Reply { reply } => {
+                                    self.schedule_reply(ctx, &mut scheduler, &frame.header, &reply);
+                                }
+                                Action::Hold => {}
+                                Action::Refuse { reply, reason } => {
+                                    let header = reply_header(ctx, &frame.header);
+                                    write_message(io_client, &header, &reply).await;
+                                    let _ = io_client.flush().await;
+
+                                    warn!(
+                                        consumer = ctx.consumer.to_string(),
+                                        protocol = frame.header.protocol.to_string(),
+                                        reason,
+                                        "connection refused"
+                                    );
+                                    return Ok(CloseReason::Denied);
+                                }
+                                Action::Close { reason } => {
+                                    let _ = io_instance.flush().await;
+
+                                    warn!(
+                                        consumer = ctx.consumer.to_string(),
+                                        protocol = frame.header.protocol.to_string(),
+                                        reason,
+                                        "connection closed"
+                                    );
+                                    return Ok(CloseReason::Denied);
+                                }
+                            }
+                        }
+                    }
                     let _ = io_instance.flush().await;
                 }
+                DuplexEvent::ClientWrite(frame) => {
+                    let _ = io_client.write_all(&frame.encode()).await;
+                    let _ = io_client.flush().await;
+                }
                 DuplexEvent::InstanceRead(bytes) => {
-                    self.limiter(&ctx.consumer, bytes).await?;
-
                     state.metrics.count_total_packages_bytes(
                         &ctx.consumer,
                         &ctx.namespace,
@@ -132,69 +628,854 @@ impl ProxyApp {
                         bytes,
                     );
 
-                    let _ = io_client.write_all(&io_instance_buf[0..bytes]).await;
-                    let _ = io_client.flush().await;
+                    instance_frames.extend(&io_instance_buf[0..bytes]);
+                    while let Some(frame) = instance_frames.next_frame() {
+                        capture(ctx, Direction::Outbound, &frame).await;
+                        let messages = match self.observe_frame(ctx, &frame, Direction::Outbound) {
+                            Ok(messages) => messages,
+                            Err(err) => {
+                                warn!(
+                                    error = err.to_string(),
+                                    consumer = ctx.consumer.to_string(),
+                                    protocol = frame.header.protocol.to_string(),
+                                    "invalid mini-protocol message from instance"
+                                );
+                                scheduler.push(frame);
+                                continue;
+                            }
+                        };
+
+                        // Local-state-query is forwarded by message because the proxy can hold or
+                        // rewrite the replies to serve the cache. Everything else keeps the frames.
+                        if frame.header.protocol != Protocol::LocalStateQuery {
+                            for message in messages {
+                                self.outbound_message(ctx, &frame.header.protocol, &message)
+                                    .await;
+                            }
+                            scheduler.push(frame);
+                            continue;
+                        }
+
+                        for message in messages {
+                            match self
+                                .outbound_message(ctx, &frame.header.protocol, &message)
+                                .await
+                            {
+                                Action::Forward => {
+                                    schedule_message(&mut scheduler, &frame.header, &message);
+                                }
+                                Action::Replace { message } => {
+                                    schedule_message(&mut scheduler, &frame.header, &message);
+                                }
+                                Action::Reply { reply } => {
+                                    let header = reply_header(ctx, &frame.header);
+                                    write_message(io_instance, &header, &reply).await;
+                                    let _ = io_instance.flush().await;
+                                }
+                                Action::Hold => {}
+                                Action::Refuse { reason, .. } | Action::Close { reason } => {
+                                    warn!(
+                                        consumer = ctx.consumer.to_string(),
+                                        protocol = frame.header.protocol.to_string(),
+                                        reason,
+                                        "connection closed"
+                                    );
+                                    return Ok(CloseReason::Denied);
+                                }
+                            }
+                        }
+                    }
                 }
             }
         }
     }
 
-    async fn has_limiter(&self, consumer: &Consumer) -> bool {
-        let rate_limiter_map = self.state.limiter.read().await;
-        rate_limiter_map.get(&consumer.key).is_some()
+    fn check_frame(&self, ctx: &Context, frame: &Frame) -> Result<(), FrameError> {
+        frame.validate_inbound(self.config.max_sdu_payload_len)?;
+        if ctx.handshake.is_none() && frame.header.protocol != Protocol::Handshake {
+            return Err(FrameError::MissingHandshake);
+        }
+        Ok(())
     }
 
-    async fn add_limiter(&self, consumer: &Consumer, tier: &Tier) {
-        let rates = tier
-            .rates
-            .iter()
-            .map(|r| {
-                Arc::new(
-                    RateLimiter::builder()
-                        .initial(r.limit)
-                        .interval(r.interval)
-                        .refill(r.limit)
-                        .build(),
-                )
-            })
-            .collect();
+    fn reject_frame(&self, ctx: &Context, err: FrameError, frame: Option<&Frame>) {
+        self.state.metrics.count_total_frames_rejected(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            &err.to_string(),
+        );
+        warn!(
+            consumer = ctx.consumer.to_string(),
+            reason = err.to_string(),
+            protocol = frame.map(|frame| frame.header.protocol.to_string()),
+            payload_len = frame.map(|frame| frame.payload.len()),
+            "client traffic rejected, closing connection"
+        );
+    }
 
-        self.state
-            .limiter
-            .write()
-            .await
-            .insert(consumer.key.clone(), rates);
+    /// Queues a message the proxy answers the client with, counted and rate limited like the
+    /// frames of the node.
+    fn schedule_reply(
+        &self,
+        ctx: &Context,
+        scheduler: &mut Scheduler,
+        header: &Header,
+        reply: &[u8],
+    ) {
+        let header = reply_header(ctx, header);
+        let metrics = &self.state.metrics;
+        for frame in Frame::segments(header.protocol, header.mode, header.timestamp, reply) {
+            metrics.count_total_packages_bytes(
+                &ctx.consumer,
+                &ctx.namespace,
+                &ctx.instance,
+                frame.size(),
+            );
+            metrics.count_total_protocol_bytes(
+                &ctx.consumer,
+                &ctx.namespace,
+                &ctx.instance,
+                &header.protocol,
+                &Direction::Outbound,
+                frame.size(),
+            );
+            scheduler.push(frame);
+        }
+        metrics.count_total_protocol_messages(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            &header.protocol,
+            &Direction::Outbound,
+            1,
+        );
+    }
+
+    fn observe_frame(
+        &self,
+        ctx: &mut Context,
+        frame: &Frame,
+        direction: Direction,
+    ) -> Result<Vec<Vec<u8>>, decode::Error> {
+        trace!(
+            consumer = ctx.consumer.to_string(),
+            timestamp = frame.header.timestamp,
+            protocol = frame.header.protocol.to_string(),
+            mode = frame.header.mode.to_string(),
+            payload_len = frame.header.payload_len,
+            direction = direction.to_string(),
+            "frame"
+        );
+
+        self.state.metrics.count_total_protocol_bytes(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            &frame.header.protocol,
+            &direction,
+            frame.size(),
+        );
+
+        let decoder = match direction {
+            Direction::Inbound => &mut ctx.inbound_messages,
+            Direction::Outbound => &mut ctx.outbound_messages,
+        };
+        let messages = decoder.push(frame)?;
+        if !messages.is_empty() {
+            self.state.metrics.count_total_protocol_messages(
+                &ctx.consumer,
+                &ctx.namespace,
+                &ctx.instance,
+                &frame.header.protocol,
+                &direction,
+                messages.len(),
+            );
+        }
+
+        Ok(messages)
+    }
+
+    async fn inbound_message(
+        &self,
+        ctx: &mut Context,
+        protocol: &Protocol,
+        message: &[u8],
+    ) -> Action {
+        match protocol {
+            Protocol::Handshake => match self.check_handshake(ctx, message) {
+                Action::Forward => {
+                    ctx.handshake = Some(message.to_vec());
+                    Action::Forward
+                }
+                action => action,
+            },
+            Protocol::LocalStateQuery => match self.check_state_query(ctx, message).await {
+                Action::Forward => self.cached_state_query(ctx, message).await,
+                action => action,
+            },
+            Protocol::LocalTxSubmission => self.check_submission(ctx, message).await,
+            Protocol::ChainSync => match self.edge_chain_sync(ctx, message).await {
+                Action::Forward => {
+                    self.track_chain_sync_request(ctx, message);
+                    Action::Forward
+                }
+                action => action,
+            },
+            _ => Action::Forward,
+        }
+    }
+
+    async fn outbound_message(
+        &self,
+        ctx: &mut Context,
+        protocol: &Protocol,
+        message: &[u8],
+    ) -> Action {
+        match protocol {
+            Protocol::Handshake => {
+                if let Ok(Some(version)) = handshake::accepted_version(message) {
+                    ctx.version = Some(version);
+                }
+                Action::Forward
+            }
+            Protocol::LocalStateQuery => self.cache_state_query_reply(ctx, message).await,
+            Protocol::ChainSync => {
+                self.track_chain_sync_reply(ctx, message);
+                Action::Forward
+            }
+            Protocol::LocalTxSubmission => {
+                self.audit_submission_reply(ctx, message);
+                Action::Forward
+            }
+            _ => Action::Forward,
+        }
+    }
+
+    fn audit_submission_reply(&self, ctx: &mut Context, message: &[u8]) {
+        let (result, reason) = match Submission::decode(message) {
+            Ok(Submission::AcceptTx) => ("accepted", None),
+            Ok(Submission::RejectTx(reason)) => ("rejected", Some(reason)),
+            Ok(_) => return,
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    consumer = ctx.consumer.to_string(),
+                    "invalid local-tx-submission message from instance"
+                );
+                return;
+            }
+        };
+
+        if let Some(pending) = ctx.pending_tx.take() {
+            audit_tx(&ctx.consumer, &ctx.tier, pending, result, reason.as_deref());
+        }
+    }
+
+    /// Follows the client acquisitions and answers the cacheable queries from the cache.
+    async fn cached_state_query(&self, ctx: &mut Context, message: &[u8]) -> Action {
+        if self.config.state_query_cache.is_empty() {
+            return Action::Forward;
+        }
+
+        match StateQuery::decode(message) {
+            Ok(StateQuery::Acquire(point)) => {
+                ctx.acquisition = Acquisition::Acquiring(point);
+                Action::Forward
+            }
+            Ok(StateQuery::Release) | Ok(StateQuery::Done) => {
+                ctx.acquisition = Acquisition::Released;
+                Action::Forward
+            }
+            Ok(StateQuery::Query) => {
+                let (Acquisition::Acquired(point), Some(version)) = (&ctx.acquisition, ctx.version)
+                else {
+                    return Action::Forward;
+                };
+                let Ok(Some(query)) = Query::decode(message) else {
+                    return Action::Forward;
+                };
+                if !self
+                    .config
+                    .state_query_cache
+                    .iter()
+                    .any(|q| q == query.name)
+                {
+                    return Action::Forward;
+                }
+
+                let cached = self.state.query_cache.read().await.get(
+                    &ctx.consumer.network,
+                    &ctx.consumer.version,
+                    version,
+                    point,
+                    message,
+                );
+                let result = match cached {
+                    Some(_) => "hit",
+                    None => "miss",
+                };
+                self.state.metrics.count_total_state_query_cache(
+                    &ctx.consumer,
+                    &ctx.namespace,
+                    &ctx.instance,
+                    &query,
+                    result,
+                );
+
+                match cached {
+                    Some(cached) => Action::Reply {
+                        reply: query::result(&cached),
+                    },
+                    None => {
+                        ctx.cacheable_query = Some(message.to_vec());
+                        Action::Forward
+                    }
+                }
+            }
+            _ => Action::Forward,
+        }
+    }
+
+    async fn cache_state_query_reply(&self, ctx: &mut Context, message: &[u8]) -> Action {
+        if self.config.state_query_cache.is_empty() {
+            return Action::Forward;
+        }
+
+        let reply = match StateQuery::decode(message) {
+            Ok(reply) => reply,
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    consumer = ctx.consumer.to_string(),
+                    "invalid local-state-query message from instance"
+                );
+                return Action::Forward;
+            }
+        };
+
+        let network = &ctx.consumer.network;
+        match (
+            reply,
+            std::mem::replace(&mut ctx.acquisition, Acquisition::Released),
+        ) {
+            (StateQuery::Acquired, Acquisition::Acquiring(Some(point))) => {
+                self.observe_point(ctx, &point).await;
+                ctx.acquisition = Acquisition::Acquired(point);
+                Action::Forward
+            }
+            // The client doesn't know which point the tip is, so `MsgAcquired` is held until the
+            // node answers the proxy's own `GetChainPoint`.
+            (StateQuery::Acquired, Acquisition::Acquiring(None)) => {
+                ctx.acquisition = Acquisition::ResolvingTip;
+                Action::Reply {
+                    reply: query::get_chain_point(),
+                }
+            }
+            (StateQuery::Result(point), Acquisition::ResolvingTip) => {
+                self.observe_point(ctx, &point).await;
+                ctx.acquisition = Acquisition::Acquired(point);
+                Action::Replace {
+                    message: query::acquired(),
+                }
+            }
+            (StateQuery::Result(result), Acquisition::Acquired(point)) => {
+                if let (Some(query), Some(version)) = (ctx.cacheable_query.take(), ctx.version) {
+                    self.state.query_cache.write().await.insert(
+                        network,
+                        &ctx.consumer.version,
+                        version,
+                        &point,
+                        &query,
+                        &result,
+                    );
+                }
+                ctx.acquisition = Acquisition::Acquired(point);
+                Action::Forward
+            }
+            (StateQuery::Failure, _) => Action::Forward,
+            (_, acquisition) => {
+                ctx.acquisition = acquisition;
+                Action::Forward
+            }
+        }
     }
 
-    async fn limiter(&self, consumer: &Consumer, amount_of_bytes: usize) -> Result<()> {
-        if !self.has_limiter(consumer).await {
-            let tiers = self.state.tiers.read().await.clone();
-            let tier = tiers.get(&consumer.tier);
-            if tier.is_none() {
-                return Err(Error::new(pingora::ErrorType::AcceptError));
+    /// Serves the chain-sync sessions that intersect inside the window of the shared follower.
+    /// Intersections happen with the node idle, so the session can move between the proxy and
+    /// the node on each `MsgFindIntersect`.
+    async fn edge_chain_sync(&self, ctx: &mut Context, message: &[u8]) -> Action {
+        if self.config.chain_sync_window == 0 {
+            return Action::Forward;
+        }
+
+        let Ok(message) = minicbor::decode::<ChainSyncMessage<BlockContent>>(message) else {
+            return Action::Forward;
+        };
+
+        match message {
+            ChainSyncMessage::FindIntersect(points) => {
+                let Some(follower) = self.follower(ctx).await else {
+                    return Action::Forward;
+                };
+
+                let Some((point, next, tip)) = follower.intersect(&points).await else {
+                    ctx.edge = None;
+                    self.state.metrics.count_total_chain_sync_intersections(
+                        &ctx.consumer,
+                        &ctx.namespace,
+                        &ctx.instance,
+                        "upstream",
+                    );
+                    return Action::Forward;
+                };
+
+                self.state.metrics.count_total_chain_sync_intersections(
+                    &ctx.consumer,
+                    &ctx.namespace,
+                    &ctx.instance,
+                    "edge",
+                );
+                ctx.edge = Some(EdgeSession {
+                    updates: follower.subscribe(),
+                    follower,
+                    next,
+                    intersection: Some(point.clone()),
+                    awaiting: false,
+                    pipelined: 0,
+                });
+                Action::Reply {
+                    reply: chain_sync_message(ChainSyncMessage::IntersectFound(point, tip)),
+                }
             }
-            let tier = tier.unwrap();
+            ChainSyncMessage::RequestNext => {
+                let Some(session) = ctx.edge.as_mut() else {
+                    return Action::Forward;
+                };
+                // Pipelined requests are answered in order once the awaited one is.
+                if session.awaiting {
+                    session.pipelined += 1;
+                    return Action::Hold;
+                }
 
-            let refreshed_consumer = match self.state.get_consumer(&consumer.key).await {
-                Some(consumer) => consumer,
-                // Port was deleted
-                None => return Err(Error::new(pingora::ErrorType::ConnectRefused)),
-            };
+                match session.next_reply().await {
+                    Ok(Some(reply)) => Action::Reply { reply },
+                    Ok(None) => {
+                        session.awaiting = true;
+                        Action::Reply {
+                            reply: chain_sync_message(ChainSyncMessage::AwaitReply),
+                        }
+                    }
+                    Err(()) => Action::Close {
+                        reason: "chain-sync consumer fell behind the shared follower".into(),
+                    },
+                }
+            }
+            ChainSyncMessage::Done => {
+                ctx.edge = None;
+                Action::Forward
+            }
+            _ => Action::Forward,
+        }
+    }
+
+    fn track_chain_sync_request(&self, ctx: &mut Context, message: &[u8]) {
+        if !self.config.chain_sync_failover {
+            return;
+        }
 
-            self.add_limiter(&refreshed_consumer, tier).await;
+        match minicbor::decode::<ChainSyncMessage<BlockContent>>(message) {
+            Ok(ChainSyncMessage::RequestNext) | Ok(ChainSyncMessage::FindIntersect(_)) => {
+                ctx.chain_sync.requests.push_back(message.to_vec());
+            }
+            _ => {}
         }
+    }
 
-        let rate_limiter_map = self.state.limiter.read().await.clone();
-        let rates = rate_limiter_map.get(&consumer.key).unwrap();
+    fn track_chain_sync_reply(&self, ctx: &mut Context, message: &[u8]) {
+        if !self.config.chain_sync_failover {
+            return;
+        }
 
-        join_all(
-            rates
+        let chain_sync = &mut ctx.chain_sync;
+        match minicbor::decode::<ChainSyncMessage<BlockContent>>(message) {
+            Ok(ChainSyncMessage::AwaitReply) => chain_sync.awaiting = true,
+            Ok(ChainSyncMessage::RollForward(block, _)) => {
+                chain_sync.point = follower::block_point(&block).ok();
+                chain_sync.answered();
+            }
+            Ok(ChainSyncMessage::RollBackward(point, _))
+            | Ok(ChainSyncMessage::IntersectFound(point, _)) => {
+                chain_sync.point = Some(point);
+                chain_sync.answered();
+            }
+            Ok(ChainSyncMessage::IntersectNotFound(_)) => chain_sync.answered(),
+            _ => {}
+        }
+    }
+
+    /// The session can only move to another node when all the client state the proxy can't
+    /// rebuild is the chain-sync position, and no chain-sync message was cut in the middle.
+    fn can_failover(&self, ctx: &Context) -> bool {
+        self.config.chain_sync_failover
+            && ctx.handshake.is_some()
+            && ctx
+                .protocols
                 .iter()
-                .map(|r| async { r.acquire(amount_of_bytes).await }),
+                .all(|p| matches!(p, Protocol::Handshake | Protocol::ChainSync))
+            && ctx.outbound_messages.is_empty(&Protocol::ChainSync)
+            && (ctx.edge.is_some() || ctx.chain_sync.point.is_some())
+    }
+
+    async fn failover(
+        &self,
+        ctx: &mut Context,
+        scheduler: &mut Scheduler,
+    ) -> Option<(Stream, FrameDecoder)> {
+        let addresses: Vec<SocketAddr> = match lookup_host(&ctx.instance).await {
+            Ok(addresses) => addresses.filter(|a| *a != ctx.upstream).collect(),
+            Err(err) => {
+                error!(error = err.to_string(), "fail to lookup ip");
+                Vec::new()
+            }
+        };
+
+        for address in addresses {
+            match self.resume(ctx, scheduler, address).await {
+                Ok((io, frames, messages)) => {
+                    info!(
+                        consumer = ctx.consumer.to_string(),
+                        from = ctx.upstream.to_string(),
+                        to = address.to_string(),
+                        "chain-sync session moved to another node"
+                    );
+                    self.state.metrics.count_total_failovers(
+                        &ctx.consumer,
+                        &ctx.namespace,
+                        &ctx.instance,
+                        "recovered",
+                    );
+                    ctx.upstream = address;
+                    ctx.outbound_messages = messages;
+                    return Some((io, frames));
+                }
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        consumer = ctx.consumer.to_string(),
+                        address = address.to_string(),
+                        "fail to resume chain-sync session"
+                    );
+                }
+            }
+        }
+
+        self.state.metrics.count_total_failovers(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            "failed",
+        );
+        None
+    }
+
+    /// Replays the handshake on the node and brings its chain-sync to where the client is.
+    async fn resume(
+        &self,
+        ctx: &mut Context,
+        scheduler: &mut Scheduler,
+        address: SocketAddr,
+    ) -> Result<(Stream, FrameDecoder, MessageDecoder), Box<dyn StdError + Send + Sync>> {
+        let peer = BasicPeer::new(&address.to_string());
+        let mut io = self.client_connector.new_stream(&peer).await?;
+        let mut frames = FrameDecoder::new();
+        let mut messages = MessageDecoder::new();
+
+        let handshake = ctx.handshake.clone().unwrap_or_default();
+        let header = initiator_header(ctx, Protocol::Handshake);
+        write_message(&mut io, &header, &handshake).await;
+        io.flush().await?;
+
+        let reply = read_message(&mut io, &mut frames, &mut messages, Protocol::Handshake).await?;
+        let version = handshake::accepted_version(&reply)?;
+        if version.is_none() || version != ctx.version {
+            return Err("handshake version not accepted".into());
+        }
+
+        // Sessions served by the shared follower don't use the node chain-sync.
+        if ctx.edge.is_some() {
+            return Ok((io, frames, messages));
+        }
+
+        let point = ctx
+            .chain_sync
+            .point
+            .clone()
+            .ok_or("unknown chain-sync point")?;
+        let header = initiator_header(ctx, Protocol::ChainSync);
+        let intersect = chain_sync_message(ChainSyncMessage::FindIntersect(vec![point]));
+        write_message(&mut io, &header, &intersect).await;
+        io.flush().await?;
+
+        let reply = read_message(&mut io, &mut frames, &mut messages, Protocol::ChainSync).await?;
+        let Ok(ChainSyncMessage::IntersectFound(..)) =
+            minicbor::decode::<ChainSyncMessage<BlockContent>>(&reply)
+        else {
+            return Err("chain-sync point not found".into());
+        };
+
+        if ctx.chain_sync.requests.is_empty() {
+            return Ok((io, frames, messages));
+        }
+        for request in &ctx.chain_sync.requests {
+            write_message(&mut io, &header, request).await;
+        }
+        io.flush().await?;
+
+        // The client already got `MsgAwaitReply` for the first request, so it can only receive
+        // the reply itself. The replies to the others come as usual.
+        if ctx.chain_sync.awaiting {
+            let reply =
+                read_message(&mut io, &mut frames, &mut messages, Protocol::ChainSync).await?;
+            if !matches!(
+                minicbor::decode::<ChainSyncMessage<BlockContent>>(&reply),
+                Ok(ChainSyncMessage::AwaitReply)
+            ) {
+                let header = reply_header(ctx, &header);
+                schedule_message(scheduler, &header, &reply);
+                self.track_chain_sync_reply(ctx, &reply);
+            }
+        }
+
+        Ok((io, frames, messages))
+    }
+
+    async fn follower(&self, ctx: &Context) -> Option<Arc<Follower>> {
+        let magic = *self.config.network_magics.get(&ctx.consumer.network)?;
+
+        if let Some(follower) = self.state.followers.read().await.get(&ctx.instance) {
+            if !follower.is_stopped() {
+                return Some(follower.clone());
+            }
+        }
+
+        let mut followers = self.state.followers.write().await;
+        followers.retain(|_, follower| !follower.is_stopped());
+        let follower = followers
+            .entry(ctx.instance.clone())
+            .or_insert_with(|| {
+                Follower::spawn(ctx.instance.clone(), magic, self.config.chain_sync_window)
+            })
+            .clone();
+        Some(follower)
+    }
+
+    async fn observe_point(&self, ctx: &Context, point: &[u8]) {
+        let result = self.state.query_cache.write().await.observe_point(
+            &ctx.consumer.network,
+            &ctx.consumer.version,
+            point,
+        );
+        if let Err(err) = result {
+            warn!(
+                error = err.to_string(),
+                consumer = ctx.consumer.to_string(),
+                "invalid point acquired on local-state-query"
+            );
+        }
+    }
+
+    async fn check_submission(&self, ctx: &mut Context, message: &[u8]) -> Action {
+        let tx = match Submission::decode(message) {
+            Ok(Submission::SubmitTx(tx)) => tx,
+            Ok(_) => return Action::Forward,
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    consumer = ctx.consumer.to_string(),
+                    "invalid local-tx-submission message"
+                );
+                return Action::Forward;
+            }
+        };
+
+        let pending = PendingTx {
+            tx,
+            submitted_at: SystemTime::now(),
+        };
+
+        let reason = if ctx.tier.exceeds_tx_size(&pending.tx) {
+            "size"
+        } else if !budget(
+            &self.state,
+            &self.state.tx_limiter,
+            &ctx.consumer,
+            &ctx.tier.tx_rates,
+            1,
         )
-        .await;
+        .await
+        {
+            "rate"
+        } else {
+            ctx.pending_tx = Some(pending);
+            return Action::Forward;
+        };
 
-        Ok(())
+        self.state.metrics.count_total_txs_denied(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            reason,
+        );
+        audit_tx(&ctx.consumer, &ctx.tier, pending, "denied", None);
+
+        // MsgRejectTx carries a ledger error the proxy can't build, so the connection is closed.
+        Action::Close {
+            reason: format!("tx exceeds the {reason} limit for tier {}", ctx.tier.name),
+        }
+    }
+
+    async fn check_state_query(&self, ctx: &Context, message: &[u8]) -> Action {
+        let query = match Query::decode(message) {
+            Ok(Some(query)) => query,
+            Ok(None) => return Action::Forward,
+            // A query the proxy can't classify would skip the query policy of the tier.
+            Err(err) if ctx.tier.has_query_policy() => {
+                return Action::Close {
+                    reason: format!("invalid local-state-query message: {err}"),
+                }
+            }
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    consumer = ctx.consumer.to_string(),
+                    "invalid local-state-query message"
+                );
+                return Action::Forward;
+            }
+        };
+
+        self.state.metrics.count_total_state_queries(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            &query,
+        );
+
+        let reason = if ctx.tier.denies_query(&query) {
+            "denied"
+        } else if !budget(
+            &self.state,
+            &self.state.query_limiter,
+            &ctx.consumer,
+            &ctx.tier.query_rates,
+            ctx.tier.query_cost(&query),
+        )
+        .await
+        {
+            "budget"
+        } else {
+            return Action::Forward;
+        };
+
+        self.state.metrics.count_total_state_queries_denied(
+            &ctx.consumer,
+            &ctx.namespace,
+            &ctx.instance,
+            &query,
+            reason,
+        );
+
+        // There is no local-state-query message to refuse a query, so the connection is closed.
+        Action::Close {
+            reason: format!(
+                "query {} is {reason} for tier {}",
+                query.name, ctx.tier.name
+            ),
+        }
+    }
+
+    fn check_handshake(&self, ctx: &Context, message: &[u8]) -> Action {
+        let Some(expected) = self.config.network_magics.get(&ctx.consumer.network) else {
+            return Action::Forward;
+        };
+
+        let proposal = match Proposal::decode(message) {
+            Ok(Some(proposal)) => proposal,
+            Ok(None) => return Action::Forward,
+            Err(err) => {
+                // The node is still the one who answers a handshake it can't decode.
+                warn!(
+                    error = err.to_string(),
+                    consumer = ctx.consumer.to_string(),
+                    "invalid handshake message"
+                );
+                return Action::Forward;
+            }
+        };
+
+        match proposal.mismatched_magic(*expected) {
+            Some(magic) => {
+                let reason = format!(
+                    "network magic {magic} doesn't match the port network {} ({expected})",
+                    ctx.consumer.network
+                );
+                Action::Refuse {
+                    reply: handshake::refuse(proposal.highest_version(), &reason),
+                    reason,
+                }
+            }
+            None => Action::Forward,
+        }
+    }
+
+    /// Takes one of the connections left to capture for the consumer, if any.
+    /// Only ports of the `CAPTURE_NAMESPACES` are captured, the annotation is set by the owner of
+    /// the port.
+    async fn start_capture(&self, consumer: &Consumer) -> Option<CaptureWriter> {
+        let path = self.config.capture_path.as_ref()?;
+        if !self.config.capture_namespaces.contains(&consumer.namespace) {
+            return None;
+        }
+
+        let mut captures = self.state.captures.write().await;
+        let remaining = captures.get_mut(&consumer.key)?;
+        *remaining -= 1;
+        let remaining = *remaining;
+        if remaining == 0 {
+            captures.remove(&consumer.key);
+        }
+        drop(captures);
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let file = path.join(format!(
+            "{}.{}-{started}.cap",
+            consumer.namespace, consumer.port_name
+        ));
+
+        match CaptureWriter::create(&file, self.config.capture_max_bytes).await {
+            Ok(capture) => {
+                info!(
+                    consumer = consumer.to_string(),
+                    file = file.display().to_string(),
+                    remaining,
+                    "capturing connection"
+                );
+                Some(capture)
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    consumer = consumer.to_string(),
+                    file = file.display().to_string(),
+                    "failed to create capture"
+                );
+                None
+            }
+        }
     }
 
     async fn get_tier(&self, tier: &str) -> Result<Tier> {
@@ -217,6 +1498,117 @@ impl ProxyApp {
     }
 }
 
+/// Frame taken from the scheduler, waiting for the byte limiter of the consumer before it's
+/// written to the client.
+type Charge = Pin<Box<dyn Future<Output = Result<Frame>> + Send>>;
+
+/// Charges the frame once from the byte limiter of the consumer. The charge is kept by the
+/// connection until it's done, so other events don't cancel it and charge the frame again.
+async fn charge_frame(state: Arc<State>, consumer: Consumer, frame: Frame) -> Result<Frame> {
+    let mut key = state.limiter_key(&consumer).await;
+    if !state.limiter.read().await.contains_key(&key) {
+        let tiers = state.tiers.read().await.clone();
+        let Some(tier) = tiers.get(&consumer.tier) else {
+            return Err(Error::new(pingora::ErrorType::AcceptError));
+        };
+
+        let refreshed_consumer = match state.get_consumer(&consumer.key).await {
+            Some(consumer) => consumer,
+            // Port was deleted or its token expired
+            None => return Err(Error::new(pingora::ErrorType::ConnectRefused)),
+        };
+
+        state
+            .limiter
+            .write()
+            .await
+            .entry(refreshed_consumer.key.clone())
+            .or_insert_with(|| rate_limiters(&tier.rates));
+        key = refreshed_consumer.key;
+    }
+
+    let rates = state
+        .limiter
+        .read()
+        .await
+        .get(&key)
+        .cloned()
+        .unwrap_or_default();
+    join_all(rates.iter().map(|r| r.acquire(frame.size()))).await;
+
+    Ok(frame)
+}
+
+async fn charged(charge: &mut Option<Charge>) -> Result<Frame> {
+    match charge {
+        Some(charge) => charge.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Charges `cost` from the consumer budget on `limiters` without waiting for a refill.
+pub async fn budget(
+    state: &State,
+    limiters: &RwLock<HashMap<Vec<u8>, Vec<Arc<RateLimiter>>>>,
+    consumer: &Consumer,
+    rates: &[TierRate],
+    cost: usize,
+) -> bool {
+    consumer_limiters(state, limiters, consumer, rates)
+        .await
+        .iter()
+        .all(|r| r.try_acquire(cost))
+}
+
+/// Waits until `cost` can be charged from the consumer budget on `limiters`.
+pub async fn throttle(
+    state: &State,
+    limiters: &RwLock<HashMap<Vec<u8>, Vec<Arc<RateLimiter>>>>,
+    consumer: &Consumer,
+    rates: &[TierRate],
+    cost: usize,
+) {
+    let rates = consumer_limiters(state, limiters, consumer, rates).await;
+    join_all(rates.iter().map(|r| r.acquire(cost))).await;
+}
+
+async fn consumer_limiters(
+    state: &State,
+    limiters: &RwLock<HashMap<Vec<u8>, Vec<Arc<RateLimiter>>>>,
+    consumer: &Consumer,
+    rates: &[TierRate],
+) -> Vec<Arc<RateLimiter>> {
+    if rates.is_empty() {
+        return Vec::new();
+    }
+
+    let key = state.limiter_key(consumer).await;
+    if !limiters.read().await.contains_key(&key) {
+        limiters
+            .write()
+            .await
+            .entry(key.clone())
+            .or_insert_with(|| rate_limiters(rates));
+    }
+
+    limiters.read().await.get(&key).cloned().unwrap_or_default()
+}
+
+fn rate_limiters(rates: &[TierRate]) -> Vec<Arc<RateLimiter>> {
+    rates
+        .iter()
+        .map(|r| {
+            Arc::new(
+                RateLimiter::builder()
+                    .initial(r.limit)
+                    .interval(r.interval)
+                    .refill(r.limit)
+                    .build(),
+            )
+        })
+        .collect()
+}
+
 #[async_trait]
 impl ServerApp for ProxyApp {
     async fn process_new(
@@ -228,29 +1620,28 @@ impl ServerApp for ProxyApp {
         let hostname = io_client
             .get_ssl()
             .and_then(|tls| tls.servername_raw(NameType::HOST_NAME))
-            .and_then(|b| std::str::from_utf8(b).ok());
-        if hostname.is_none() {
-            error!("hostname is not present in the certificate");
-            return None;
-        }
-        let hostname = hostname.unwrap();
-
-        let captures_result = self.host_regex.captures(hostname);
-        if captures_result.is_none() {
-            error!("invalid hostname pattern");
-            return None;
-        }
-        let captures = captures_result?;
+            .and_then(|b| std::str::from_utf8(b).ok())
+            .map(String::from)
+            .unwrap_or_default();
+        let certificate = io_client.get_ssl().and_then(ClientCertificate::from_ssl);
 
-        let token = captures.get(1)?.as_str().to_string();
-
-        let result = bech32::decode(&token);
-        if let Err(error) = result {
-            error!(?error, "invalid bech32");
-            return None;
-        }
+        self.serve(io_client, &hostname, certificate).await
+    }
+}
 
-        let consumer = self.state.get_consumer(&result.unwrap().1).await?;
+impl ProxyApp {
+    /// Serves a client connection once the host with the token and the client certificate, if
+    /// any, are known.
+    pub async fn serve(
+        &self,
+        io_client: Stream,
+        hostname: &str,
+        certificate: Option<ClientCertificate>,
+    ) -> Option<Stream> {
+        let consumer = match self.authenticate(hostname, certificate.as_ref()).await {
+            Ok(consumer) => consumer,
+            Err(reason) => return refuse(io_client, reason).await,
+        };
         let instance = format!(
             "node-{}-{}.{}:{}",
             consumer.network, consumer.version, self.config.node_dns, self.config.node_port
@@ -269,7 +1660,8 @@ impl ServerApp for ProxyApp {
                     consumer = consumer.to_string(),
                     "Error to get the tier"
                 );
-                return None;
+                let reason = format!("tier {} not found", consumer.tier);
+                return refuse(io_client, &reason).await;
             }
 
             let tier = tier_result.unwrap();
@@ -280,10 +1672,25 @@ impl ServerApp for ProxyApp {
                 max_connections = tier.max_connections
             );
 
-            return None;
+            let reason = format!(
+                "connection limit {} reached for tier {}",
+                tier.max_connections, tier.name
+            );
+            return refuse(io_client, &reason).await;
         }
 
-        let context = Context::new(&consumer, &instance, &namespace);
+        let tier = match self.get_tier(&consumer.tier).await {
+            Ok(tier) => tier,
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    consumer = consumer.to_string(),
+                    "Error to get the tier"
+                );
+                let reason = format!("tier {} not found", consumer.tier);
+                return refuse(io_client, &reason).await;
+            }
+        };
 
         let lookup_result = lookup_host(&instance).await;
         if let Err(err) = lookup_result {
@@ -295,6 +1702,16 @@ impl ServerApp for ProxyApp {
         let node_addr = lookup.choose(&mut rng)?;
 
         let proxy_to = BasicPeer::new(&node_addr.to_string());
+        let capture = self.start_capture(&consumer).await;
+        let context = Context::new(
+            &consumer,
+            &tier,
+            &instance,
+            &namespace,
+            *node_addr,
+            capture,
+            self.config.max_message_len,
+        );
 
         let io_instance = self.client_connector.new_stream(&proxy_to).await;
 
@@ -314,4 +1731,280 @@ impl ServerApp for ProxyApp {
             }
         }
     }
+
+    /// Finds the consumer by the client certificate fingerprint, or else by the token in the
+    /// host. Ports with a client certificate also need a matching certificate with the token.
+    async fn authenticate(
+        &self,
+        hostname: &str,
+        certificate: Option<&ClientCertificate>,
+    ) -> std::result::Result<Consumer, &'static str> {
+        if let Some(certificate) = certificate {
+            let fingerprint = certificate.fingerprint();
+            if let Some(consumer) = self.state.get_consumer_by_fingerprint(&fingerprint).await {
+                return Ok(consumer);
+            }
+        }
+
+        let Some(captures) = self.host_regex.captures(hostname) else {
+            error!("invalid hostname pattern");
+            return Err("unknown token");
+        };
+        let token = captures.get(1).ok_or("unknown token")?.as_str();
+
+        let key = match bech32::decode(token) {
+            Ok((_hrp, key)) => key,
+            Err(error) => {
+                error!(?error, "invalid bech32");
+                return Err("unknown token");
+            }
+        };
+
+        let Some(consumer) = self.state.get_consumer(&key).await else {
+            error!(token, "consumer not found");
+            return Err("unknown token");
+        };
+        if !consumer.accepts(certificate) {
+            error!(
+                consumer = consumer.to_string(),
+                "client certificate missing or not accepted"
+            );
+            return Err("client certificate required");
+        }
+
+        Ok(consumer)
+    }
+}
+
+// Audit records go to their own target so they can be filtered and shipped apart from the rest of
+// the logs.
+pub fn audit_tx(
+    consumer: &Consumer,
+    tier: &Tier,
+    pending: PendingTx,
+    result: &str,
+    reject_reason: Option<&[u8]>,
+) {
+    let submitted_at = pending
+        .submitted_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let latency_ms = pending
+        .submitted_at
+        .elapsed()
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    info!(
+        target: "tx_audit",
+        consumer = consumer.to_string(),
+        tier = tier.name,
+        tx_hash = pending.tx.hash.unwrap_or_default(),
+        tx_size = pending.tx.size,
+        era = pending.tx.era,
+        submitted_at,
+        result,
+        reject_reason = reject_reason.map(hex::encode).unwrap_or_default(),
+        latency_ms,
+        "tx submission"
+    );
+}
+
+// A failed write stops the capture but the connection goes on.
+async fn capture(ctx: &mut Context, direction: Direction, frame: &Frame) {
+    let Some(capture) = ctx.capture.as_mut() else {
+        return;
+    };
+    match capture.write(direction, frame).await {
+        Ok(true) => {}
+        Ok(false) => {
+            info!(
+                consumer = ctx.consumer.to_string(),
+                "capture reached its size limit, stopping it"
+            );
+            if let Some(capture) = ctx.capture.take() {
+                if let Err(err) = capture.close().await {
+                    warn!(
+                        error = err.to_string(),
+                        consumer = ctx.consumer.to_string(),
+                        "failed to write capture"
+                    );
+                }
+            }
+        }
+        Err(err) => {
+            warn!(
+                error = err.to_string(),
+                consumer = ctx.consumer.to_string(),
+                "failed to write capture, stopping it"
+            );
+            ctx.capture = None;
+        }
+    }
+}
+
+fn schedule_message(scheduler: &mut Scheduler, header: &Header, message: &[u8]) {
+    for segment in Frame::segments(header.protocol, header.mode, header.timestamp, message) {
+        scheduler.push(segment);
+    }
+}
+
+pub async fn expired(expiration: Option<(Instant, CloseReason)>) -> CloseReason {
+    match expiration {
+        Some((at, reason)) => {
+            tokio::time::sleep_until(at.into()).await;
+            reason
+        }
+        None => std::future::pending().await,
+    }
+}
+
+pub async fn write_message(io: &mut Stream, header: &Header, message: &[u8]) {
+    for segment in Frame::segments(header.protocol, header.mode, header.timestamp, message) {
+        let _ = io.write_all(&segment.encode()).await;
+    }
+}
+
+/// Header for a message the proxy sends back to the sender of `header`.
+fn reply_header(ctx: &Context, header: &Header) -> Header {
+    let mode = match header.mode {
+        Mode::Initiator => Mode::Responder,
+        Mode::Responder => Mode::Initiator,
+    };
+    Header {
+        timestamp: ctx.timestamp(),
+        mode,
+        protocol: header.protocol,
+        payload_len: 0,
+    }
+}
+
+fn chain_sync_message(message: ChainSyncMessage<BlockContent>) -> Vec<u8> {
+    minicbor::to_vec(message).unwrap()
+}
+
+fn initiator_header(ctx: &Context, protocol: Protocol) -> Header {
+    Header {
+        timestamp: ctx.timestamp(),
+        mode: Mode::Initiator,
+        protocol,
+        payload_len: 0,
+    }
+}
+
+/// Reads from the stream until a whole message of the protocol is available.
+pub async fn read_message(
+    io: &mut Stream,
+    frames: &mut FrameDecoder,
+    messages: &mut MessageDecoder,
+    protocol: Protocol,
+) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
+    let mut buf = [0; 1024];
+    loop {
+        while let Some(frame) = frames.next_frame() {
+            if frame.header.protocol != protocol {
+                continue;
+            }
+            if let Some(message) = messages.push(&frame)?.into_iter().next() {
+                return Ok(message);
+            }
+        }
+
+        let bytes = timeout(READ_TIMEOUT, io.read(&mut buf)).await??;
+        if bytes == 0 {
+            return Err("connection closed".into());
+        }
+        frames.extend(&buf[0..bytes]);
+    }
+}
+
+/// Answers the client handshake with a refuse explaining why the connection is rejected, so the
+/// client doesn't only see the connection closing.
+async fn refuse(mut io_client: Stream, reason: &str) -> Option<Stream> {
+    let mut frames = FrameDecoder::new();
+    let mut messages = MessageDecoder::new();
+    let message = read_message(
+        &mut io_client,
+        &mut frames,
+        &mut messages,
+        Protocol::Handshake,
+    )
+    .await
+    .ok()?;
+    let proposal = Proposal::decode(&message).ok()??;
+
+    let header = Header {
+        timestamp: 0,
+        mode: Mode::Responder,
+        protocol: Protocol::Handshake,
+        payload_len: 0,
+    };
+    let reply = handshake::refuse(proposal.highest_version(), reason);
+    write_message(&mut io_client, &header, &reply).await;
+    let _ = io_client.flush().await;
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mux::{Header, Mode};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_charged_once() {
+        let state = State::shared();
+        let tier: Tier = toml::from_str(
+            r#"
+name = "charge"
+max_connections = 1
+rates = [{ limit = 1000, interval = "1h" }]
+"#,
+        )
+        .unwrap();
+        state
+            .tiers
+            .write()
+            .await
+            .insert(tier.name.clone(), tier.clone());
+        let consumer = Consumer {
+            namespace: "prj-test".into(),
+            port_name: "charge".into(),
+            key: b"charge".to_vec(),
+            tier: tier.name.clone(),
+            ..Default::default()
+        };
+        state
+            .consumers
+            .write()
+            .await
+            .insert(consumer.key.clone(), consumer.clone());
+
+        let frame = Frame {
+            header: Header {
+                timestamp: 0,
+                mode: Mode::Responder,
+                protocol: Protocol::ChainSync,
+                payload_len: 100,
+            },
+            payload: vec![0; 100],
+        };
+        let mut charge: Option<Charge> = Some(Box::pin(charge_frame(
+            state.clone(),
+            consumer.clone(),
+            frame.clone(),
+        )));
+        assert_eq!(charged(&mut charge).await.unwrap(), frame);
+
+        let rates = state
+            .limiter
+            .read()
+            .await
+            .get(&consumer.key)
+            .cloned()
+            .unwrap();
+        assert_eq!(rates[0].balance(), 1000 - frame.size());
+    }
 }
@@ -0,0 +1,338 @@
+use pallas_codec::minicbor::{decode, Decoder, Encoder};
+
+/// Local-state-query messages the proxy follows to know the point acquired by the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateQuery {
+    /// `MsgAcquire` or `MsgReAcquire`, with the point or `None` for the tip.
+    Acquire(Option<Vec<u8>>),
+    Acquired,
+    Failure,
+    Query,
+    Result(Vec<u8>),
+    Release,
+    Done,
+}
+impl StateQuery {
+    pub fn decode(message: &[u8]) -> Result<Self, decode::Error> {
+        let mut d = Decoder::new(message);
+        d.array()?;
+
+        match d.u16()? {
+            0 | 6 => Ok(StateQuery::Acquire(Some(raw_item(&mut d, message)?))),
+            8..=11 => Ok(StateQuery::Acquire(None)),
+            1 => Ok(StateQuery::Acquired),
+            2 => Ok(StateQuery::Failure),
+            3 => Ok(StateQuery::Query),
+            4 => Ok(StateQuery::Result(raw_item(&mut d, message)?)),
+            5 => Ok(StateQuery::Release),
+            7 => Ok(StateQuery::Done),
+            _ => Err(decode::Error::message(
+                "unknown variant for local-state-query message",
+            )),
+        }
+    }
+}
+
+pub fn acquired() -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(1).unwrap().u16(1).unwrap();
+    e.into_writer()
+}
+
+/// `MsgAcquire` of the volatile tip.
+pub fn acquire_tip() -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(1).unwrap().u16(8).unwrap();
+    e.into_writer()
+}
+
+pub fn release() -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(1).unwrap().u16(5).unwrap();
+    e.into_writer()
+}
+
+pub fn get_current_era() -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(2).unwrap().u16(3).unwrap();
+    e.array(2).unwrap().u16(0).unwrap();
+    e.array(2).unwrap().u16(2).unwrap();
+    e.array(1).unwrap().u16(1).unwrap();
+    e.into_writer()
+}
+
+/// `MsgQuery` running `query`, an encoded block query like `[3]`, if `era` is the current one.
+pub fn era_query(era: u16, query: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(2).unwrap().u16(3).unwrap();
+    e.array(2).unwrap().u16(0).unwrap();
+    e.array(2).unwrap().u16(0).unwrap();
+    e.array(2).unwrap().u16(era).unwrap();
+    let mut message = e.into_writer();
+    message.extend_from_slice(query);
+    message
+}
+
+pub fn get_chain_point() -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(2).unwrap().u16(3).unwrap();
+    e.array(1).unwrap().u16(3).unwrap();
+    e.into_writer()
+}
+
+pub fn result(result: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(2).unwrap().u16(4).unwrap();
+    let mut message = e.into_writer();
+    message.extend_from_slice(result);
+    message
+}
+
+fn raw_item(d: &mut Decoder, message: &[u8]) -> Result<Vec<u8>, decode::Error> {
+    let start = d.position();
+    d.skip()?;
+    Ok(message[start..d.position()].to_vec())
+}
+
+/// Local-state-query `MsgQuery` classified by the query kind and the era it targets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub name: &'static str,
+    pub era: Option<u16>,
+}
+impl Query {
+    /// Returns `None` when the message is a valid local-state-query message other than `MsgQuery`.
+    pub fn decode(message: &[u8]) -> Result<Option<Self>, decode::Error> {
+        let mut d = Decoder::new(message);
+        d.array()?;
+        if d.u16()? != 3 {
+            return Ok(None);
+        }
+
+        d.array()?;
+        let query = match d.u16()? {
+            0 => decode_ledger_query(&mut d)?,
+            1 => Self::new("GetSystemStart", None),
+            2 => Self::new("GetChainBlockNo", None),
+            3 => Self::new("GetChainPoint", None),
+            4 => Self::new("DebugLedgerConfig", None),
+            _ => Self::new("Unknown", None),
+        };
+
+        Ok(Some(query))
+    }
+
+    fn new(name: &'static str, era: Option<u16>) -> Self {
+        Self { name, era }
+    }
+
+    pub fn era_name(&self) -> &'static str {
+        match self.era {
+            None => "none",
+            Some(era) => era_name(era),
+        }
+    }
+}
+
+pub fn era_name(era: u16) -> &'static str {
+    match era {
+        0 => "byron",
+        1 => "shelley",
+        2 => "allegra",
+        3 => "mary",
+        4 => "alonzo",
+        5 => "babbage",
+        6 => "conway",
+        _ => "unknown",
+    }
+}
+
+// Hard fork combinator queries: `[0, [era, query]]` runs the query if the era is the current
+// one, `[1, [0], era]` asks for the era start and `[2, query]` asks about the eras themselves.
+fn decode_ledger_query(d: &mut Decoder) -> Result<Query, decode::Error> {
+    d.array()?;
+    match d.u16()? {
+        0 => {
+            d.array()?;
+            let era = d.u16()?;
+            let name = decode_block_query(d, era)?;
+            Ok(Query::new(name, Some(era)))
+        }
+        1 => {
+            d.skip()?;
+            let era = d.u16()?;
+            Ok(Query::new("GetEraStart", Some(era)))
+        }
+        2 => {
+            d.array()?;
+            let name = match d.u16()? {
+                0 => "GetInterpreter",
+                1 => "GetCurrentEra",
+                _ => "Unknown",
+            };
+            Ok(Query::new(name, None))
+        }
+        _ => Ok(Query::new("Unknown", None)),
+    }
+}
+
+fn decode_block_query(d: &mut Decoder, era: u16) -> Result<&'static str, decode::Error> {
+    d.array()?;
+    let mut tag = d.u16()?;
+
+    if era == 0 {
+        return Ok(match tag {
+            0 => "GetUpdateInterfaceState",
+            _ => "Unknown",
+        });
+    }
+
+    // `GetCBOR` wraps another query, which is the one the node runs.
+    while tag == 9 {
+        d.array()?;
+        tag = d.u16()?;
+    }
+
+    let name = match tag {
+        0 => "GetLedgerTip",
+        1 => "GetEpochNo",
+        2 => "GetNonMyopicMemberRewards",
+        3 => "GetCurrentPParams",
+        4 => "GetProposedPParamsUpdates",
+        5 => "GetStakeDistribution",
+        6 => "GetUTxOByAddress",
+        7 => "GetUTxOWhole",
+        8 => "DebugEpochState",
+        10 => "GetFilteredDelegationsAndRewardAccounts",
+        11 => "GetGenesisConfig",
+        12 => "DebugNewEpochState",
+        13 => "DebugChainDepState",
+        14 => "GetRewardProvenance",
+        15 => "GetUTxOByTxIn",
+        16 => "GetStakePools",
+        17 => "GetStakePoolParams",
+        18 => "GetRewardInfoPools",
+        19 => "GetPoolState",
+        20 => "GetStakeSnapshots",
+        21 => "GetPoolDistr",
+        22 => "GetStakeDelegDeposits",
+        23 => "GetConstitution",
+        24 => "GetGovState",
+        25 => "GetDRepState",
+        26 => "GetDRepStakeDistr",
+        27 => "GetCommitteeMembersState",
+        28 => "GetFilteredVoteDelegatees",
+        29 => "GetAccountState",
+        30 => "GetSPOStakeDistr",
+        31 => "GetProposals",
+        32 => "GetRatifyState",
+        33 => "GetFuturePParams",
+        34 => "GetLedgerPeerSnapshot",
+        35 => "QueryStakePoolDefaultVote",
+        36 => "GetPoolDistr2",
+        37 => "GetStakeDistribution2",
+        38 => "GetMaxMajorProtocolVersion",
+        39 => "GetDRepsDelegations",
+        _ => "Unknown",
+    };
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(message: &str) -> Query {
+        Query::decode(&hex::decode(message).unwrap())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn state_query_messages() {
+        assert_eq!(
+            StateQuery::decode(&acquire_tip()).unwrap(),
+            StateQuery::Acquire(None)
+        );
+        // `MsgAcquire` of a specific point, `[0, [slot, hash]]`.
+        assert_eq!(
+            StateQuery::decode(&hex::decode("82008201410a").unwrap()).unwrap(),
+            StateQuery::Acquire(Some(vec![0x82, 0x01, 0x41, 0x0a]))
+        );
+        assert_eq!(
+            StateQuery::decode(&acquired()).unwrap(),
+            StateQuery::Acquired
+        );
+        assert_eq!(
+            StateQuery::decode(&result(&[0x81, 0x06])).unwrap(),
+            StateQuery::Result(vec![0x81, 0x06])
+        );
+        assert_eq!(StateQuery::decode(&release()).unwrap(), StateQuery::Release);
+        assert!(StateQuery::decode(&[0x81, 0x0c]).is_err());
+    }
+
+    #[test]
+    fn not_a_query() {
+        assert_eq!(Query::decode(&acquire_tip()).unwrap(), None);
+    }
+
+    #[test]
+    fn top_level_queries() {
+        assert_eq!(query("82038101").name, "GetSystemStart");
+        assert_eq!(
+            Query::decode(&get_chain_point()).unwrap().unwrap().name,
+            "GetChainPoint"
+        );
+        assert_eq!(query("82038117").name, "Unknown");
+    }
+
+    #[test]
+    fn hard_fork_queries() {
+        let current_era = Query::decode(&get_current_era()).unwrap().unwrap();
+        assert_eq!(current_era, Query::new("GetCurrentEra", None));
+        // `[3, [0, [1, [0], 5]]]`
+        assert_eq!(
+            query("820382008301810005"),
+            Query::new("GetEraStart", Some(5))
+        );
+    }
+
+    #[test]
+    fn era_queries() {
+        let utxo = Query::decode(&era_query(6, &[0x81, 0x07]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(utxo, Query::new("GetUTxOWhole", Some(6)));
+        assert_eq!(utxo.era_name(), "conway");
+
+        let params = Query::decode(&era_query(5, &[0x81, 0x03]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(params, Query::new("GetCurrentPParams", Some(5)));
+
+        let byron = Query::decode(&era_query(0, &[0x81, 0x00]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(byron, Query::new("GetUpdateInterfaceState", Some(0)));
+    }
+
+    #[test]
+    fn get_cbor_is_classified_by_the_wrapped_query() {
+        // `GetCBOR` of `GetUTxOWhole`, `[9, [7]]`.
+        let wrapped = Query::decode(&era_query(6, &[0x82, 0x09, 0x81, 0x07]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(wrapped, Query::new("GetUTxOWhole", Some(6)));
+
+        let nested = Query::decode(&era_query(6, &[0x82, 0x09, 0x82, 0x09, 0x81, 0x05]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(nested, Query::new("GetStakeDistribution", Some(6)));
+    }
+
+    #[test]
+    fn truncated_query() {
+        assert!(Query::decode(&[0x82, 0x03, 0x82, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp, connectors::TransportConnector, protocols::Stream, server::ShutdownWatch,
+    upstreams::peer::BasicPeer,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::lookup_host,
+    select,
+    time::timeout,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Config,
+    handshake::{self, Proposal},
+    mux::{Direction, Frame, FrameDecoder, MessageDecoder, Mode, Protocol},
+    State,
+};
+
+/// Label of the peers past the limit of peers with their own series.
+const OVERFLOW_LABEL: &str = "other";
+
+/// Proxies Ouroboros node-to-node connections of a network to its relay nodes. Peers are only
+/// accounted by their address, since N2N has no credentials.
+pub struct RelayApp {
+    client_connector: TransportConnector,
+    network: String,
+    magic: u64,
+    config: Arc<Config>,
+    state: Arc<State>,
+    /// Open connections by metric label, to drop the label values when the last one closes.
+    labels: Mutex<HashMap<String, usize>>,
+    /// Peers with a series on the denied connections.
+    denied: Mutex<HashSet<String>>,
+}
+impl RelayApp {
+    pub fn new(network: &str, magic: u64, config: Arc<Config>, state: Arc<State>) -> Self {
+        Self {
+            client_connector: TransportConnector::new(None),
+            network: network.into(),
+            magic,
+            config,
+            state,
+            labels: Default::default(),
+            denied: Default::default(),
+        }
+    }
+
+    /// Counts the connection of the peer, returning its metric label. Peers are labelled by
+    /// address up to `RELAY_MAX_PEER_METRICS` at a time, the ones past it share `other`.
+    fn connected(&self, peer: &str) -> String {
+        let mut labels = self.labels.lock().unwrap();
+        let label =
+            match labels.contains_key(peer) || labels.len() < self.config.relay_max_peer_metrics {
+                true => peer.to_string(),
+                false => OVERFLOW_LABEL.to_string(),
+            };
+        *labels.entry(label.clone()).or_default() += 1;
+        self.state
+            .metrics
+            .inc_total_relay_connections(&self.network, &label);
+        label
+    }
+
+    fn disconnected(&self, label: &str) {
+        let mut labels = self.labels.lock().unwrap();
+        let Some(active) = labels.get_mut(label) else {
+            return;
+        };
+        *active -= 1;
+        if *active == 0 {
+            labels.remove(label);
+            self.state.metrics.remove_relay_peer(&self.network, label);
+        } else {
+            self.state
+                .metrics
+                .dec_total_relay_connections(&self.network, label);
+        }
+    }
+
+    /// Counts a denied connection of the peer. Denied peers never disconnect, so their series
+    /// stay, up to `RELAY_MAX_PEER_METRICS` peers.
+    fn denied(&self, peer: &str, reason: &str) {
+        let mut denied = self.denied.lock().unwrap();
+        let label = match denied.contains(peer) || denied.len() < self.config.relay_max_peer_metrics
+        {
+            true => {
+                denied.insert(peer.to_string());
+                peer
+            }
+            false => OVERFLOW_LABEL,
+        };
+        self.state
+            .metrics
+            .count_total_relay_connections_denied(&self.network, label, reason);
+    }
+
+    /// Takes a connection slot of the peer, failing with the reason the peer isn't accepted.
+    async fn admit(&self, peer: &IpAddr) -> Result<(), &'static str> {
+        if self.config.relay_deny.iter().any(|net| net.contains(peer)) {
+            return Err("denied_peer");
+        }
+        if !self.config.relay_allow.is_empty()
+            && !self.config.relay_allow.iter().any(|net| net.contains(peer))
+        {
+            return Err("not_allowed");
+        }
+
+        let mut connections = self.state.relay_connections.write().await;
+        let active = connections.get(peer).copied().unwrap_or_default();
+        if self
+            .config
+            .relay_max_connections_per_ip
+            .is_some_and(|max| active >= max)
+        {
+            return Err("connection_limit");
+        }
+        connections.insert(*peer, active + 1);
+        Ok(())
+    }
+
+    async fn release(&self, peer: &IpAddr) {
+        let mut connections = self.state.relay_connections.write().await;
+        if let Some(active) = connections.get_mut(peer) {
+            *active -= 1;
+            if *active == 0 {
+                connections.remove(peer);
+            }
+        }
+    }
+
+    async fn relay(
+        &self,
+        mut io_client: Stream,
+        label: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (message, received) = timeout(
+            self.config.handshake_timeout,
+            read_handshake(&mut io_client),
+        )
+        .await??;
+
+        let proposal = Proposal::decode(&message)?.ok_or("handshake is not a proposal")?;
+        if let Some(magic) = proposal.mismatched_magic(self.magic) {
+            self.denied(label, "magic");
+
+            let reason = format!("network magic {magic} is not {}", self.magic);
+            let reply = handshake::refuse(proposal.highest_version(), &reason);
+            for frame in Frame::segments(Protocol::Handshake, Mode::Responder, 0, &reply) {
+                io_client.write_all(&frame.encode()).await?;
+            }
+            io_client.flush().await?;
+            return Err(reason.into());
+        }
+
+        let upstream = format!(
+            "node-{}-{}.{}:{}",
+            self.network,
+            self.config.relay_node_release,
+            self.config.node_dns,
+            self.config.relay_node_port
+        );
+        let node_addr = lookup_host(&upstream)
+            .await?
+            .next()
+            .ok_or("relay node address not found")?;
+        let mut io_instance = self
+            .client_connector
+            .new_stream(&BasicPeer::new(&node_addr.to_string()))
+            .await?;
+
+        // Everything the peer sent so far goes as is, the proxy doesn't change N2N traffic.
+        io_instance.write_all(&received).await?;
+        io_instance.flush().await?;
+        self.count_bytes(label, Direction::Inbound, received.len());
+
+        let mut io_client_buf = [0; 1024];
+        let mut io_instance_buf = [0; 1024];
+        loop {
+            select! {
+                n = io_client.read(&mut io_client_buf) => {
+                    let bytes = n?;
+                    if bytes == 0 {
+                        return Ok(());
+                    }
+                    self.count_bytes(label, Direction::Inbound, bytes);
+                    io_instance.write_all(&io_client_buf[0..bytes]).await?;
+                    io_instance.flush().await?;
+                },
+                n = io_instance.read(&mut io_instance_buf) => {
+                    let bytes = n?;
+                    if bytes == 0 {
+                        return Ok(());
+                    }
+                    self.count_bytes(label, Direction::Outbound, bytes);
+                    io_client.write_all(&io_instance_buf[0..bytes]).await?;
+                    io_client.flush().await?;
+                },
+            }
+        }
+    }
+
+    fn count_bytes(&self, label: &str, direction: Direction, bytes: usize) {
+        self.state.metrics.count_total_relay_bytes(
+            &self.network,
+            label,
+            &direction.to_string(),
+            bytes,
+        );
+    }
+}
+
+#[async_trait]
+impl ServerApp for RelayApp {
+    async fn process_new(
+        self: &Arc<Self>,
+        io_client: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let Some(ip) = io_client
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied())
+            .map(|addr| addr.ip())
+        else {
+            error!(network = self.network, "relay peer address not found");
+            return None;
+        };
+        let peer = ip.to_string();
+
+        if let Err(reason) = self.admit(&ip).await {
+            self.denied(&peer, reason);
+            warn!(network = self.network, peer, reason, "relay peer denied");
+            return None;
+        }
+
+        let label = self.connected(&peer);
+        info!(network = self.network, peer, "relay peer connected");
+
+        if let Err(err) = self.relay(io_client, &label).await {
+            warn!(
+                error = err.to_string(),
+                network = self.network,
+                peer,
+                "relay connection error"
+            );
+        }
+
+        self.release(&ip).await;
+        self.disconnected(&label);
+        info!(network = self.network, peer, "relay peer disconnected");
+
+        None
+    }
+}
+
+/// Reads until the peer proposes the handshake, returning the proposal and the bytes read.
+async fn read_handshake(
+    io: &mut Stream,
+) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let mut frames = FrameDecoder::new();
+    let mut messages = MessageDecoder::new();
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        let bytes = io.read(&mut buf).await?;
+        if bytes == 0 {
+            return Err("connection closed".into());
+        }
+        received.extend_from_slice(&buf[0..bytes]);
+        frames.extend(&buf[0..bytes]);
+
+        while let Some(frame) = frames.next_frame() {
+            if frame.header.protocol != Protocol::Handshake {
+                return Err(format!("{} used before the handshake", frame.header.protocol).into());
+            }
+            if let Some(message) = messages.push(&frame)?.into_iter().next() {
+                return Ok((message, received));
+            }
+        }
+    }
+}
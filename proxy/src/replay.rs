@@ -0,0 +1,168 @@
+use std::{collections::HashMap, error::Error, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    capture,
+    mux::{Direction, Frame, FrameDecoder, MessageDecoder, Protocol},
+};
+
+/// Time to wait for the node to answer as it did on the capture.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const USAGE: &str = "usage: proxy replay <capture file> <node address>";
+
+/// `proxy replay <capture file> <node address>` sends the client frames of a capture to a node
+/// and compares the node replies with the captured ones, message by message.
+pub fn main(args: &[String]) {
+    let [capture, address] = args else {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    };
+
+    let runtime = tokio::runtime::Runtime::new().expect("failed to create tokio runtime");
+    match runtime.block_on(replay(capture, address)) {
+        Ok(true) => println!("replies match the capture"),
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            eprintln!("replay failed: {err}");
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn replay(path: &str, address: &str) -> Result<bool, Box<dyn Error>> {
+    let records = capture::read(&std::fs::read(path)?)?;
+    if let (Some(first), Some(last)) = (records.first(), records.last()) {
+        let duration = Duration::from_micros(last.timestamp.saturating_sub(first.timestamp));
+        println!(
+            "replaying {} frames captured over {duration:?}",
+            records.len()
+        );
+    }
+
+    let mut io = TcpStream::connect(address).await?;
+
+    let mut captured = Messages::default();
+    let mut replayed = Messages::default();
+    let mut frames = FrameDecoder::new();
+    let mut buf = [0; 1024];
+
+    for record in &records {
+        match record.direction {
+            Direction::Outbound => captured.push(&record.frame)?,
+            // Client frames are sent once the node replied everything it did before them.
+            Direction::Inbound => {
+                while !replayed.caught_up(&captured) {
+                    let bytes = match timeout(REPLY_TIMEOUT, io.read(&mut buf)).await {
+                        Ok(bytes) => bytes?,
+                        Err(_) => break,
+                    };
+                    if bytes == 0 {
+                        break;
+                    }
+                    frames.extend(&buf[0..bytes]);
+                    while let Some(frame) = frames.next_frame() {
+                        replayed.push(&frame)?;
+                    }
+                }
+                io.write_all(&record.frame.encode()).await?;
+            }
+        }
+    }
+
+    while !replayed.caught_up(&captured) {
+        let Ok(bytes) = timeout(REPLY_TIMEOUT, io.read(&mut buf)).await else {
+            break;
+        };
+        let bytes = bytes?;
+        if bytes == 0 {
+            break;
+        }
+        frames.extend(&buf[0..bytes]);
+        while let Some(frame) = frames.next_frame() {
+            replayed.push(&frame)?;
+        }
+    }
+
+    Ok(diff(&captured, &replayed))
+}
+
+/// Node messages by mini-protocol.
+#[derive(Default)]
+struct Messages {
+    decoder: MessageDecoder,
+    messages: HashMap<Protocol, Vec<Vec<u8>>>,
+}
+impl Messages {
+    fn push(&mut self, frame: &Frame) -> Result<(), Box<dyn Error>> {
+        let messages = self.decoder.push(frame)?;
+        self.messages
+            .entry(frame.header.protocol)
+            .or_default()
+            .extend(messages);
+        Ok(())
+    }
+
+    fn count(&self, protocol: &Protocol) -> usize {
+        self.messages
+            .get(protocol)
+            .map(Vec::len)
+            .unwrap_or_default()
+    }
+
+    fn caught_up(&self, other: &Messages) -> bool {
+        other
+            .messages
+            .keys()
+            .all(|protocol| self.count(protocol) >= other.count(protocol))
+    }
+}
+
+fn diff(captured: &Messages, replayed: &Messages) -> bool {
+    let mut protocols: Vec<&Protocol> = captured
+        .messages
+        .keys()
+        .chain(replayed.messages.keys())
+        .collect();
+    protocols.sort_by_key(|protocol| protocol.id());
+    protocols.dedup();
+
+    let mut differences = 0;
+    for protocol in protocols {
+        let empty = Vec::new();
+        let captured = captured.messages.get(protocol).unwrap_or(&empty);
+        let replayed = replayed.messages.get(protocol).unwrap_or(&empty);
+
+        for index in 0..captured.len().max(replayed.len()) {
+            let (captured, replayed) = (captured.get(index), replayed.get(index));
+            if captured == replayed {
+                continue;
+            }
+
+            differences += 1;
+            println!("{protocol} message {index} differs");
+            println!("  captured: {}", describe(captured));
+            println!("  replayed: {}", describe(replayed));
+        }
+    }
+
+    if differences > 0 {
+        println!("{differences} messages differ from the capture");
+    }
+    differences == 0
+}
+
+fn describe(message: Option<&Vec<u8>>) -> String {
+    match message {
+        Some(message) => {
+            let preview = &message[..message.len().min(64)];
+            format!("{} bytes {}", message.len(), hex::encode(preview))
+        }
+        None => "missing".into(),
+    }
+}
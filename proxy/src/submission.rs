@@ -0,0 +1,119 @@
+use pallas_codec::minicbor::{data::IanaTag, decode, Decoder, Encoder};
+use pallas_crypto::hash::Hasher;
+
+/// Local-tx-submission messages, keeping only what the proxy needs from each one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Submission {
+    SubmitTx(Tx),
+    AcceptTx,
+    RejectTx(Vec<u8>),
+    Done,
+}
+impl Submission {
+    pub fn decode(message: &[u8]) -> Result<Self, decode::Error> {
+        let mut d = Decoder::new(message);
+        d.array()?;
+
+        match d.u16()? {
+            0 => Ok(Submission::SubmitTx(Tx::decode(&mut d)?)),
+            1 => Ok(Submission::AcceptTx),
+            2 => {
+                let start = d.position();
+                d.skip()?;
+                let reason = message[start..d.position()].to_vec();
+                Ok(Submission::RejectTx(reason))
+            }
+            3 => Ok(Submission::Done),
+            _ => Err(decode::Error::message(
+                "unknown variant for local-tx-submission message",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tx {
+    pub era: u16,
+    /// Hash of the tx body. Byron txs are not hashed.
+    pub hash: Option<String>,
+    pub size: usize,
+}
+impl Tx {
+    // The tx is sent as `[era, #6.24(bytes)]`, where the bytes are the serialized tx.
+    pub fn decode(d: &mut Decoder) -> Result<Self, decode::Error> {
+        d.array()?;
+        let era = d.u16()?;
+        if d.tag()? != IanaTag::Cbor.tag() {
+            return Err(decode::Error::message("expected encoded CBOR data item"));
+        }
+        Self::new(era, d.bytes()?)
+    }
+
+    pub fn new(era: u16, bytes: &[u8]) -> Result<Self, decode::Error> {
+        let hash = match era {
+            0 => None,
+            _ => Some(Hasher::<256>::hash(body(bytes)?).to_string()),
+        };
+
+        Ok(Self {
+            era,
+            hash,
+            size: bytes.len(),
+        })
+    }
+}
+
+/// `MsgSubmitTx` with a serialized tx of the era.
+pub fn submit_tx(era: u16, tx: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.array(2).unwrap().u16(0).unwrap();
+    e.array(2).unwrap().u16(era).unwrap();
+    e.tag(IanaTag::Cbor).unwrap().bytes(tx).unwrap();
+    e.into_writer()
+}
+
+// Since Shelley the tx is an array and the body is its first item.
+fn body(tx: &[u8]) -> Result<&[u8], decode::Error> {
+    let mut d = Decoder::new(tx);
+    d.array()?;
+    let start = d.position();
+    d.skip()?;
+    Ok(&tx[start..d.position()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Preprod tx b1db2a411cb651a413840d3c8b112895a5bda2519a8ba6a372b8dd1ffc7746c2.
+    const TX: &str = "84a300d9010281825820fa72e22e7661608e75c8837b26bfc7b9fb61ddcf3456f12fb58d57ab7437002f00018182581d60d8188ff2e2bd2384524e2e52b9f0ee0dc19e50ab7baac6d771f6d45e1a001c095b021a00027b25a101d9010281830320828200581c3118644aa21ba172c82732ce80d1c94cdcb5f2e8891e1ad2645707188200581ce07caf4bf751495f75774ace30552441e4df84d141e5d1f5029cb04df5f6";
+    const TX_ID: &str = "b1db2a411cb651a413840d3c8b112895a5bda2519a8ba6a372b8dd1ffc7746c2";
+
+    #[test]
+    fn tx_hash_is_the_tx_id() {
+        let tx = hex::decode(TX).unwrap();
+        let Submission::SubmitTx(submitted) = Submission::decode(&submit_tx(6, &tx)).unwrap()
+        else {
+            panic!("expected MsgSubmitTx");
+        };
+        assert_eq!(
+            submitted,
+            Tx {
+                era: 6,
+                hash: Some(TX_ID.into()),
+                size: tx.len(),
+            }
+        );
+    }
+
+    #[test]
+    fn byron_tx_has_no_hash() {
+        let tx = hex::decode(TX).unwrap();
+        let Submission::SubmitTx(submitted) = Submission::decode(&submit_tx(0, &tx)).unwrap()
+        else {
+            panic!("expected MsgSubmitTx");
+        };
+        assert_eq!(submitted.hash, None);
+        assert_eq!(submitted.size, tx.len());
+    }
+}
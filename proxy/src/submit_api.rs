@@ -0,0 +1,337 @@
+use std::{error::Error, sync::Arc, time::Duration, time::SystemTime};
+
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode};
+use pingora::{
+    apps::http_app::ServeHttp, connectors::TransportConnector, protocols::http::ServerSession,
+};
+use regex::Regex;
+use serde_json::json;
+use tokio::time::{error::Elapsed, timeout};
+use tracing::{error, warn};
+
+use crate::{
+    client::NodeSession,
+    config::Config,
+    mux::Protocol,
+    proxy::{audit_tx, budget, PendingTx},
+    submission::{self, Submission, Tx},
+    Consumer, State,
+};
+
+const SUBMIT_PATH: &str = "/api/submit/tx";
+/// Era of the submitted txs. The body has no era, so like cardano-submit-api the txs are taken
+/// as Conway txs.
+const SUBMIT_ERA: u16 = 6;
+/// Largest body read, way above the protocol max tx size.
+const MAX_BODY_LEN: usize = 64 * 1024;
+/// Time to connect to the node and get the tx accepted or rejected.
+const SUBMIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTP endpoint compatible with cardano-submit-api. Each request is submitted on its own
+/// local-tx-submission session to the consumer's instance.
+pub struct SubmitApiApp {
+    client_connector: TransportConnector,
+    host_regex: Regex,
+    config: Arc<Config>,
+    state: Arc<State>,
+}
+impl SubmitApiApp {
+    pub fn new(config: Arc<Config>, state: Arc<State>) -> Self {
+        Self {
+            client_connector: TransportConnector::new(None),
+            host_regex: Regex::new(r"([\w\d-]+)\..+").unwrap(),
+            config,
+            state,
+        }
+    }
+
+    async fn consumer(&self, session: &ServerSession) -> Option<Consumer> {
+        let header = session.req_header();
+        let host = header
+            .uri
+            .host()
+            .or_else(|| header.headers.get("host")?.to_str().ok())?;
+        let key = host_key(&self.host_regex, host)?;
+        // Ports that require a client certificate can't be used without one.
+        self.state
+            .get_consumer(&key)
+            .await
+            .filter(|consumer| consumer.accepts(None))
+    }
+
+    async fn submit(&self, session: &mut ServerSession) -> (StatusCode, serde_json::Value) {
+        let header = session.req_header();
+        if header.uri.path() != SUBMIT_PATH {
+            return (StatusCode::NOT_FOUND, json!("not found"));
+        }
+        if header.method != Method::POST {
+            return (StatusCode::METHOD_NOT_ALLOWED, json!("method not allowed"));
+        }
+        let content_type = header
+            .headers
+            .get("content-type")
+            .and_then(|value| value.to_str().ok());
+        if content_type != Some("application/cbor") {
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                json!("content type must be application/cbor"),
+            );
+        }
+
+        let Some(consumer) = self.consumer(session).await else {
+            return (StatusCode::UNAUTHORIZED, json!("unknown token"));
+        };
+        let Some(tier) = self.state.tiers.read().await.get(&consumer.tier).cloned() else {
+            error!(consumer = consumer.to_string(), "tier not found");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!(format!("tier {} not found", consumer.tier)),
+            );
+        };
+        if !tier.allows(&Protocol::LocalTxSubmission)
+            || !consumer.allows(&Protocol::LocalTxSubmission)
+        {
+            return (
+                StatusCode::FORBIDDEN,
+                json!("tx submission is not allowed for the port"),
+            );
+        }
+
+        let mut body = Vec::new();
+        loop {
+            match session.read_request_body().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(err) => return (StatusCode::BAD_REQUEST, json!(err.to_string())),
+            }
+            if body.len() > MAX_BODY_LEN {
+                return (StatusCode::PAYLOAD_TOO_LARGE, json!("tx too large"));
+            }
+        }
+
+        let tx = match Tx::new(SUBMIT_ERA, &body) {
+            Ok(tx) => tx,
+            Err(err) => return (StatusCode::BAD_REQUEST, json!(format!("invalid tx: {err}"))),
+        };
+        let pending = PendingTx {
+            tx,
+            submitted_at: SystemTime::now(),
+        };
+
+        let instance = format!(
+            "node-{}-{}.{}:{}",
+            consumer.network, consumer.version, self.config.node_dns, self.config.node_port
+        );
+        let namespace = &self.config.proxy_namespace;
+
+        let denied = if tier.exceeds_tx_size(&pending.tx) {
+            Some("size")
+        } else if !budget(
+            &self.state,
+            &self.state.tx_limiter,
+            &consumer,
+            &tier.tx_rates,
+            1,
+        )
+        .await
+        {
+            Some("rate")
+        } else {
+            None
+        };
+        if let Some(reason) = denied {
+            self.state
+                .metrics
+                .count_total_txs_denied(&consumer, namespace, &instance, reason);
+            self.state
+                .metrics
+                .count_total_http_submissions(&consumer, namespace, &instance, "denied");
+            audit_tx(&consumer, &tier, pending, "denied", None);
+            return denied_response(reason, &tier.name);
+        }
+
+        let tx_hash = pending.tx.hash.clone();
+        let result = timeout(SUBMIT_TIMEOUT, self.submit_tx(&consumer, &instance, &body)).await;
+        if let Ok(Err(err)) = &result {
+            warn!(
+                error = err.to_string(),
+                consumer = consumer.to_string(),
+                "tx submission to the node failed"
+            );
+        }
+        let (result, status, response, reject_reason) =
+            submission_response(result, tx_hash.as_deref());
+
+        self.state
+            .metrics
+            .count_total_http_submissions(&consumer, namespace, &instance, result);
+        audit_tx(&consumer, &tier, pending, result, reject_reason.as_deref());
+
+        (status, response)
+    }
+
+    async fn submit_tx(
+        &self,
+        consumer: &Consumer,
+        instance: &str,
+        tx: &[u8],
+    ) -> Result<Submission, Box<dyn Error + Send + Sync>> {
+        let magic = *self
+            .config
+            .network_magics
+            .get(&consumer.network)
+            .ok_or("unknown network")?;
+        let mut session = NodeSession::connect(&self.client_connector, instance, magic).await?;
+        session
+            .send(
+                Protocol::LocalTxSubmission,
+                &submission::submit_tx(SUBMIT_ERA, tx),
+            )
+            .await?;
+        // The node validates the tx before replying, which can take longer than a read.
+        let reply = session
+            .wait_for(Protocol::LocalTxSubmission, SUBMIT_TIMEOUT)
+            .await?;
+
+        Ok(Submission::decode(&reply)?)
+    }
+}
+
+/// Key of the token in the first label of the host.
+fn host_key(host_regex: &Regex, host: &str) -> Option<Vec<u8>> {
+    let token = host_regex.captures(host)?.get(1)?.as_str();
+    let (_hrp, key) = bech32::decode(token).ok()?;
+    Some(key)
+}
+
+/// Response to a tx denied by the tier, by the limit it exceeds.
+fn denied_response(reason: &str, tier: &str) -> (StatusCode, serde_json::Value) {
+    let status = match reason {
+        "size" => StatusCode::PAYLOAD_TOO_LARGE,
+        _ => StatusCode::TOO_MANY_REQUESTS,
+    };
+    let message = format!("tx exceeds the {reason} limit for tier {tier}");
+    (status, json!(message))
+}
+
+type SubmitResult = Result<Result<Submission, Box<dyn Error + Send + Sync>>, Elapsed>;
+
+/// Result for the metrics, response and reject reason of a submission to the node.
+fn submission_response(
+    result: SubmitResult,
+    tx_hash: Option<&str>,
+) -> (&'static str, StatusCode, serde_json::Value, Option<Vec<u8>>) {
+    let timed_out = || {
+        (
+            "no_reply",
+            StatusCode::GATEWAY_TIMEOUT,
+            json!("the node didn't reply in time"),
+            None,
+        )
+    };
+    match result {
+        Ok(Ok(Submission::AcceptTx)) => ("accepted", StatusCode::ACCEPTED, json!(tx_hash), None),
+        Ok(Ok(Submission::RejectTx(reason))) => (
+            "rejected",
+            StatusCode::BAD_REQUEST,
+            json!({"tag": "TxSubmitFail", "contents": hex::encode(&reason)}),
+            Some(reason),
+        ),
+        Ok(Ok(_)) => (
+            "no_reply",
+            StatusCode::BAD_GATEWAY,
+            json!("unexpected reply from the node"),
+            None,
+        ),
+        Ok(Err(err)) if err.is::<Elapsed>() => timed_out(),
+        Ok(Err(err)) => (
+            "no_reply",
+            StatusCode::BAD_GATEWAY,
+            json!(err.to_string()),
+            None,
+        ),
+        Err(_) => timed_out(),
+    }
+}
+
+#[async_trait]
+impl ServeHttp for SubmitApiApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let (status, body) = self.submit(session).await;
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(serde_json::to_vec(&body).unwrap())
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bech32::{Bech32m, Hrp};
+
+    use super::*;
+
+    fn host_regex() -> Regex {
+        Regex::new(r"([\w\d-]+)\..+").unwrap()
+    }
+
+    #[test]
+    fn host_token() {
+        let key = vec![7; 8];
+        let token = bech32::encode::<Bech32m>(Hrp::parse("cnode").unwrap(), &key).unwrap();
+
+        let host = format!("{token}.cardano-mainnet.node-m1.demeter.run");
+        assert_eq!(host_key(&host_regex(), &host), Some(key.clone()));
+        let host = format!("{token}.cardano-mainnet.node-m1.demeter.run:443");
+        assert_eq!(host_key(&host_regex(), &host), Some(key));
+
+        assert_eq!(host_key(&host_regex(), "localhost"), None);
+        assert_eq!(host_key(&host_regex(), "not-a-token.demeter.run"), None);
+        assert_eq!(host_key(&host_regex(), ""), None);
+    }
+
+    #[test]
+    fn denied_status() {
+        let (status, body) = denied_response("size", "0");
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body, json!("tx exceeds the size limit for tier 0"));
+        let (status, _) = denied_response("rate", "0");
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn submission_status() {
+        let (result, status, body, reason) =
+            submission_response(Ok(Ok(Submission::AcceptTx)), Some("abcd"));
+        assert_eq!(
+            (result, status, body, reason),
+            ("accepted", StatusCode::ACCEPTED, json!("abcd"), None)
+        );
+
+        let (result, status, body, reason) =
+            submission_response(Ok(Ok(Submission::RejectTx(vec![0x82, 0x01]))), None);
+        assert_eq!(result, "rejected");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, json!({"tag": "TxSubmitFail", "contents": "8201"}));
+        assert_eq!(reason, Some(vec![0x82, 0x01]));
+
+        let (_, status, _, _) = submission_response(Ok(Ok(Submission::Done)), None);
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let (_, status, _, _) = submission_response(Ok(Err("connection closed".into())), None);
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        // The node not replying is a timeout, whether the read or the whole submission ran out.
+        let elapsed = timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        let (_, status, _, _) = submission_response(Ok(Err(elapsed.into())), None);
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        let elapsed = timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        let (_, status, _, _) = submission_response(Err(elapsed), None);
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    }
+}
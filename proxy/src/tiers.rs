@@ -36,6 +36,8 @@ impl TierBackgroundService {
             .collect();
 
         self.state.limiter.write().await.clear();
+        self.state.query_limiter.write().await.clear();
+        self.state.tx_limiter.write().await.clear();
 
         Ok(())
     }
@@ -0,0 +1,551 @@
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use openssl::{base64, sha::sha1};
+use pingora::{
+    apps::ServerApp,
+    protocols::{
+        raw_connect::ProxyDigest, GetProxyDigest, GetSocketDigest, GetTimingDigest, Peek, Shutdown,
+        SocketDigest, Ssl, Stream, TimingDigest, UniqueID, UniqueIDType,
+    },
+    server::ShutdownWatch,
+    tls::ssl::SslRef,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::timeout,
+};
+use tracing::error;
+
+use crate::proxy::ProxyApp;
+
+/// Time the client has to send the upgrade request.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_LEN: usize = 8192;
+/// Largest WebSocket frame accepted from the client. Mux frames are at most 64 KiB.
+const MAX_FRAME_PAYLOAD_LEN: usize = 1024 * 1024;
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+
+/// Accepts WebSocket upgrades and serves the N2C connection carried on binary messages like the
+/// TLS listener does. The token comes from the `Host` header instead of the SNI.
+pub struct WebSocketApp {
+    proxy: ProxyApp,
+}
+impl WebSocketApp {
+    pub fn new(proxy: ProxyApp) -> Self {
+        Self { proxy }
+    }
+}
+
+#[async_trait]
+impl ServerApp for WebSocketApp {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut io_client: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let (host, received) = match timeout(UPGRADE_TIMEOUT, upgrade(&mut io_client)).await {
+            Ok(Ok(upgrade)) => upgrade,
+            Ok(Err(reason)) => {
+                error!(reason, "invalid websocket upgrade");
+                let response = format!(
+                    "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{reason}",
+                    reason.len()
+                );
+                let _ = io_client.write_all(response.as_bytes()).await;
+                let _ = io_client.flush().await;
+                return None;
+            }
+            Err(_) => {
+                error!("websocket upgrade timeout");
+                return None;
+            }
+        };
+
+        let io_client: Stream = Box::new(WebSocket::new(io_client, received));
+        self.proxy.serve(io_client, &host, None).await
+    }
+}
+
+/// HTTP request head read from a connection, with the bytes the client sent after it.
+pub struct Request {
+    pub method: String,
+    headers: Vec<(String, String)>,
+    pub received: Vec<u8>,
+}
+impl Request {
+    /// Header value by its lowercase name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The port isn't part of the token.
+    pub fn host(&self) -> Option<&str> {
+        self.header("host")?.split(':').next()
+    }
+
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// Reads an HTTP request head.
+pub async fn read_request(io: &mut Stream) -> Result<Request, String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let end = loop {
+        if let Some(position) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if request.len() > MAX_REQUEST_LEN {
+            return Err("request too large".into());
+        }
+        let bytes = io.read(&mut buf).await.map_err(|err| err.to_string())?;
+        if bytes == 0 {
+            return Err("connection closed".into());
+        }
+        request.extend_from_slice(&buf[0..bytes]);
+    };
+
+    let head = std::str::from_utf8(&request[0..end]).map_err(|_| "invalid request")?;
+    let mut lines = head.split("\r\n");
+    let method = lines
+        .next()
+        .and_then(|line| line.split(' ').next())
+        .unwrap_or_default()
+        .to_string();
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    Ok(Request {
+        method,
+        headers,
+        received: request.split_off(end),
+    })
+}
+
+/// Switches the protocol of an upgrade request.
+pub async fn accept(io: &mut Stream, request: &Request) -> Result<(), String> {
+    if request.method != "GET" {
+        return Err("websocket upgrades must use GET".into());
+    }
+    if !request.is_upgrade() {
+        return Err("missing websocket upgrade".into());
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        return Err("unsupported websocket version".into());
+    }
+    let key = request
+        .header("sec-websocket-key")
+        .ok_or("missing websocket key")?;
+
+    let accept = accept_key(key);
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    io.write_all(response.as_bytes())
+        .await
+        .map_err(|err| err.to_string())?;
+    io.flush().await.map_err(|err| err.to_string())
+}
+
+/// `Sec-WebSocket-Accept` for the `Sec-WebSocket-Key` of the client.
+fn accept_key(key: &str) -> String {
+    base64::encode_block(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()))
+}
+
+/// Reads the upgrade request and switches the protocol, returning the host and the bytes the
+/// client sent after the request.
+async fn upgrade(io: &mut Stream) -> Result<(String, Vec<u8>), String> {
+    let request = read_request(io).await?;
+    accept(io, &request).await?;
+    let host = request.host().ok_or("missing host")?.to_string();
+
+    Ok((host, request.received))
+}
+
+/// Byte stream over the binary messages of a WebSocket connection, so the proxy reads and writes
+/// mux frames as it does on TLS connections. Control frames are answered while reading.
+#[derive(Debug)]
+pub struct WebSocket {
+    inner: Stream,
+    /// Bytes read from the connection that don't make a whole WebSocket frame yet.
+    received: Vec<u8>,
+    /// Message payload not read by the proxy yet.
+    payload: Vec<u8>,
+    /// Frames waiting to be written to the connection.
+    pending: Vec<u8>,
+    closed: bool,
+}
+impl WebSocket {
+    pub fn new(inner: Stream, received: Vec<u8>) -> Self {
+        Self {
+            inner,
+            received,
+            payload: Vec::new(),
+            pending: Vec::new(),
+            closed: false,
+        }
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(0..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn handle(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<()> {
+        match opcode {
+            OPCODE_BINARY | OPCODE_CONTINUATION => self.payload.extend(payload),
+            OPCODE_PING => self.pending.extend(encode(OPCODE_PONG, &payload)),
+            OPCODE_PONG => {}
+            OPCODE_CLOSE => {
+                if !self.closed {
+                    self.pending.extend(encode(OPCODE_CLOSE, &[]));
+                }
+                self.closed = true;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "only binary websocket messages are supported",
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for WebSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            // Pongs and close replies go out as soon as possible, a pending write is retried later.
+            if let Poll::Ready(Err(err)) = this.poll_pending(cx) {
+                return Poll::Ready(Err(err));
+            }
+
+            if !this.payload.is_empty() {
+                let len = this.payload.len().min(buf.remaining());
+                buf.put_slice(&this.payload[0..len]);
+                this.payload.drain(0..len);
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some((opcode, _fin, payload)) = decode(&mut this.received)? {
+                this.handle(opcode, payload)?;
+                continue;
+            }
+
+            let mut bytes = [0; 1024];
+            let mut read = ReadBuf::new(&mut bytes);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                this.closed = true;
+            }
+            this.received.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl AsyncWrite for WebSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_pending(cx))?;
+        this.pending.extend(encode(OPCODE_BINARY, buf));
+        // The message is buffered whole, so it's written even if the connection isn't ready.
+        if let Poll::Ready(Err(err)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.closed {
+            this.closed = true;
+            this.pending.extend(encode(OPCODE_CLOSE, &[]));
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl Shutdown for WebSocket {
+    async fn shutdown(&mut self) {
+        let _ = AsyncWriteExt::shutdown(self).await;
+    }
+}
+impl UniqueID for WebSocket {
+    fn id(&self) -> UniqueIDType {
+        self.inner.id()
+    }
+}
+impl Ssl for WebSocket {
+    fn get_ssl(&self) -> Option<&SslRef> {
+        self.inner.get_ssl()
+    }
+}
+impl GetTimingDigest for WebSocket {
+    fn get_timing_digest(&self) -> Vec<Option<TimingDigest>> {
+        self.inner.get_timing_digest()
+    }
+}
+impl GetProxyDigest for WebSocket {
+    fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>> {
+        self.inner.get_proxy_digest()
+    }
+}
+impl GetSocketDigest for WebSocket {
+    fn get_socket_digest(&self) -> Option<Arc<SocketDigest>> {
+        self.inner.get_socket_digest()
+    }
+}
+impl Peek for WebSocket {}
+
+/// Takes the next client frame from the buffer, with the opcode and whether it's the last frame
+/// of the message. Client frames are always masked.
+pub fn decode(buffer: &mut Vec<u8>) -> io::Result<Option<(u8, bool, Vec<u8>)>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buffer[0] & 0x0f;
+    let fin = buffer[0] & 0x80 != 0;
+    if buffer[1] & 0x80 == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "websocket client frames must be masked",
+        ));
+    }
+
+    let (len, mut position) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as usize, 4),
+        127 if buffer.len() >= 10 => (
+            u64::from_be_bytes(buffer[2..10].try_into().unwrap()) as usize,
+            10,
+        ),
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+    if len > MAX_FRAME_PAYLOAD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "websocket frame too large",
+        ));
+    }
+    if buffer.len() < position + 4 + len {
+        return Ok(None);
+    }
+
+    let mask: [u8; 4] = buffer[position..position + 4].try_into().unwrap();
+    position += 4;
+    let payload = buffer[position..position + len]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    buffer.drain(0..position + len);
+
+    Ok(Some((opcode, fin, payload)))
+}
+
+/// Server frames are sent whole and without mask.
+pub fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Client frame masked with `mask`.
+    fn masked(first: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+        let mut frame = encode(0, payload);
+        frame[0] = first;
+        frame[1] |= 0x80;
+        let position = frame.len() - payload.len();
+        frame.splice(position..position, mask);
+        for (i, byte) in frame[position + 4..].iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        frame
+    }
+
+    #[test]
+    fn accept_key_example() {
+        // RFC 6455 section 1.3.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn decode_masked_frame() {
+        // RFC 6455 section 5.7, a single-frame masked text message.
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(masked(0x81, b"Hello", [0x37, 0xfa, 0x21, 0x3d]), frame);
+
+        let mut buffer = frame.to_vec();
+        buffer.push(0x82);
+        assert_eq!(
+            decode(&mut buffer).unwrap(),
+            Some((OPCODE_TEXT, true, b"Hello".to_vec()))
+        );
+        // The start of the next frame is kept.
+        assert_eq!(buffer, [0x82]);
+        assert_eq!(decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_partial_frame() {
+        let frame = masked(0x82, b"Hello", [1, 2, 3, 4]);
+        for len in 0..frame.len() {
+            let mut buffer = frame[0..len].to_vec();
+            assert_eq!(decode(&mut buffer).unwrap(), None);
+            assert_eq!(buffer.len(), len);
+        }
+    }
+
+    #[test]
+    fn decode_fragmented_message() {
+        // RFC 6455 section 5.7, a fragmented text message, masked as clients send it.
+        let mut buffer = masked(0x01, b"Hel", [1, 2, 3, 4]);
+        buffer.extend(masked(0x80, b"lo", [5, 6, 7, 8]));
+
+        assert_eq!(
+            decode(&mut buffer).unwrap(),
+            Some((OPCODE_TEXT, false, b"Hel".to_vec()))
+        );
+        assert_eq!(
+            decode(&mut buffer).unwrap(),
+            Some((OPCODE_CONTINUATION, true, b"lo".to_vec()))
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn decode_control_frames() {
+        // RFC 6455 section 5.7, a masked ping with "Hello".
+        let mut buffer = masked(0x89, b"Hello", [0x37, 0xfa, 0x21, 0x3d]);
+        assert_eq!(
+            decode(&mut buffer).unwrap(),
+            Some((OPCODE_PING, true, b"Hello".to_vec()))
+        );
+
+        let mut buffer = masked(0x88, &[], [1, 2, 3, 4]);
+        assert_eq!(
+            decode(&mut buffer).unwrap(),
+            Some((OPCODE_CLOSE, true, Vec::new()))
+        );
+    }
+
+    #[test]
+    fn decode_extended_lengths() {
+        for (len, header) in [
+            (256, &[0x82, 0xfe, 0x01, 0x00][..]),
+            (65536, &[0x82, 0xff, 0, 0, 0, 0, 0, 1, 0, 0][..]),
+        ] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut buffer = masked(0x82, &payload, [1, 2, 3, 4]);
+            assert_eq!(&buffer[0..header.len()], header);
+            assert_eq!(
+                decode(&mut buffer).unwrap(),
+                Some((OPCODE_BINARY, true, payload))
+            );
+        }
+    }
+
+    #[test]
+    fn decode_rejects_invalid_frames() {
+        // RFC 6455 section 5.7, the unmasked "Hello" a server sends.
+        let mut buffer = vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert!(decode(&mut buffer).is_err());
+
+        let mut buffer = vec![0x82, 0xff];
+        buffer.extend_from_slice(&(MAX_FRAME_PAYLOAD_LEN as u64 + 1).to_be_bytes());
+        assert!(decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn encode_unmasked_frames() {
+        // RFC 6455 section 5.7.
+        assert_eq!(
+            encode(OPCODE_TEXT, b"Hello"),
+            [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+        assert_eq!(
+            encode(OPCODE_PONG, b"Hello"),
+            [0x8a, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+        assert_eq!(encode(OPCODE_CLOSE, &[]), [0x88, 0x00]);
+
+        let frame = encode(OPCODE_BINARY, &[0; 256]);
+        assert_eq!(frame[0..4], [0x82, 0x7e, 0x01, 0x00]);
+        assert_eq!(frame.len(), 4 + 256);
+
+        let frame = encode(OPCODE_BINARY, &[0; 65536]);
+        assert_eq!(frame[0..10], [0x82, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame.len(), 10 + 65536);
+    }
+}